@@ -0,0 +1,174 @@
+//! IP based access control with CIDR allow and deny lists.
+
+use crate::request::Headers;
+use serde::{Deserialize, Deserializer};
+use std::net::IpAddr;
+
+#[derive(Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct AccessConfig {
+    // applies to every request
+    #[serde(flatten)]
+    pub global: AccessList,
+    // `[[access.paths]]`, checked on top of the global lists
+    pub paths: Vec<PathAccess>,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct PathAccess {
+    pub prefix: String,
+    #[serde(flatten)]
+    pub list: AccessList,
+}
+
+#[derive(Deserialize, Clone, Default)]
+pub struct AccessList {
+    // when set, only these networks get through
+    #[serde(default, deserialize_with = "deserialize_cidrs")]
+    pub allow: Vec<Cidr>,
+    // always refused, even when they are also allowed
+    #[serde(default, deserialize_with = "deserialize_cidrs")]
+    pub deny: Vec<Cidr>,
+}
+
+/// A network like `10.0.0.0/8` or `2001:db8::/32`, a bare address matches
+/// only itself.
+#[derive(Clone, Copy)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix_len: u32,
+}
+
+impl Cidr {
+    pub fn parse(value: &str) -> Option<Cidr> {
+        let (address, prefix_len) = match value.split_once('/') {
+            Some((address, prefix_len)) => (address, Some(prefix_len)),
+            None => (value, None),
+        };
+        let network: IpAddr = address.trim().parse().ok()?;
+        let max = if network.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(prefix_len) => prefix_len.trim().parse().ok().filter(|len| *len <= max)?,
+            None => max,
+        };
+        Some(Cidr {
+            network,
+            prefix_len,
+        })
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.network, ip.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix_len).unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix_len).unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+pub(crate) fn deserialize_cidrs<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Vec<Cidr>, D::Error> {
+    Vec::<String>::deserialize(deserializer)?
+        .iter()
+        .map(|value| {
+            Cidr::parse(value)
+                .ok_or_else(|| serde::de::Error::custom(format!("invalid network {}", value)))
+        })
+        .collect()
+}
+
+impl AccessList {
+    fn permits(&self, ip: IpAddr) -> bool {
+        let allowed = self.allow.is_empty() || self.allow.iter().any(|cidr| cidr.contains(ip));
+        allowed && !self.deny.iter().any(|cidr| cidr.contains(ip))
+    }
+}
+
+impl AccessConfig {
+    /// Whether `ip` may access any of `paths`, e.g. the requested and the
+    /// rewritten path. The global lists apply first, then the rule with the
+    /// longest prefix matching one of the paths.
+    pub fn permits(&self, ip: IpAddr, paths: &[&str]) -> bool {
+        if !self.global.permits(ip) {
+            return false;
+        }
+        self.paths
+            .iter()
+            .filter(|rule| paths.iter().any(|path| path.starts_with(&rule.prefix)))
+            .max_by_key(|rule| rule.prefix.len())
+            .is_none_or(|rule| rule.list.permits(ip))
+    }
+}
+
+/// The address of the client behind `peer`. Only when `peer` is one of the
+/// `trusted` proxies are Forwarded or X-Forwarded-For believed, walking
+/// from the nearest hop outwards until an address that isn't trusted. Any
+/// client can send those headers, so the first untrusted hop is the client.
+pub fn client_ip(peer: IpAddr, trusted: &[Cidr], headers: &Headers) -> IpAddr {
+    if trusted.is_empty() {
+        return peer;
+    }
+    // Forwarded (RFC 7239) wins when a proxy sent both
+    let forwarded = joined(headers, "forwarded");
+    let hops: Vec<Option<IpAddr>> = if !forwarded.is_empty() {
+        forwarded
+            .split(',')
+            .map(|element| {
+                element
+                    .split(';')
+                    .filter_map(|pair| pair.split_once('='))
+                    .find(|(name, _)| name.trim().eq_ignore_ascii_case("for"))
+                    .and_then(|(_, node)| parse_node(node))
+            })
+            .collect()
+    } else {
+        joined(headers, "x-forwarded-for")
+            .split(',')
+            .filter(|hop| !hop.trim().is_empty())
+            .map(parse_node)
+            .collect()
+    };
+
+    let mut client = peer;
+    for hop in hops.into_iter().rev() {
+        if !trusted.iter().any(|cidr| cidr.contains(client)) {
+            break;
+        }
+        // `unknown` or an obfuscated name, nothing further out can be used
+        let Some(ip) = hop else {
+            break;
+        };
+        client = ip;
+    }
+    client
+}
+
+// every field of this name as one comma separated list
+fn joined(headers: &Headers, name: &str) -> String {
+    headers
+        .iter()
+        .filter(|(field, _)| field.eq_ignore_ascii_case(name))
+        .map(|(_, value)| value)
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+// `192.0.2.1`, `192.0.2.1:4711`, `"[2001:db8::1]:4711"` or a bare IPv6
+// address
+fn parse_node(node: &str) -> Option<IpAddr> {
+    let node = node.trim().trim_matches('"');
+    if let Some(rest) = node.strip_prefix('[') {
+        return rest.split_once(']')?.0.parse().ok();
+    }
+    node.parse().ok().or_else(|| {
+        let (address, _port) = node.rsplit_once(':')?;
+        address.parse().ok()
+    })
+}
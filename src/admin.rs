@@ -0,0 +1,288 @@
+//! The read-only built-in endpoints below `/_nebula/`, each switched on in
+//! the [admin] section.
+
+use crate::config::{ListenConfig, NebulaConfig};
+use crate::error;
+use crate::http_date;
+use crate::request::Request;
+use crate::response::{escape_html, Response};
+use crate::state::ServerState;
+use crate::uri;
+use base64::Engine;
+use serde::Serialize;
+use std::fmt::Write;
+use std::net::SocketAddr;
+
+pub enum Endpoint {
+    Echo,
+    Events,
+    Health,
+    Ready,
+    Metrics,
+    Status,
+}
+
+#[derive(Serialize)]
+struct Readiness<'a> {
+    status: &'a str,
+    // why the server isn't ready, empty when it is
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    reasons: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct Echo<'a> {
+    method: &'a str,
+    target: &'a str,
+    // decoded and normalized, as the server looks it up
+    path: &'a str,
+    version: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    remote_addr: Option<String>,
+    // name and value pairs in the order they were sent, repeats included
+    query: Vec<(String, String)>,
+    headers: Vec<(&'a str, &'a str)>,
+    body: String,
+    // "utf-8", or "base64" for bodies that aren't text
+    body_encoding: &'a str,
+}
+
+/// Whether `listen` serves the endpoints, which is every listener unless
+/// [admin] has a port of its own.
+pub fn served_on(config: &NebulaConfig, listen: &ListenConfig) -> bool {
+    listen.admin || config.admin.port.is_none()
+}
+
+/// The enabled endpoint at `path` on `listen`, if any.
+pub fn find(config: &NebulaConfig, listen: &ListenConfig, path: &str) -> Option<Endpoint> {
+    let admin = &config.admin;
+    if !served_on(config, listen) {
+        return None;
+    }
+    match path {
+        "/_nebula/echo" if admin.echo => Some(Endpoint::Echo),
+        "/_nebula/metrics" if admin.metrics => Some(Endpoint::Metrics),
+        "/_nebula/status" if admin.status => Some(Endpoint::Status),
+        "/_nebula/events" if admin.events => Some(Endpoint::Events),
+        "/_nebula/healthz" if admin.health => Some(Endpoint::Health),
+        "/_nebula/readyz" if admin.health => Some(Endpoint::Ready),
+        _ => None,
+    }
+}
+
+/// Answers a GET for `endpoint`.
+pub fn respond(endpoint: Endpoint, config: &NebulaConfig, state: &ServerState) -> Response {
+    match endpoint {
+        Endpoint::Echo => unreachable!("answered by echo, which needs the request"),
+        Endpoint::Events => Response::events(state.reload_events()),
+        Endpoint::Metrics => Response::builder()
+            .header("Content-Type", "text/plain; version=0.0.4")
+            .body(state.metrics().render(state))
+            .build(),
+        Endpoint::Status => Response::builder()
+            .header("Content-Type", "text/html; charset=utf-8")
+            .header("Cache-Control", "no-store")
+            .body(status_page(config, state))
+            .build(),
+        // the process answers requests, that's all liveness means
+        Endpoint::Health => Response::json(&Readiness {
+            status: "ok",
+            reasons: Vec::new(),
+        }),
+        Endpoint::Ready => {
+            let reasons = not_ready(config, state);
+            let mut response = Response::json(&Readiness {
+                status: if reasons.is_empty() {
+                    "ready"
+                } else {
+                    "not_ready"
+                },
+                reasons: reasons.clone(),
+            });
+            if !reasons.is_empty() {
+                response.set_status(503);
+            }
+            response
+        }
+    }
+}
+
+/// The request to `/_nebula/echo` as the server parsed it, `path` being
+/// the normalized one.
+pub fn echo(
+    request: &Request,
+    path: &str,
+    body: &[u8],
+    remote_addr: Option<SocketAddr>,
+) -> Response {
+    let (body, body_encoding) = match std::str::from_utf8(body) {
+        Ok(text) => (text.to_string(), "utf-8"),
+        Err(_) => (
+            base64::engine::general_purpose::STANDARD.encode(body),
+            "base64",
+        ),
+    };
+    let mut response = Response::json(&Echo {
+        method: &request.method,
+        target: &request.target,
+        path,
+        version: &request.version,
+        remote_addr: remote_addr.map(|addr| addr.to_string()),
+        query: request
+            .query
+            .as_deref()
+            .map(query_pairs)
+            .unwrap_or_default(),
+        headers: request.headers.iter().collect(),
+        body,
+        body_encoding,
+    });
+    response.headers_mut().insert("Cache-Control", "no-store");
+    response
+}
+
+fn query_pairs(query: &str) -> Vec<(String, String)> {
+    query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+            (form_decode(name), form_decode(value))
+        })
+        .collect()
+}
+
+// `+` stands for a space in query strings, broken escapes are kept as sent
+fn form_decode(text: &str) -> String {
+    let text = text.replace('+', " ");
+    uri::percent_decode(&text).unwrap_or(text)
+}
+
+// draining servers, a config file that failed to reload and proxy rules
+// without a healthy upstream keep the server out of a load balancer
+fn not_ready(config: &NebulaConfig, state: &ServerState) -> Vec<String> {
+    let mut reasons = Vec::new();
+    if state.is_shutting_down() {
+        reasons.push("shutting down".to_string());
+    }
+    if let Some(error) = state.last_reload_error() {
+        reasons.push(format!("last reload failed: {}", error));
+    }
+    for rule in &config.proxies {
+        let upstreams = rule.upstreams();
+        if !upstreams.is_empty()
+            && upstreams
+                .iter()
+                .all(|upstream| !state.upstreams().is_healthy(upstream))
+        {
+            reasons.push(format!("no healthy upstream for {}", rule.prefix));
+        }
+    }
+    reasons
+}
+
+// uptime, load and recent errors followed by what the config sets up
+fn status_page(config: &NebulaConfig, state: &ServerState) -> String {
+    let metrics = state.metrics();
+    let ready = not_ready(config, state);
+    let uptime = state.uptime().as_secs();
+
+    let mut rows = vec![
+        ("Version", env!("CARGO_PKG_VERSION").to_string()),
+        (
+            "Uptime",
+            format!(
+                "{}d {:02}:{:02}:{:02}",
+                uptime / 86400,
+                uptime % 86400 / 3600,
+                uptime % 3600 / 60,
+                uptime % 60
+            ),
+        ),
+        ("Open connections", state.active_connections().to_string()),
+        ("Requests served", metrics.total_requests().to_string()),
+        (
+            "Requests per second",
+            format!("{:.2} over the last minute", metrics.requests_per_second()),
+        ),
+        (
+            "Ready",
+            if ready.is_empty() {
+                "yes".to_string()
+            } else {
+                format!("no, {}", ready.join(", "))
+            },
+        ),
+    ];
+    let unix_socket = config.server.unix_socket.iter();
+    let listeners: Vec<String> = config
+        .server
+        .listeners()
+        .iter()
+        .map(|listen| format!("{}:{}", listen.address, listen.port))
+        .chain(unix_socket.map(|path| format!("unix:{}", path)))
+        .collect();
+    rows.push(("Listeners", listeners.join(", ")));
+    rows.push((
+        "Workers",
+        config
+            .server
+            .workers
+            .map_or("one per CPU".to_string(), |workers| workers.to_string()),
+    ));
+    rows.push(("Public directory", config.content.public_dir.clone()));
+    rows.push(("Virtual hosts", config.vhosts.len().to_string()));
+    rows.push(("Mounts", config.mounts.len().to_string()));
+    for rule in &config.proxies {
+        let upstreams: Vec<String> = rule
+            .upstreams()
+            .iter()
+            .map(|upstream| {
+                let health = if state.upstreams().is_healthy(upstream) {
+                    ""
+                } else {
+                    " (unhealthy)"
+                };
+                format!("{}{}", upstream, health)
+            })
+            .collect();
+        rows.push((
+            "Proxy",
+            format!("{} to {}", rule.prefix, upstreams.join(", ")),
+        ));
+    }
+
+    let mut html = String::from(
+        "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>Nebula status</title>\
+         <style>body{font-family:sans-serif;margin:2em}th{text-align:left;padding-right:2em}\
+         td,th{padding:.2em 0;vertical-align:top}pre{white-space:pre-wrap}</style>\
+         </head><body><h1>Nebula status</h1><table>",
+    );
+    for (name, value) in rows {
+        let _ = write!(
+            html,
+            "<tr><th>{}</th><td>{}</td></tr>",
+            name,
+            escape_html(&value)
+        );
+    }
+    html.push_str("</table><h2>Recent errors</h2>");
+
+    let errors = error::recent();
+    if errors.is_empty() {
+        html.push_str("<p>None since the server started.</p>");
+    } else {
+        html.push_str("<table>");
+        for (time, message) in errors.iter().rev() {
+            let _ = write!(
+                html,
+                "<tr><th>{}</th><td><pre>{}</pre></td></tr>",
+                http_date::format(*time),
+                escape_html(message)
+            );
+        }
+        html.push_str("</table>");
+    }
+    html.push_str("</body></html>\n");
+    html
+}
@@ -0,0 +1,110 @@
+//! HTTP Basic authentication against htpasswd files (RFC 7617).
+
+use argon2::{Argon2, PasswordHash, PasswordVerifier};
+use base64::Engine;
+use serde::Deserialize;
+use std::fs;
+
+#[derive(Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct AuthConfig {
+    // `[[auth.basic]]` rules, the longest matching prefix wins
+    pub basic: Vec<BasicAuthRule>,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct BasicAuthRule {
+    pub prefix: String,
+    // `user:hash` lines with bcrypt (`$2y$...`) or argon2 (`$argon2id$...`)
+    // hashes, re-read on every request so edits apply immediately
+    pub htpasswd: String,
+    #[serde(default = "default_realm")]
+    pub realm: String,
+}
+
+pub(crate) fn default_realm() -> String {
+    "Restricted".to_string()
+}
+
+pub enum AuthResult<'a> {
+    /// No rule covers the path.
+    Public,
+    Authorized(String),
+    /// Credentials are missing or wrong, the client should be challenged.
+    Unauthorized {
+        realm: &'a str,
+    },
+}
+
+/// Checks the Authorization header of a request against the rule covering
+/// any of `paths`, e.g. the requested and the rewritten path.
+pub fn check<'a>(
+    config: &'a AuthConfig,
+    paths: &[&str],
+    authorization: Option<&str>,
+) -> AuthResult<'a> {
+    let Some(rule) = config
+        .basic
+        .iter()
+        .filter(|rule| paths.iter().any(|path| path.starts_with(&rule.prefix)))
+        .max_by_key(|rule| rule.prefix.len())
+    else {
+        return AuthResult::Public;
+    };
+    check_rule(rule, authorization)
+}
+
+/// Checks the Authorization header against the users of one rule.
+pub fn check_rule<'a>(rule: &'a BasicAuthRule, authorization: Option<&str>) -> AuthResult<'a> {
+    let unauthorized = AuthResult::Unauthorized { realm: &rule.realm };
+    let Some((user, password)) = authorization.and_then(parse_basic) else {
+        return unauthorized;
+    };
+
+    let htpasswd = match fs::read_to_string(&rule.htpasswd) {
+        Ok(htpasswd) => htpasswd,
+        Err(e) => {
+            tracing::warn!("Failed to read {}: {}", rule.htpasswd, e);
+            return unauthorized;
+        }
+    };
+    let hash = htpasswd
+        .lines()
+        .filter_map(|line| line.trim().split_once(':'))
+        .find(|(name, _)| *name == user)
+        .map(|(_, hash)| hash);
+
+    match hash {
+        Some(hash) if verify(&password, hash) => AuthResult::Authorized(user),
+        _ => unauthorized,
+    }
+}
+
+// `Basic dXNlcjpwYXNz` -> ("user", "pass")
+fn parse_basic(authorization: &str) -> Option<(String, String)> {
+    let (scheme, credentials) = authorization.trim().split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("basic") {
+        return None;
+    }
+    let decoded = base64::engine::general_purpose::STANDARD
+        .decode(credentials.trim())
+        .ok()?;
+    let decoded = String::from_utf8(decoded).ok()?;
+    let (user, password) = decoded.split_once(':')?;
+    Some((user.to_string(), password.to_string()))
+}
+
+fn verify(password: &str, hash: &str) -> bool {
+    if hash.starts_with("$argon2") {
+        PasswordHash::new(hash).is_ok_and(|parsed| {
+            Argon2::default()
+                .verify_password(password.as_bytes(), &parsed)
+                .is_ok()
+        })
+    } else if hash.starts_with("$2") {
+        bcrypt::verify(password, hash).unwrap_or(false)
+    } else {
+        // plain text, crypt and MD5 entries aren't accepted
+        false
+    }
+}
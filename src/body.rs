@@ -0,0 +1,368 @@
+//! Response bodies, either in memory or streamed from a file or reader.
+
+use crate::listener::Stream;
+use memmap2::Mmap;
+use std::fs::File;
+use std::io::{self, BufReader, Read, Write};
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+use std::os::fd::RawFd;
+use std::sync::Arc;
+
+pub enum Body {
+    Bytes(Vec<u8>),
+    /// Contents shared with the file cache.
+    Shared(Arc<Vec<u8>>),
+    /// `len` bytes of `file` starting at `offset`, read while being sent so
+    /// memory use doesn't grow with the file size. The file may be shared
+    /// through the open file cache, so it is only read at explicit offsets.
+    File {
+        file: Arc<File>,
+        offset: u64,
+        len: u64,
+    },
+    /// `len` bytes of a memory mapped file starting at `offset`.
+    Mapped {
+        map: Mmap,
+        offset: usize,
+        len: usize,
+    },
+    /// `len` bytes produced by a reader, e.g. a route handler's stream.
+    Reader {
+        reader: Box<dyn Read + Send>,
+        len: u64,
+    },
+    /// Bytes produced by a reader until it ends, e.g. an event stream. The
+    /// length isn't known up front, so the connection is closed after it.
+    Stream(Box<dyn Read + Send>),
+}
+
+impl Body {
+    pub fn len(&self) -> u64 {
+        match self {
+            Body::Bytes(bytes) => bytes.len() as u64,
+            Body::Shared(bytes) => bytes.len() as u64,
+            Body::File { len, .. } => *len,
+            Body::Mapped { len, .. } => *len as u64,
+            Body::Reader { len, .. } => *len,
+            Body::Stream(_) => 0,
+        }
+    }
+
+    /// Whether only closing the connection can tell the client where the
+    /// body ends.
+    pub fn is_stream(&self) -> bool {
+        matches!(self, Body::Stream(_))
+    }
+
+    /// The body as a byte slice, `None` for files and readers that are
+    /// streamed.
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            Body::Bytes(bytes) => Some(bytes),
+            Body::Shared(bytes) => Some(bytes),
+            Body::Mapped { map, offset, len } => Some(&map[*offset..*offset + *len]),
+            Body::File { .. } | Body::Reader { .. } | Body::Stream(_) => None,
+        }
+    }
+
+    /// Maps `len` bytes of `file` into memory. The kernel pages the file in
+    /// as it is sent, sparing the copies of buffered reads.
+    pub fn map(file: &File, len: u64) -> io::Result<Body> {
+        // SAFETY: the map is only read from. Truncating the file while it is
+        // mapped makes further reads fault, which is the usual mmap caveat
+        // for files served from a directory nobody writes to concurrently.
+        let map = unsafe { Mmap::map(file)? };
+        let len = usize::try_from(len)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "file too large to map"))?;
+        if map.len() < len {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        Ok(Body::Mapped {
+            map,
+            offset: 0,
+            len,
+        })
+    }
+
+    /// Narrows the body to the bytes `start..=end`.
+    pub fn slice(self, start: u64, end: u64) -> Body {
+        match self {
+            Body::Mapped { map, offset, .. } => Body::Mapped {
+                map,
+                offset: offset + start as usize,
+                len: (end - start + 1) as usize,
+            },
+            Body::File { file, offset, .. } => Body::File {
+                file,
+                offset: offset + start,
+                len: end - start + 1,
+            },
+            Body::Reader { mut reader, .. } => {
+                // a failed skip shows up as a short body once it is sent
+                let _ = io::copy(&mut (&mut reader).take(start), &mut io::sink());
+                Body::Reader {
+                    reader,
+                    len: end - start + 1,
+                }
+            }
+            // unknown lengths can't be ranged, handlers never get here
+            Body::Stream(reader) => Body::Stream(reader),
+            body => {
+                let bytes = body.as_bytes().unwrap_or_default();
+                Body::Bytes(bytes[start as usize..=end as usize].to_vec())
+            }
+        }
+    }
+
+    /// The bytes `start..=end` of each range as a `multipart/byteranges`
+    /// body separated by `boundary`, each part labelled with
+    /// `content_type`. `None` for readers, which can't be read more than
+    /// once.
+    pub fn byteranges(
+        self,
+        ranges: &[(u64, u64)],
+        content_type: &str,
+        boundary: &str,
+    ) -> Option<Body> {
+        let total = self.len();
+        let part_head = |start: u64, end: u64| {
+            format!(
+                "--{}\r\nContent-Type: {}\r\nContent-Range: bytes {}-{}/{}\r\n\r\n",
+                boundary, content_type, start, end, total
+            )
+        };
+        let closing = format!("--{}--\r\n", boundary);
+
+        if let Body::File { file, offset, .. } = self {
+            // the parts are read from the file as they are sent, like the
+            // whole of it would be
+            let mut len = 0;
+            let mut reader: Box<dyn Read + Send> = Box::new(io::empty());
+            for &(start, end) in ranges {
+                let head = part_head(start, end);
+                len += head.len() as u64 + (end - start + 1) + 2;
+                let part = FileReader {
+                    file: file.clone(),
+                    offset: offset + start,
+                    remaining: end - start + 1,
+                };
+                reader = Box::new(
+                    reader
+                        .chain(io::Cursor::new(head))
+                        .chain(part)
+                        .chain(&b"\r\n"[..]),
+                );
+            }
+            len += closing.len() as u64;
+            let reader = Box::new(reader.chain(io::Cursor::new(closing)));
+            return Some(Body::Reader { reader, len });
+        }
+
+        let bytes = self.as_bytes()?;
+        let mut body = Vec::new();
+        for &(start, end) in ranges {
+            body.extend_from_slice(part_head(start, end).as_bytes());
+            body.extend_from_slice(&bytes[start as usize..=end as usize]);
+            body.extend_from_slice(b"\r\n");
+        }
+        body.extend_from_slice(closing.as_bytes());
+        Some(Body::Bytes(body))
+    }
+
+    /// Writes the whole body to `out`. Fails when a file shrank or a reader
+    /// ended early, since the announced Content-Length can't be met anymore.
+    pub fn write_to<W: Write>(self, out: &mut W) -> io::Result<u64> {
+        match self {
+            Body::File { file, offset, len } => {
+                let reader = FileReader {
+                    file,
+                    offset,
+                    remaining: len,
+                };
+                let mut reader = BufReader::with_capacity(64 * 1024, reader);
+                let written = io::copy(&mut reader, out)?;
+                if written < len {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "file shrank while it was sent",
+                    ));
+                }
+                Ok(written)
+            }
+            Body::Reader { reader, len } => {
+                let written = io::copy(&mut reader.take(len), out)?;
+                if written < len {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "body ended before its announced length",
+                    ));
+                }
+                Ok(written)
+            }
+            Body::Stream(mut reader) => {
+                // every read is written right away, a stream is only over
+                // when the reader ends or the client hangs up
+                let mut buf = [0; 8 * 1024];
+                let mut written = 0;
+                loop {
+                    let n = reader.read(&mut buf)?;
+                    if n == 0 {
+                        return Ok(written);
+                    }
+                    match out.write_all(&buf[..n]).and_then(|_| out.flush()) {
+                        Ok(()) => written += n as u64,
+                        Err(e) if is_hangup(&e) => return Ok(written),
+                        Err(e) => return Err(e),
+                    }
+                }
+            }
+            body => {
+                let bytes = body.as_bytes().unwrap_or_default();
+                out.write_all(bytes)?;
+                Ok(bytes.len() as u64)
+            }
+        }
+    }
+
+    /// Like `write_to`, but a file body goes from the page cache straight
+    /// to the socket with sendfile on Linux and macOS. Anywhere else, or
+    /// when the file system doesn't support it, the file is read and
+    /// written as usual.
+    pub fn send_to(self, stream: &mut Stream) -> io::Result<u64> {
+        #[cfg(any(target_os = "linux", target_os = "macos"))]
+        if let Body::File { file, offset, len } = &self {
+            use std::os::fd::{AsFd, AsRawFd};
+            if let Some(sent) =
+                sendfile(file.as_raw_fd(), stream.as_fd().as_raw_fd(), *offset, *len)?
+            {
+                return Ok(sent);
+            }
+        }
+        self.write_to(stream)
+    }
+}
+
+// `None` when the kernel refused before anything was sent
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+fn sendfile(file: RawFd, socket: RawFd, offset: u64, len: u64) -> io::Result<Option<u64>> {
+    // what a single call sends at most on Linux
+    const MAX_CHUNK: u64 = 0x7fff_f000;
+
+    let mut sent = 0;
+    while sent < len {
+        let chunk = (len - sent).min(MAX_CHUNK) as usize;
+        match sendfile_chunk(file, socket, offset + sent, chunk) {
+            Ok(0) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "file shrank while it was sent",
+                ))
+            }
+            Ok(n) => sent += n as u64,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e)
+                if sent == 0
+                    && matches!(
+                        e.raw_os_error(),
+                        Some(libc::EINVAL | libc::ENOSYS | libc::ENOTSUP)
+                    ) =>
+            {
+                return Ok(None)
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(Some(sent))
+}
+
+#[cfg(target_os = "linux")]
+fn sendfile_chunk(file: RawFd, socket: RawFd, offset: u64, count: usize) -> io::Result<usize> {
+    let mut offset = offset as libc::off_t;
+    // SAFETY: both fds come from `Body::send_to`, which borrows the file and
+    // the stream they belong to until sendfile returns, so neither can be
+    // closed meanwhile. `offset` is a live local the kernel writes back to.
+    match unsafe { libc::sendfile(socket, file, &mut offset, count) } {
+        -1 => Err(io::Error::last_os_error()),
+        n => Ok(n as usize),
+    }
+}
+
+#[cfg(target_os = "macos")]
+fn sendfile_chunk(file: RawFd, socket: RawFd, offset: u64, count: usize) -> io::Result<usize> {
+    // in: how much to send, out: how much was sent, even when it failed
+    let mut len = count as libc::off_t;
+    // SAFETY: both fds come from `Body::send_to`, which borrows the file and
+    // the stream they belong to until sendfile returns, so neither can be
+    // closed meanwhile. `len` is a live local, no headers are passed.
+    let result = unsafe {
+        libc::sendfile(
+            file,
+            socket,
+            offset as libc::off_t,
+            &mut len,
+            std::ptr::null_mut(),
+            0,
+        )
+    };
+    if result == -1 && len == 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(len as usize)
+}
+
+// reads `remaining` bytes from `offset` on, leaving the position of the
+// shared descriptor alone
+struct FileReader {
+    file: Arc<File>,
+    offset: u64,
+    remaining: u64,
+}
+
+impl Read for FileReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let max = buf
+            .len()
+            .min(usize::try_from(self.remaining).unwrap_or(usize::MAX));
+        let n = read_at(&self.file, &mut buf[..max], self.offset)?;
+        self.offset += n as u64;
+        self.remaining -= n as u64;
+        Ok(n)
+    }
+}
+
+#[cfg(unix)]
+fn read_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    std::os::unix::fs::FileExt::read_at(file, buf, offset)
+}
+
+#[cfg(windows)]
+fn read_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    std::os::windows::fs::FileExt::seek_read(file, buf, offset)
+}
+
+fn is_hangup(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::BrokenPipe
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+    )
+}
+
+impl From<&str> for Body {
+    fn from(text: &str) -> Self {
+        Body::Bytes(text.as_bytes().to_vec())
+    }
+}
+
+impl From<String> for Body {
+    fn from(text: String) -> Self {
+        Body::Bytes(text.into_bytes())
+    }
+}
+
+impl From<Vec<u8>> for Body {
+    fn from(bytes: Vec<u8>) -> Self {
+        Body::Bytes(bytes)
+    }
+}
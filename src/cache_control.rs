@@ -0,0 +1,68 @@
+//! Cache-Control values picked by path.
+
+use serde::Deserialize;
+
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct CacheControlConfig {
+    // sent when no rule matches, an empty string sends nothing
+    pub default: String,
+    // first matching rule wins
+    pub rules: Vec<CacheRule>,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct CacheRule {
+    // `*` matches any run of characters, e.g. `/assets/*` or `*.html`
+    pub pattern: String,
+    pub value: String,
+}
+
+impl Default for CacheControlConfig {
+    fn default() -> Self {
+        CacheControlConfig {
+            default: "max-age=86400".to_string(),
+            rules: Vec::new(),
+        }
+    }
+}
+
+impl CacheControlConfig {
+    /// Picks the value for a response. Rules are matched against the request
+    /// path and the file it was served from, so `*.html` also covers `/`.
+    pub fn value_for(&self, path: &str, file: Option<&str>) -> Option<&str> {
+        let value = self
+            .rules
+            .iter()
+            .find(|rule| {
+                glob_match(&rule.pattern, path)
+                    || file.is_some_and(|file| glob_match(&rule.pattern, file))
+            })
+            .map_or(self.default.as_str(), |rule| rule.value.as_str());
+        (!value.is_empty()).then_some(value)
+    }
+}
+
+/// Matches `text` against a pattern where `*` stands for any run of
+/// characters, slashes included.
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or("");
+    let Some(mut rest) = text.strip_prefix(first) else {
+        return false;
+    };
+
+    let parts: Vec<&str> = parts.collect();
+    let Some((last, middle)) = parts.split_last() else {
+        // no `*` at all
+        return rest.is_empty();
+    };
+
+    for part in middle {
+        match rest.find(part) {
+            Some(pos) => rest = &rest[pos + part.len()..],
+            None => return false,
+        }
+    }
+    rest.ends_with(last)
+}
@@ -0,0 +1,104 @@
+use clap::{Parser, Subcommand};
+use nebula::config::NebulaConfig;
+use std::path::PathBuf;
+
+/// HTTP server built in Rust for learning purposes.
+#[derive(Parser)]
+#[command(name = "http-nebula", version)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// Configuration file to load [default: nebula.toml]
+    #[arg(short, long)]
+    pub config: Option<PathBuf>,
+
+    /// Refuse to start when the config file is missing or invalid instead
+    /// of using the defaults, always on when --config is given
+    #[arg(long)]
+    pub strict_config: bool,
+
+    /// Address to bind to, overrides server.address
+    #[arg(short, long)]
+    pub address: Option<String>,
+
+    /// Port to listen on, overrides server.port
+    #[arg(short, long)]
+    pub port: Option<u16>,
+
+    /// Directory to serve, overrides content.public_dir
+    #[arg(short, long)]
+    pub dir: Option<String>,
+
+    /// Detach from the terminal and run in the background
+    #[arg(long)]
+    pub daemon: bool,
+
+    /// More output, -v for debug messages, -vv for trace messages and -vvv
+    /// also dumps every request and response head like logging.dump_wire
+    #[arg(short, long, action = clap::ArgAction::Count)]
+    pub verbose: u8,
+}
+
+/// Checking the config, and commands for a server that is already
+/// running, found through server.pid_file.
+#[derive(Subcommand)]
+pub enum Command {
+    /// Check the configuration file and exit, non-zero when it has problems
+    Check,
+    /// Shut the server down gracefully
+    Stop,
+    /// Make the server reload its configuration file
+    Reload,
+    /// Replace the server with the binary on disk without dropping
+    /// connections
+    Upgrade,
+    /// Print a link to a path below a [[mount]] with a secret, valid for a
+    /// while
+    Sign {
+        /// The path the link is for, like /private/report.pdf
+        path: String,
+        /// Seconds until the link expires
+        #[arg(long, default_value_t = 3600)]
+        expires_in: u64,
+    },
+}
+
+impl Cli {
+    pub fn config_path(&self) -> PathBuf {
+        self.config
+            .clone()
+            .unwrap_or_else(|| PathBuf::from("nebula.toml"))
+    }
+
+    /// Whether a broken config aborts startup. A file that was named
+    /// explicitly is expected to be used.
+    pub fn strict_config(&self) -> bool {
+        self.strict_config || self.config.is_some()
+    }
+
+    /// Layers the flags that were given on top of the loaded config.
+    pub fn apply(&self, config: &mut NebulaConfig) {
+        // an explicit address or port replaces any configured listeners
+        if self.address.is_some() || self.port.is_some() {
+            config.server.listen.clear();
+        }
+        if let Some(address) = &self.address {
+            config.server.address = address.clone();
+        }
+        if let Some(port) = self.port {
+            config.server.port = port;
+        }
+        if let Some(dir) = &self.dir {
+            config.content.public_dir = dir.clone();
+        }
+        match self.verbose {
+            0 => {}
+            1 => config.logging.level = "info,nebula=debug".to_string(),
+            _ => config.logging.level = "info,nebula=trace".to_string(),
+        }
+        if self.verbose >= 3 {
+            config.logging.dump_wire = true;
+        }
+    }
+}
@@ -0,0 +1,168 @@
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use serde::Deserialize;
+use std::io::{self, Write};
+
+/// Larger files are streamed as they are instead of being loaded into
+/// memory for compression, precompressed sidecars still apply to them.
+pub const MAX_COMPRESS_SIZE: u64 = 16 * 1024 * 1024;
+
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct CompressionConfig {
+    pub enabled: bool,
+    // bodies smaller than this are not worth the CPU time
+    pub min_size: usize,
+    pub mime_types: Vec<String>,
+    // 0 (fastest) to 11 (smallest)
+    pub brotli_quality: u32,
+    // serve `foo.js.br` / `foo.js.gz` next to `foo.js` when present
+    pub precompressed: bool,
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        CompressionConfig {
+            enabled: true,
+            min_size: 1024,
+            mime_types: [
+                "text/html",
+                "text/css",
+                "text/plain",
+                "text/xml",
+                "application/javascript",
+                "application/json",
+                "application/xml",
+                "image/svg+xml",
+            ]
+            .iter()
+            .map(|mime| mime.to_string())
+            .collect(),
+            brotli_quality: 5,
+            precompressed: true,
+        }
+    }
+}
+
+impl CompressionConfig {
+    pub fn should_compress(&self, content_type: &str, len: usize) -> bool {
+        // ignore parameters like `; charset=utf-8`
+        let mime = content_type.split(';').next().unwrap_or("").trim();
+        self.enabled && len >= self.min_size && self.mime_types.iter().any(|m| m == mime)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Encoding {
+    Brotli,
+    Gzip,
+}
+
+impl Encoding {
+    /// The content-coding token used in Accept-Encoding and Content-Encoding.
+    pub fn name(self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Gzip => "gzip",
+        }
+    }
+
+    /// File extension of precompressed sidecar files.
+    pub fn extension(self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Gzip => "gz",
+        }
+    }
+}
+
+const ENCODINGS: [Encoding; 2] = [Encoding::Brotli, Encoding::Gzip];
+
+/// Picks the encoding with the highest q-value, brotli wins ties since it
+/// produces smaller output for text.
+pub fn negotiate(accept_encoding: &str) -> Option<Encoding> {
+    negotiate_from(accept_encoding, &ENCODINGS)
+}
+
+/// Looks for precompressed variants of `path` and returns the best one the
+/// client accepts along with its location on disk.
+pub fn find_precompressed(
+    path: &str,
+    accept_encoding: &str,
+    is_file: impl Fn(&str) -> bool,
+) -> Option<(Encoding, String)> {
+    let sidecar = |encoding: Encoding| format!("{}.{}", path, encoding.extension());
+    let available: Vec<Encoding> = ENCODINGS
+        .into_iter()
+        .filter(|encoding| is_file(&sidecar(*encoding)))
+        .collect();
+
+    negotiate_from(accept_encoding, &available).map(|encoding| (encoding, sidecar(encoding)))
+}
+
+fn negotiate_from(accept_encoding: &str, available: &[Encoding]) -> Option<Encoding> {
+    available
+        .iter()
+        .copied()
+        .map(|encoding| (encoding, encoding_quality(accept_encoding, encoding.name())))
+        .filter(|(_, quality)| *quality > 0.0)
+        .fold(
+            None,
+            |best: Option<(Encoding, f32)>, candidate| match best {
+                Some(best) if best.1 >= candidate.1 => Some(best),
+                _ => Some(candidate),
+            },
+        )
+        .map(|(encoding, _)| encoding)
+}
+
+pub fn compress(
+    encoding: Encoding,
+    data: &[u8],
+    config: &CompressionConfig,
+) -> io::Result<Vec<u8>> {
+    match encoding {
+        Encoding::Brotli => brotli(data, config.brotli_quality),
+        Encoding::Gzip => gzip(data),
+    }
+}
+
+/// Returns the q-value the client assigned to `coding` in an Accept-Encoding
+/// header, falling back to a `*` entry and 0 when the coding isn't listed.
+pub fn encoding_quality(accept_encoding: &str, coding: &str) -> f32 {
+    let mut wildcard = None;
+
+    for entry in accept_encoding.split(',') {
+        let mut params = entry.split(';');
+        let name = params.next().unwrap_or("").trim();
+        let quality = params
+            .filter_map(|param| param.trim().strip_prefix("q="))
+            .find_map(|q| q.trim().parse::<f32>().ok())
+            .unwrap_or(1.0);
+
+        if name.eq_ignore_ascii_case(coding) {
+            return quality;
+        }
+        if name == "*" {
+            wildcard = Some(quality);
+        }
+    }
+
+    wildcard.unwrap_or(0.0)
+}
+
+fn brotli(data: &[u8], quality: u32) -> io::Result<Vec<u8>> {
+    let mut output = Vec::with_capacity(data.len() / 2);
+    {
+        let mut encoder = brotli::CompressorWriter::new(&mut output, 4096, quality.min(11), 22);
+        encoder.write_all(data)?;
+        encoder.flush()?;
+    }
+    Ok(output)
+}
+
+fn gzip(data: &[u8]) -> io::Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::with_capacity(data.len() / 2), Compression::default());
+    encoder.write_all(data)?;
+    encoder.finish()
+}
@@ -0,0 +1,730 @@
+use crate::access::{AccessConfig, Cidr};
+use crate::auth::AuthConfig;
+use crate::cache_control::CacheControlConfig;
+use crate::compression::CompressionConfig;
+use crate::cors::CorsConfig;
+use crate::digest::EtagConfig;
+use crate::error::NebulaError;
+use crate::file_cache::FileCacheConfig;
+use crate::headers::HeadersConfig;
+use crate::hotlink::{HotlinkAction, HotlinkConfig};
+use crate::include;
+use crate::logging::{self, LoggingConfig};
+use crate::open_files::OpenFileCacheConfig;
+use crate::proxy::ProxyConfig;
+use crate::rate_limit::RateLimitConfig;
+use crate::rewrite::{RedirectRule, RewriteRule};
+use crate::telemetry::{self, TelemetryConfig};
+use crate::throttle::LimitsConfig;
+use crate::upload::UploadConfig;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+#[derive(Deserialize, Clone)]
+pub struct NebulaConfig {
+    pub server: ServerConfig,
+    pub content: ContentConfig,
+    #[serde(default)]
+    pub compression: CompressionConfig,
+    #[serde(default)]
+    pub cache_control: CacheControlConfig,
+    #[serde(default)]
+    pub file_cache: FileCacheConfig,
+    #[serde(default)]
+    pub open_file_cache: OpenFileCacheConfig,
+    #[serde(default)]
+    pub etag: EtagConfig,
+    // status code -> page relative to public_dir, e.g. `404 = "errors/404.html"`
+    #[serde(default)]
+    pub errors: HashMap<String, String>,
+    // extension -> Content-Type, e.g. `webmanifest = "application/manifest+json"`
+    #[serde(default)]
+    pub mime: HashMap<String, String>,
+    #[serde(default)]
+    pub logging: LoggingConfig,
+    #[serde(default)]
+    pub telemetry: TelemetryConfig,
+    #[serde(default)]
+    pub admin: AdminConfig,
+    #[serde(default)]
+    pub performance: PerformanceConfig,
+    #[serde(default)]
+    pub rate_limit: RateLimitConfig,
+    #[serde(default)]
+    pub limits: LimitsConfig,
+    #[serde(default)]
+    pub access: AccessConfig,
+    #[serde(default)]
+    pub cors: CorsConfig,
+    #[serde(default)]
+    pub hotlink: HotlinkConfig,
+    #[serde(default)]
+    pub upload: UploadConfig,
+    #[serde(default)]
+    pub headers: HeadersConfig,
+    #[serde(default)]
+    pub auth: AuthConfig,
+    // `[[vhost]]` entries, requests for other hosts are served from [content]
+    #[serde(default, rename = "vhost")]
+    pub vhosts: Vec<VhostConfig>,
+    // `[[mount]]` entries serving other directories below a URL prefix
+    #[serde(default, rename = "mount")]
+    pub mounts: Vec<MountConfig>,
+    // `[[proxy]]` rules, matching paths are forwarded to an upstream server
+    #[serde(default, rename = "proxy")]
+    pub proxies: Vec<ProxyConfig>,
+    // `[[redirect]]` rules, checked before anything else
+    #[serde(default, rename = "redirect")]
+    pub redirects: Vec<RedirectRule>,
+    // `[[rewrite]]` rules, applied in order after redirects
+    #[serde(default, rename = "rewrite")]
+    pub rewrites: Vec<RewriteRule>,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct ServerConfig {
+    pub address: String,
+    pub port: u16,
+    #[serde(default = "default_keep_alive_timeout")]
+    pub keep_alive_timeout: u64,
+    #[serde(default = "default_max_requests_per_connection")]
+    pub max_requests_per_connection: usize,
+    // defaults to the number of available CPUs
+    pub workers: Option<usize>,
+    #[serde(default = "default_queue_size")]
+    pub queue_size: usize,
+    #[serde(default = "default_max_request_line")]
+    pub max_request_line: usize,
+    // total size of all header lines following the request line
+    #[serde(default = "default_max_header_size")]
+    pub max_header_size: usize,
+    // seconds in-flight connections get to finish after SIGINT/SIGTERM
+    #[serde(default = "default_drain_timeout")]
+    pub drain_timeout: u64,
+    // seconds from the first byte of a request head until its end, and the
+    // slowest rate in bytes per second it may arrive at (0 means any)
+    #[serde(default = "default_header_timeout")]
+    pub header_timeout: u64,
+    #[serde(default)]
+    pub min_header_rate: u64,
+    // open connections in total and per client IP, 0 means unlimited.
+    // connections over the limit get an immediate 503
+    #[serde(default)]
+    pub max_connections: usize,
+    #[serde(default)]
+    pub max_connections_per_ip: usize,
+    // `[[server.listen]]` entries, address and port above are used when empty
+    #[serde(default)]
+    pub listen: Vec<ListenConfig>,
+    #[serde(default)]
+    pub server_tokens: ServerTokens,
+    // request bodies over this many bytes get a 413, 0 means unlimited
+    #[serde(default = "default_max_body_size")]
+    pub max_body_size: u64,
+    // load balancers whose Forwarded or X-Forwarded-For header is believed,
+    // the client IP found there is used for rate limits, access lists and
+    // the access log
+    #[serde(default, deserialize_with = "crate::access::deserialize_cidrs")]
+    pub trusted_proxies: Vec<Cidr>,
+    // a unix domain socket to listen on, address and port above aren't
+    // bound then unless [[server.listen]] entries are given too
+    pub unix_socket: Option<String>,
+    // permissions of the socket file, e.g. 0o660, the umask decides when
+    // not set
+    pub unix_socket_mode: Option<u32>,
+    // written at startup and removed on exit, the stop and reload commands
+    // find the server through it
+    pub pid_file: Option<String>,
+    // copies of the server forked at startup, each with its own
+    // SO_REUSEPORT sockets. Linux only, only read at startup
+    #[serde(default = "default_processes")]
+    pub processes: usize,
+}
+
+/// How much the Server header tells about the server.
+#[derive(Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ServerTokens {
+    /// `Nebula/0.2.0`
+    #[default]
+    Full,
+    /// `Nebula`
+    Minimal,
+    /// No Server header at all.
+    Off,
+}
+
+impl ServerTokens {
+    pub fn header(self) -> Option<&'static str> {
+        match self {
+            ServerTokens::Full => Some(concat!("Nebula/", env!("CARGO_PKG_VERSION"))),
+            ServerTokens::Minimal => Some("Nebula"),
+            ServerTokens::Off => None,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone)]
+pub struct ListenConfig {
+    pub address: String,
+    pub port: u16,
+    // IPv6 sockets don't accept IPv4 connections unless this is turned off
+    #[serde(default = "default_ipv6_only")]
+    pub ipv6_only: bool,
+    // only serve the vhosts with these names on this listener
+    pub vhosts: Option<Vec<String>>,
+    // every connection starts with a PROXY protocol v1 or v2 header from a
+    // load balancer, the client address in it is used instead of the peer's
+    #[serde(default)]
+    pub proxy_protocol: bool,
+    // the listener for [admin] port, it serves nothing but /_nebula/
+    #[serde(skip)]
+    pub admin: bool,
+}
+
+impl ListenConfig {
+    pub fn new(address: &str, port: u16) -> ListenConfig {
+        ListenConfig {
+            address: address.to_string(),
+            port,
+            ipv6_only: default_ipv6_only(),
+            vhosts: None,
+            proxy_protocol: false,
+            admin: false,
+        }
+    }
+}
+
+impl ServerConfig {
+    /// The TCP listeners, the unix socket isn't one of them.
+    pub fn listeners(&self) -> Vec<ListenConfig> {
+        if self.listen.is_empty() && self.unix_socket.is_some() {
+            Vec::new()
+        } else if self.listen.is_empty() {
+            vec![ListenConfig::new(&self.address, self.port)]
+        } else {
+            self.listen.clone()
+        }
+    }
+
+    pub fn body_limit(&self) -> u64 {
+        match self.max_body_size {
+            0 => u64::MAX,
+            limit => limit,
+        }
+    }
+}
+
+fn default_max_body_size() -> u64 {
+    10 * 1024 * 1024
+}
+
+fn default_keep_alive_timeout() -> u64 {
+    5
+}
+
+fn default_max_requests_per_connection() -> usize {
+    100
+}
+
+fn default_queue_size() -> usize {
+    128
+}
+
+fn default_max_request_line() -> usize {
+    8192
+}
+
+fn default_max_header_size() -> usize {
+    16384
+}
+
+fn default_processes() -> usize {
+    1
+}
+
+fn default_drain_timeout() -> u64 {
+    30
+}
+
+fn default_header_timeout() -> u64 {
+    10
+}
+
+fn default_ipv6_only() -> bool {
+    true
+}
+
+#[derive(Deserialize, Clone)]
+pub struct ContentConfig {
+    pub public_dir: String,
+    // tried in order when a directory is requested
+    #[serde(default = "default_index_files")]
+    pub index_files: Vec<String>,
+    // serve the first index file for every path that isn't a file on disk
+    #[serde(default)]
+    pub spa_fallback: bool,
+    // added to text Content-Types, an empty string leaves them without one
+    #[serde(default = "default_charset")]
+    pub charset: String,
+    // answer paths with a component starting with `.` with a 404
+    #[serde(default = "default_deny_dotfiles")]
+    pub deny_dotfiles: bool,
+    #[serde(default)]
+    pub follow_symlinks: FollowSymlinks,
+    #[serde(default)]
+    pub trailing_slash: TrailingSlash,
+    // PUT stores files and DELETE removes them, for authenticated users
+    #[serde(default)]
+    pub writable: bool,
+    // PROPFIND listings for WebDAV clients, plus MKCOL, COPY, MOVE and
+    // locks when writable
+    #[serde(default)]
+    pub webdav: bool,
+    // honor .nebula.toml files in served directories
+    #[serde(default)]
+    pub directory_overrides: bool,
+    // serve `page.html` or `page.json` for `/page`, as the Accept header
+    // prefers
+    #[serde(default)]
+    pub negotiate: bool,
+    // serve `page.en.html` or `page.de.html` for `/page.html`, as the
+    // Accept-Language header prefers
+    #[serde(default)]
+    pub negotiate_language: bool,
+    // served when the client accepts none of the languages there are
+    #[serde(default = "default_language")]
+    pub default_language: String,
+}
+
+/// How paths ending in a slash are canonicalized with a 301.
+#[derive(Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum TrailingSlash {
+    /// `/docs` -> `/docs/` when docs is a directory.
+    #[default]
+    Add,
+    /// `/docs/` -> `/docs` for anything that exists.
+    Remove,
+    /// Serve both forms as they are.
+    Ignore,
+}
+
+/// Which symlinks below the public directory are followed.
+#[derive(Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum FollowSymlinks {
+    /// Refuse any path that passes through a symlink.
+    Off,
+    /// Follow symlinks as long as the real path stays inside the public
+    /// directory.
+    #[default]
+    SameRoot,
+    All,
+}
+
+fn default_index_files() -> Vec<String> {
+    vec!["index.html".to_string()]
+}
+
+fn default_charset() -> String {
+    "utf-8".to_string()
+}
+
+fn default_deny_dotfiles() -> bool {
+    true
+}
+
+fn default_language() -> String {
+    "en".to_string()
+}
+
+#[derive(Deserialize, Clone)]
+pub struct MountConfig {
+    // `/static` covers `/static` and everything below `/static/`
+    pub url: String,
+    pub dir: String,
+    // the files are only served through links made with `http-nebula sign`
+    // and this secret, until they expire
+    pub secret: Option<String>,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct VhostConfig {
+    // referenced by `server.listen.vhosts`
+    pub name: Option<String>,
+    // exact names or wildcards like `*.example.com`
+    pub hostnames: Vec<String>,
+    pub public_dir: String,
+    // falls back to content.index_files
+    pub index_files: Option<Vec<String>>,
+    // falls back to content.charset
+    pub charset: Option<String>,
+    // falls back to content.spa_fallback
+    pub spa_fallback: Option<bool>,
+    // serve hosts that match no vhost from this one instead of [content]
+    #[serde(default)]
+    pub default: bool,
+}
+
+impl VhostConfig {
+    fn matches(&self, host: &str) -> bool {
+        self.hostnames
+            .iter()
+            .any(|name| match name.strip_prefix("*.") {
+                Some(domain) => host
+                    .strip_suffix(domain)
+                    .is_some_and(|subdomain| subdomain.ends_with('.')),
+                None => name.eq_ignore_ascii_case(host),
+            })
+    }
+}
+
+/// The document root a request is served from.
+pub struct Site<'a> {
+    pub public_dir: &'a str,
+    pub index_files: &'a [String],
+    pub charset: &'a str,
+    pub spa_fallback: bool,
+}
+
+impl NebulaConfig {
+    /// Finds the mount with the longest URL prefix covering `path` and
+    /// returns its directory along with the rest of the path.
+    pub fn mount_for<'a>(&'a self, path: &'a str) -> Option<(&'a str, &'a str)> {
+        self.mount(path)
+            .map(|(mount, rest)| (mount.dir.as_str(), rest))
+    }
+
+    /// The secret links below `path` have to be signed with, if its mount
+    /// has one.
+    pub fn mount_secret(&self, path: &str) -> Option<&str> {
+        self.mount(path)?.0.secret.as_deref()
+    }
+
+    fn mount<'a, 'p>(&'a self, path: &'p str) -> Option<(&'a MountConfig, &'p str)> {
+        self.mounts
+            .iter()
+            .filter_map(|mount| {
+                let rest = path.strip_prefix(mount.url.trim_end_matches('/'))?;
+                (rest.is_empty() || rest.starts_with('/')).then_some((mount, rest))
+            })
+            .max_by_key(|(mount, _)| mount.url.len())
+    }
+
+    /// Picks the vhost for a Host header value, falling back to the default
+    /// vhost and then to the [content] section. `allowed` limits the choice
+    /// to the vhosts a listener is bound to.
+    pub fn site_for(&self, host: Option<&str>, allowed: Option<&[String]>) -> Site<'_> {
+        let host = host.map(|host| strip_port(host).to_ascii_lowercase());
+        let candidates = || {
+            self.vhosts.iter().filter(move |vhost| match allowed {
+                Some(allowed) => vhost
+                    .name
+                    .as_ref()
+                    .is_some_and(|name| allowed.contains(name)),
+                None => true,
+            })
+        };
+
+        let vhost = host
+            .and_then(|host| candidates().find(|vhost| vhost.matches(&host)))
+            .or_else(|| candidates().find(|vhost| vhost.default));
+
+        match vhost {
+            Some(vhost) => Site {
+                public_dir: &vhost.public_dir,
+                index_files: vhost
+                    .index_files
+                    .as_deref()
+                    .unwrap_or(&self.content.index_files),
+                charset: vhost.charset.as_deref().unwrap_or(&self.content.charset),
+                spa_fallback: vhost.spa_fallback.unwrap_or(self.content.spa_fallback),
+            },
+            None => Site {
+                public_dir: &self.content.public_dir,
+                index_files: &self.content.index_files,
+                charset: &self.content.charset,
+                spa_fallback: self.content.spa_fallback,
+            },
+        }
+    }
+
+    /// What parsing can't catch: files and directories that don't exist
+    /// and names that refer to nothing. Each problem starts with the key it
+    /// was found at, e.g. `vhost[1].public_dir`.
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        let mut dir = |key: String, dir: &str| {
+            if !Path::new(dir).is_dir() {
+                problems.push(format!("{}: {} is not a directory", key, dir));
+            }
+        };
+        dir("content.public_dir".to_string(), &self.content.public_dir);
+        for (i, vhost) in self.vhosts.iter().enumerate() {
+            dir(format!("vhost[{}].public_dir", i), &vhost.public_dir);
+        }
+        for (i, mount) in self.mounts.iter().enumerate() {
+            dir(format!("mount[{}].dir", i), &mount.dir);
+        }
+        for (i, mount) in self.mounts.iter().enumerate() {
+            if mount
+                .secret
+                .as_ref()
+                .is_some_and(|secret| secret.len() < 16)
+            {
+                problems.push(format!(
+                    "mount[{}].secret: shorter than 16 characters, links could be forged",
+                    i
+                ));
+            }
+        }
+
+        let mut errors: Vec<_> = self.errors.iter().collect();
+        errors.sort();
+        for (status, page) in errors {
+            if !status
+                .parse::<u16>()
+                .is_ok_and(|status| (400..600).contains(&status))
+            {
+                problems.push(format!("errors.{}: not an error status", status));
+            }
+            let public_dirs = std::iter::once(&self.content.public_dir)
+                .chain(self.vhosts.iter().map(|vhost| &vhost.public_dir));
+            for public_dir in public_dirs {
+                let path = Path::new(public_dir).join(page);
+                if !path.is_file() {
+                    problems.push(format!(
+                        "errors.{}: {} does not exist",
+                        status,
+                        path.display()
+                    ));
+                }
+            }
+        }
+
+        for (i, rule) in self.auth.basic.iter().enumerate() {
+            if !Path::new(&rule.htpasswd).is_file() {
+                problems.push(format!(
+                    "auth.basic[{}].htpasswd: {} does not exist",
+                    i, rule.htpasswd
+                ));
+            }
+        }
+
+        let log = &self.logging.access_log;
+        if log != "stdout" && log != "off" {
+            let parent = Path::new(log).parent().filter(|dir| dir != &Path::new(""));
+            if parent.is_some_and(|dir| !dir.is_dir()) {
+                problems.push(format!(
+                    "logging.access_log: the directory of {} does not exist",
+                    log
+                ));
+            }
+        }
+
+        if !logging::is_valid_level(&self.logging.level) {
+            problems.push(format!(
+                "logging.level: {:?} is not a valid filter",
+                self.logging.level
+            ));
+        }
+
+        let tracing = &self.telemetry;
+        if tracing.enabled {
+            if let Err(e) = telemetry::split_endpoint(&tracing.endpoint) {
+                problems.push(format!("telemetry.endpoint: {}", e));
+            }
+            if !(0.0..=1.0).contains(&tracing.sample_ratio) {
+                problems.push("telemetry.sample_ratio: has to be between 0 and 1".to_string());
+            }
+        }
+
+        let hotlink = &self.hotlink;
+        if hotlink.enabled {
+            if hotlink.action == HotlinkAction::Redirect && hotlink.redirect.is_empty() {
+                problems.push("hotlink.redirect: required by the redirect action".to_string());
+            }
+            if hotlink.action == HotlinkAction::Placeholder {
+                let path = Path::new(&self.content.public_dir).join(&hotlink.placeholder);
+                if !path.is_file() {
+                    problems.push(format!(
+                        "hotlink.placeholder: {} does not exist",
+                        path.display()
+                    ));
+                }
+            }
+        }
+
+        let names: Vec<&str> = self
+            .vhosts
+            .iter()
+            .filter_map(|v| v.name.as_deref())
+            .collect();
+        for (i, listen) in self.server.listen.iter().enumerate() {
+            for name in listen.vhosts.iter().flatten() {
+                if !names.contains(&name.as_str()) {
+                    problems.push(format!(
+                        "server.listen[{}].vhosts: no vhost is named {}",
+                        i, name
+                    ));
+                }
+            }
+        }
+        if self.vhosts.iter().filter(|vhost| vhost.default).count() > 1 {
+            problems.push("vhost: more than one is the default".to_string());
+        }
+        problems
+    }
+}
+
+// `example.com:8080` -> `example.com`, `[::1]:8080` -> `[::1]`
+pub fn strip_port(host: &str) -> &str {
+    match host.rfind(':') {
+        Some(colon) if !host[colon..].contains(']') => &host[..colon],
+        _ => host,
+    }
+}
+
+/// Built-in endpoints under `/_nebula/`, all disabled unless switched on.
+#[derive(Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct AdminConfig {
+    // POST /_nebula/reload re-reads the config file
+    pub reload: bool,
+    // GET /_nebula/events streams an event for every reload
+    pub events: bool,
+    // GET /_nebula/healthz and /_nebula/readyz for liveness and readiness
+    // probes
+    pub health: bool,
+    // GET /_nebula/metrics in the Prometheus text format
+    pub metrics: bool,
+    // GET /_nebula/status, a page for humans
+    pub status: bool,
+    // any request to /_nebula/echo is answered with itself as JSON
+    pub echo: bool,
+    // serve the endpoints on their own listener instead of the public ones
+    pub address: Option<String>,
+    pub port: Option<u16>,
+}
+
+impl AdminConfig {
+    /// The listener of its own the endpoints are served on, if any.
+    pub fn listener(&self) -> Option<ListenConfig> {
+        let port = self.port?;
+        let address = self.address.as_deref().unwrap_or("127.0.0.1");
+        let mut listen = ListenConfig::new(address, port);
+        listen.admin = true;
+        Some(listen)
+    }
+}
+
+#[derive(Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct PerformanceConfig {
+    // memory map files that are too large for the file cache instead of
+    // reading them in chunks, falls back to reads when mapping fails
+    pub use_mmap: bool,
+}
+
+impl Default for NebulaConfig {
+    fn default() -> Self {
+        NebulaConfig {
+            server: ServerConfig {
+                address: "127.0.0.1".to_string(),
+                port: 7878,
+                keep_alive_timeout: default_keep_alive_timeout(),
+                max_requests_per_connection: default_max_requests_per_connection(),
+                workers: None,
+                queue_size: default_queue_size(),
+                max_request_line: default_max_request_line(),
+                max_header_size: default_max_header_size(),
+                drain_timeout: default_drain_timeout(),
+                header_timeout: default_header_timeout(),
+                min_header_rate: 0,
+                max_connections: 0,
+                max_connections_per_ip: 0,
+                listen: Vec::new(),
+                server_tokens: ServerTokens::default(),
+                max_body_size: default_max_body_size(),
+                trusted_proxies: Vec::new(),
+                unix_socket: None,
+                unix_socket_mode: None,
+                pid_file: None,
+                processes: default_processes(),
+            },
+            content: ContentConfig {
+                public_dir: "public".to_string(),
+                index_files: default_index_files(),
+                spa_fallback: false,
+                charset: default_charset(),
+                deny_dotfiles: default_deny_dotfiles(),
+                follow_symlinks: FollowSymlinks::default(),
+                trailing_slash: TrailingSlash::default(),
+                writable: false,
+                webdav: false,
+                directory_overrides: false,
+                negotiate: false,
+                negotiate_language: false,
+                default_language: default_language(),
+            },
+            compression: CompressionConfig::default(),
+            cache_control: CacheControlConfig::default(),
+            file_cache: FileCacheConfig::default(),
+            open_file_cache: OpenFileCacheConfig::default(),
+            etag: EtagConfig::default(),
+            errors: HashMap::new(),
+            mime: HashMap::new(),
+            logging: LoggingConfig::default(),
+            admin: AdminConfig::default(),
+            performance: PerformanceConfig::default(),
+            rate_limit: RateLimitConfig::default(),
+            limits: LimitsConfig::default(),
+            telemetry: TelemetryConfig::default(),
+            access: AccessConfig::default(),
+            cors: CorsConfig::default(),
+            hotlink: HotlinkConfig::default(),
+            upload: UploadConfig::default(),
+            headers: HeadersConfig::default(),
+            auth: AuthConfig::default(),
+            vhosts: Vec::new(),
+            mounts: Vec::new(),
+            proxies: Vec::new(),
+            redirects: Vec::new(),
+            rewrites: Vec::new(),
+        }
+    }
+}
+
+/// Loads the config file, falling back to the defaults when it is missing
+/// or invalid.
+pub fn load_config(path: &Path) -> NebulaConfig {
+    match try_load_config(path) {
+        Ok(config) => config,
+        Err(e) => {
+            // logging is set up with the config, so this can't go there
+            eprintln!("[{}] {}. Using default config.", e.origin(), e);
+            NebulaConfig::default()
+        }
+    }
+}
+
+/// Loads the config file without any fallback, used where a broken file
+/// must not replace a working configuration.
+pub fn try_load_config(path: &Path) -> Result<NebulaConfig, NebulaError> {
+    let error = |message: String| NebulaError::Config {
+        path: path.to_path_buf(),
+        message,
+    };
+    let content = fs::read_to_string(path).map_err(|e| error(e.to_string()))?;
+    let table: toml::Table = toml::from_str(&content).map_err(|e| error(e.to_string()))?;
+    if !table.contains_key("include") {
+        // parsed from the text again so errors point at a line
+        return toml::from_str(&content).map_err(|e| error(e.to_string()));
+    }
+    include::resolve(path, table)?
+        .try_into()
+        .map_err(|e: toml::de::Error| error(e.to_string()))
+}
@@ -0,0 +1,130 @@
+//! Cross-origin resource sharing for files served by nebula itself,
+//! proxied paths are left to their upstream.
+
+use crate::request::Request;
+use serde::Deserialize;
+
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct CorsConfig {
+    pub enabled: bool,
+    // only paths below these prefixes, empty means every path
+    pub prefixes: Vec<String>,
+    // exact origins like `https://app.example.com`, or `*` for any
+    pub allowed_origins: Vec<String>,
+    pub allowed_methods: Vec<String>,
+    // request headers preflights may ask for, `*` allows any
+    pub allowed_headers: Vec<String>,
+    // response headers scripts may read besides the simple ones
+    pub expose_headers: Vec<String>,
+    // seconds browsers may cache a preflight answer
+    pub max_age: u64,
+    // let requests carry cookies and Authorization, `*` origins are echoed
+    // back as the concrete origin in that case
+    pub allow_credentials: bool,
+}
+
+impl Default for CorsConfig {
+    fn default() -> Self {
+        CorsConfig {
+            enabled: false,
+            prefixes: Vec::new(),
+            allowed_origins: Vec::new(),
+            allowed_methods: ["GET", "HEAD", "POST"]
+                .iter()
+                .map(|method| method.to_string())
+                .collect(),
+            allowed_headers: Vec::new(),
+            expose_headers: Vec::new(),
+            max_age: 600,
+            allow_credentials: false,
+        }
+    }
+}
+
+impl CorsConfig {
+    /// Access-Control-* headers for a regular response to `request`.
+    pub fn response_headers(&self, request: &Request, path: &str) -> Vec<(&str, String)> {
+        let Some(origin) = self.allowed_origin(request, path) else {
+            return Vec::new();
+        };
+
+        let mut headers = self.origin_headers(origin);
+        if !self.expose_headers.is_empty() {
+            headers.push((
+                "Access-Control-Expose-Headers",
+                self.expose_headers.join(", "),
+            ));
+        }
+        headers
+    }
+
+    /// Returns the headers to answer a preflight with, or `None` when the
+    /// request isn't a preflight covered by this config. Preflights from
+    /// origins that aren't allowed are answered without any Access-Control
+    /// headers, which makes the browser block the actual request.
+    pub fn preflight(&self, request: &Request, path: &str) -> Option<Vec<(&str, String)>> {
+        let is_preflight = request.method == "OPTIONS"
+            && request.header("origin").is_some()
+            && request.header("access-control-request-method").is_some();
+        if !self.enabled || !is_preflight || !self.covers(path) {
+            return None;
+        }
+
+        let Some(origin) = self.allowed_origin(request, path) else {
+            return Some(Vec::new());
+        };
+
+        let mut headers = self.origin_headers(origin);
+        headers.push((
+            "Access-Control-Allow-Methods",
+            self.allowed_methods.join(", "),
+        ));
+        let allowed_headers = if self.allowed_headers.iter().any(|header| header == "*") {
+            // echo what was asked for, `*` isn't honoured with credentials
+            request
+                .header("access-control-request-headers")
+                .unwrap_or("")
+                .to_string()
+        } else {
+            self.allowed_headers.join(", ")
+        };
+        if !allowed_headers.is_empty() {
+            headers.push(("Access-Control-Allow-Headers", allowed_headers));
+        }
+        headers.push(("Access-Control-Max-Age", self.max_age.to_string()));
+        Some(headers)
+    }
+
+    fn covers(&self, path: &str) -> bool {
+        self.prefixes.is_empty() || self.prefixes.iter().any(|prefix| path.starts_with(prefix))
+    }
+
+    fn allowed_origin<'a>(&self, request: &'a Request, path: &str) -> Option<&'a str> {
+        if !self.enabled || !self.covers(path) {
+            return None;
+        }
+        let origin = request.header("origin")?;
+        self.allowed_origins
+            .iter()
+            .any(|allowed| allowed == "*" || allowed.eq_ignore_ascii_case(origin))
+            .then_some(origin)
+    }
+
+    fn origin_headers(&self, origin: &str) -> Vec<(&'static str, String)> {
+        let wildcard = self.allowed_origins.iter().any(|allowed| allowed == "*");
+        if wildcard && !self.allow_credentials {
+            return vec![("Access-Control-Allow-Origin", "*".to_string())];
+        }
+
+        let mut headers = vec![
+            ("Access-Control-Allow-Origin", origin.to_string()),
+            // the answer depends on the origin, caches have to keep them apart
+            ("Vary", "Origin".to_string()),
+        ];
+        if self.allow_credentials {
+            headers.push(("Access-Control-Allow-Credentials", "true".to_string()));
+        }
+        headers
+    }
+}
@@ -0,0 +1,118 @@
+//! Running in the background: detaching from the terminal, the PID file
+//! and signalling the server it names.
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// Detaches from the terminal. Has to happen before any thread is started,
+/// only the calling thread survives a fork. Output goes to /dev/null from
+/// here on, so the access log should go to a file.
+#[cfg(unix)]
+pub fn daemonize() -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    fork()?;
+    // SAFETY: setsid() takes no arguments and only changes the session of
+    // this process
+    if unsafe { libc::setsid() } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    // the second fork leaves a process that isn't a session leader and so
+    // can never get a controlling terminal back
+    fork()?;
+
+    let null = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/null")?;
+    for fd in 0..3 {
+        // SAFETY: `null` stays open for the loop, and replacing 0, 1 and 2
+        // leaves no Rust handle dangling, std's stdin and stdout only refer
+        // to the numbers
+        if unsafe { libc::dup2(null.as_raw_fd(), fd) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+// continues in the child, the parent exits right away
+#[cfg(unix)]
+fn fork() -> io::Result<()> {
+    // SAFETY: the binary daemonizes after the server is built and before it
+    // runs, when no thread has been started yet. With a single thread no
+    // lock can be held by one the child doesn't have, so the child goes on
+    // like any other process
+    match unsafe { libc::fork() } {
+        -1 => Err(io::Error::last_os_error()),
+        0 => Ok(()),
+        _ => std::process::exit(0),
+    }
+}
+
+#[cfg(not(unix))]
+pub fn daemonize() -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "--daemon is only available on unix",
+    ))
+}
+
+/// Writes the PID of this process to `path`, refusing to when the one in
+/// there is still running.
+pub fn write_pid_file(path: &Path) -> io::Result<()> {
+    if let Ok(pid) = read_pid(path) {
+        // the process this one replaces in an upgrade
+        #[cfg(unix)]
+        let replacing = pid == std::os::unix::process::parent_id();
+        #[cfg(not(unix))]
+        let replacing = false;
+        if is_running(pid) && !replacing {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("already running as process {}", pid),
+            ));
+        }
+    }
+    fs::write(path, format!("{}\n", std::process::id()))
+}
+
+/// Removes the PID file, unless another process already replaced it.
+pub fn remove_pid_file(path: &Path) {
+    if read_pid(path).is_ok_and(|pid| pid == std::process::id()) {
+        let _ = fs::remove_file(path);
+    }
+}
+
+/// Sends `signal` to the server named in the PID file and returns its PID.
+#[cfg(unix)]
+pub fn signal(path: &Path, signal: libc::c_int) -> io::Result<u32> {
+    let pid = read_pid(path)?;
+    // SAFETY: kill() touches no memory of ours, a stale PID fails with
+    // ESRCH
+    if unsafe { libc::kill(pid as libc::pid_t, signal) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(pid)
+}
+
+fn read_pid(path: &Path) -> io::Result<u32> {
+    fs::read_to_string(path)?
+        .trim()
+        .parse()
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "not a process ID"))
+}
+
+#[cfg(unix)]
+fn is_running(pid: u32) -> bool {
+    // signal 0 only checks whether the process exists
+    // SAFETY: kill() touches no memory of ours
+    let alive = unsafe { libc::kill(pid as libc::pid_t, 0) } == 0;
+    alive || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+#[cfg(not(unix))]
+fn is_running(_pid: u32) -> bool {
+    false
+}
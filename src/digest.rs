@@ -0,0 +1,157 @@
+//! `[etag]`: validators for static files. By default they are made from
+//! the modification time and size, hashing the contents instead gives the
+//! same file the same ETag across restarts, deploys that only touch it and
+//! mirrored servers. The SHA-256 of a file can also go out as a
+//! Repr-Digest (RFC 9530) for clients to check downloads against.
+
+use crate::fs::file_etag;
+use base64::engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD};
+use base64::Engine;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs::{self, File, Metadata};
+use std::io::{self, Read};
+use std::sync::Mutex;
+use std::time::{Instant, SystemTime};
+
+#[derive(Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct EtagConfig {
+    pub algorithm: EtagAlgorithm,
+    // send `Repr-Digest: sha-256=:...:` with static files
+    pub repr_digest: bool,
+}
+
+#[derive(Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum EtagAlgorithm {
+    /// The modification time and size, nothing has to be read.
+    #[default]
+    Metadata,
+    Sha256,
+    Blake3,
+}
+
+/// The hashes files are digested with.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Hash {
+    Sha256,
+    Blake3,
+}
+
+// files remembered at most, the ones used longest ago go first
+const MAX_ENTRIES: usize = 10_000;
+
+struct Entry {
+    // the version of the file the digest is of
+    modified: SystemTime,
+    len: u64,
+    digest: Vec<u8>,
+    used: Instant,
+}
+
+/// Digests of files by path, shared by all workers. A file is read once
+/// and again after its modification time or size changed.
+#[derive(Default)]
+pub struct Digests {
+    entries: Mutex<HashMap<(String, Hash), Entry>>,
+}
+
+impl Digests {
+    /// The ETag of the file at `path` as `config` asks for it. A file that
+    /// can't be read gets one from its metadata.
+    pub fn etag(&self, config: &EtagConfig, path: &str, metadata: &Metadata) -> Option<String> {
+        let hash = match config.algorithm {
+            EtagAlgorithm::Metadata => return file_etag(metadata),
+            EtagAlgorithm::Sha256 => Hash::Sha256,
+            EtagAlgorithm::Blake3 => Hash::Blake3,
+        };
+        match self.digest(path, metadata, hash) {
+            Ok(digest) => Some(format!("\"{}\"", URL_SAFE_NO_PAD.encode(digest))),
+            Err(e) => {
+                tracing::warn!("Failed to hash {}: {}", path, e);
+                file_etag(metadata)
+            }
+        }
+    }
+
+    /// The `hash` of the file at `path`, `metadata` being what the caller
+    /// found on disk for it.
+    pub fn digest(&self, path: &str, metadata: &Metadata, hash: Hash) -> io::Result<Vec<u8>> {
+        let modified = metadata.modified()?;
+        let key = (path.to_string(), hash);
+        if let Some(entry) = self.lock().get_mut(&key) {
+            if entry.modified == modified && entry.len == metadata.len() {
+                entry.used = Instant::now();
+                return Ok(entry.digest.clone());
+            }
+        }
+
+        // without the lock, a large file would hold up every other request
+        let digest = hash_file(path, hash)?;
+        // a file that changed while it was read is hashed again next time
+        let unchanged = fs::metadata(path)
+            .is_ok_and(|now| now.len() == metadata.len() && now.modified().ok() == Some(modified));
+        if unchanged {
+            let mut entries = self.lock();
+            if entries.len() >= MAX_ENTRIES && !entries.contains_key(&key) {
+                let oldest = entries
+                    .iter()
+                    .min_by_key(|(_, entry)| entry.used)
+                    .map(|(key, _)| key.clone());
+                if let Some(oldest) = oldest {
+                    entries.remove(&oldest);
+                }
+            }
+            entries.insert(
+                key,
+                Entry {
+                    modified,
+                    len: metadata.len(),
+                    digest: digest.clone(),
+                    used: Instant::now(),
+                },
+            );
+        }
+        Ok(digest)
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<(String, Hash), Entry>> {
+        self.entries.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// A Repr-Digest field value for the SHA-256 `digest`.
+pub fn repr_digest(digest: &[u8]) -> String {
+    format!("sha-256=:{}:", STANDARD.encode(digest))
+}
+
+/// The SHA-256 of bytes that aren't a file, like a body compressed on the
+/// fly.
+pub fn sha256(bytes: &[u8]) -> Vec<u8> {
+    Sha256::digest(bytes).to_vec()
+}
+
+fn hash_file(path: &str, hash: Hash) -> io::Result<Vec<u8>> {
+    let mut file = File::open(path)?;
+    let mut buf = vec![0; 64 * 1024];
+    let mut sha256 = Sha256::new();
+    let mut blake3 = blake3::Hasher::new();
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        match hash {
+            Hash::Sha256 => sha256.update(&buf[..n]),
+            Hash::Blake3 => {
+                blake3.update(&buf[..n]);
+            }
+        }
+    }
+    Ok(match hash {
+        Hash::Sha256 => sha256.finalize().to_vec(),
+        Hash::Blake3 => blake3.finalize().as_bytes().to_vec(),
+    })
+}
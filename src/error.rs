@@ -0,0 +1,116 @@
+//! Errors that carry where they came from and which status a client should
+//! see for them.
+
+use crate::response::Response;
+use std::collections::VecDeque;
+use std::io;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::SystemTime;
+use thiserror::Error;
+
+// how many logged errors the status page shows
+const RECENT_ERRORS: usize = 20;
+
+static RECENT: Mutex<VecDeque<(SystemTime, String)>> = Mutex::new(VecDeque::new());
+
+#[derive(Debug, Error)]
+pub enum NebulaError {
+    /// The config file could not be read or parsed.
+    #[error("Failed to load {}: {message}", path.display())]
+    Config { path: PathBuf, message: String },
+
+    /// An I/O operation failed, `context` says which one.
+    #[error("{context}: {source}")]
+    Io {
+        context: String,
+        #[source]
+        source: io::Error,
+    },
+
+    /// The client sent something that can't be served.
+    #[error("{0}")]
+    BadRequest(String),
+
+    /// The request body is larger than `server.max_body_size`.
+    #[error("Request body too large")]
+    BodyTooLarge,
+
+    /// A proxied request failed before anything reached the client.
+    #[error("Proxy to {upstream} failed: {source}")]
+    Upstream {
+        upstream: String,
+        #[source]
+        source: io::Error,
+    },
+}
+
+impl NebulaError {
+    pub fn io(context: impl Into<String>, source: io::Error) -> NebulaError {
+        NebulaError::Io {
+            context: context.into(),
+            source,
+        }
+    }
+
+    /// The part of the server the error comes from, logged with it.
+    pub fn origin(&self) -> &'static str {
+        match self {
+            NebulaError::Config { .. } => "config",
+            NebulaError::Io { .. } => "io",
+            NebulaError::BadRequest(_) | NebulaError::BodyTooLarge => "request",
+            NebulaError::Upstream { .. } => "upstream",
+        }
+    }
+
+    /// The status a client gets when this error ends its request.
+    pub fn status(&self) -> u16 {
+        match self {
+            NebulaError::Config { .. } | NebulaError::Io { .. } => 500,
+            NebulaError::BadRequest(_) => 400,
+            NebulaError::BodyTooLarge => 413,
+            NebulaError::Upstream { source, .. } => match source.kind() {
+                io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => 504,
+                // no upstream was left to try
+                io::ErrorKind::NotConnected => 503,
+                _ => 502,
+            },
+        }
+    }
+
+    /// The error as a client sees it. Only bad requests explain themselves,
+    /// everything else stays in the server log.
+    pub fn to_response(&self) -> Response {
+        let message = match (self, self.status()) {
+            (NebulaError::BadRequest(message), _) => message.as_str(),
+            (_, 413) => "Request body too large",
+            (_, 503) => "No upstream available",
+            (_, 504) => "Upstream timed out",
+            (_, 502) => "Bad gateway",
+            _ => "Internal server error",
+        };
+        Response::error(self.status(), message)
+    }
+
+    pub fn log(&self) {
+        let line = format!("[{}] {}", self.origin(), self);
+        // errors loading the config come before logging is set up
+        if tracing::dispatcher::has_been_set() {
+            tracing::error!(origin = self.origin(), "{}", self);
+        } else {
+            eprintln!("{}", line);
+        }
+
+        let mut recent = RECENT.lock().unwrap_or_else(|e| e.into_inner());
+        if recent.len() == RECENT_ERRORS {
+            recent.pop_front();
+        }
+        recent.push_back((SystemTime::now(), line));
+    }
+}
+
+/// The errors logged last, oldest first.
+pub fn recent() -> Vec<(SystemTime, String)> {
+    let recent = RECENT.lock().unwrap_or_else(|e| e.into_inner());
+    recent.iter().cloned().collect()
+}
@@ -0,0 +1,154 @@
+//! Server-Sent Events: streams of events a handler keeps sending while the
+//! response is open, with heartbeat comments so idle streams aren't closed
+//! by proxies in between.
+
+use std::io::{self, Read};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::time::Duration;
+
+// a comment is sent when no event came for this long
+const HEARTBEAT: Duration = Duration::from_secs(15);
+
+/// One event of a stream. Multi-line data is sent as several `data:`
+/// lines, which browsers join back together.
+///
+/// ```
+/// use nebula::Event;
+///
+/// let event = Event::new("{\"users\":3}").event("stats").id("42");
+/// ```
+pub struct Event {
+    event: Option<String>,
+    data: String,
+    id: Option<String>,
+    retry: Option<u64>,
+}
+
+impl Event {
+    pub fn new(data: impl Into<String>) -> Event {
+        Event {
+            event: None,
+            data: data.into(),
+            id: None,
+            retry: None,
+        }
+    }
+
+    /// The event type, `message` when not set.
+    pub fn event(mut self, event: &str) -> Self {
+        self.event = Some(single_line(event));
+        self
+    }
+
+    /// Sent back by browsers as Last-Event-ID when they reconnect.
+    pub fn id(mut self, id: &str) -> Self {
+        self.id = Some(single_line(id));
+        self
+    }
+
+    /// How many milliseconds browsers wait before reconnecting.
+    pub fn retry(mut self, millis: u64) -> Self {
+        self.retry = Some(millis);
+        self
+    }
+
+    fn encode(&self) -> String {
+        let mut encoded = String::new();
+        if let Some(event) = &self.event {
+            encoded.push_str(&format!("event: {}\n", event));
+        }
+        if let Some(id) = &self.id {
+            encoded.push_str(&format!("id: {}\n", id));
+        }
+        if let Some(retry) = self.retry {
+            encoded.push_str(&format!("retry: {}\n", retry));
+        }
+        for line in self.data.split('\n') {
+            encoded.push_str(&format!("data: {}\n", line.trim_end_matches('\r')));
+        }
+        encoded.push('\n');
+        encoded
+    }
+}
+
+/// Sends events to one open stream. It can be cloned and moved to other
+/// threads.
+#[derive(Clone)]
+pub struct EventSender {
+    sender: Sender<String>,
+}
+
+impl EventSender {
+    /// Queues `event` for the client. Returns false once the client is gone,
+    /// the sender can be dropped then.
+    pub fn send(&self, event: Event) -> bool {
+        self.sender.send(event.encode()).is_ok()
+    }
+}
+
+/// The receiving end of a stream, sent as the body of a
+/// [`Response::events`](crate::Response::events) response. The stream ends
+/// when every sender was dropped.
+pub struct EventStream {
+    receiver: Receiver<String>,
+    pending: Vec<u8>,
+    sent: usize,
+}
+
+/// A new stream and the sender for it.
+pub fn channel() -> (EventSender, EventStream) {
+    let (sender, receiver) = mpsc::channel();
+    let stream = EventStream {
+        receiver,
+        pending: Vec::new(),
+        sent: 0,
+    };
+    (EventSender { sender }, stream)
+}
+
+impl Read for EventStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.sent == self.pending.len() {
+            self.pending = match self.receiver.recv_timeout(HEARTBEAT) {
+                Ok(event) => event.into_bytes(),
+                Err(RecvTimeoutError::Timeout) => b": heartbeat\n\n".to_vec(),
+                Err(RecvTimeoutError::Disconnected) => return Ok(0),
+            };
+            self.sent = 0;
+        }
+        let n = buf.len().min(self.pending.len() - self.sent);
+        buf[..n].copy_from_slice(&self.pending[self.sent..self.sent + n]);
+        self.sent += n;
+        Ok(n)
+    }
+}
+
+/// Every open stream of one kind, e.g. the clients of `/_nebula/events`.
+#[derive(Default)]
+pub struct Broadcast {
+    senders: std::sync::Mutex<Vec<EventSender>>,
+}
+
+impl Broadcast {
+    pub fn subscribe(&self) -> EventStream {
+        let (sender, stream) = channel();
+        self.lock().push(sender);
+        stream
+    }
+
+    /// Sends `event` to every open stream and forgets the closed ones.
+    pub fn send(&self, event: Event) {
+        let encoded = event.encode();
+        self.lock()
+            .retain(|subscriber| subscriber.sender.send(encoded.clone()).is_ok());
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Vec<EventSender>> {
+        self.senders.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+// a line break would end the field early and start a new one
+fn single_line(value: &str) -> String {
+    value.replace(['\r', '\n'], "")
+}
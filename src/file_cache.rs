@@ -0,0 +1,130 @@
+//! In-memory cache for small static files.
+
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct FileCacheConfig {
+    pub enabled: bool,
+    // larger files are streamed from disk instead
+    pub max_entry_kb: u64,
+    // total size of all cached files, least recently used ones go first
+    pub max_size_mb: u64,
+}
+
+impl Default for FileCacheConfig {
+    fn default() -> Self {
+        FileCacheConfig {
+            enabled: true,
+            max_entry_kb: 256,
+            max_size_mb: 64,
+        }
+    }
+}
+
+/// File contents keyed by path. An entry is dropped when the size or
+/// modification time of the file changed, so edits show up on the next
+/// request, or once the open file cache checks again.
+#[derive(Default)]
+pub struct FileCache {
+    inner: Mutex<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    entries: HashMap<String, Entry>,
+    // lookups answered from memory and ones that had to read the file
+    hits: u64,
+    misses: u64,
+    // bytes held by all entries
+    size: u64,
+    // bumped on every hit, the entry with the smallest stamp is evicted first
+    clock: u64,
+}
+
+struct Entry {
+    contents: Arc<Vec<u8>>,
+    modified: Option<SystemTime>,
+    last_used: u64,
+}
+
+impl FileCache {
+    /// Returns the contents of `path`, from memory when the cached copy is
+    /// still current according to `metadata`.
+    pub fn read(
+        &self,
+        path: &str,
+        metadata: &fs::Metadata,
+        config: &FileCacheConfig,
+    ) -> io::Result<Arc<Vec<u8>>> {
+        let len = metadata.len();
+        let modified = metadata.modified().ok();
+        let max_size = config.max_size_mb * 1024 * 1024;
+        if !config.enabled || len > config.max_entry_kb * 1024 || len > max_size {
+            return fs::read(path).map(Arc::new);
+        }
+
+        {
+            let mut inner = self.lock();
+            inner.clock += 1;
+            let clock = inner.clock;
+            if let Some(entry) = inner.entries.get_mut(path) {
+                if entry.modified == modified && entry.contents.len() as u64 == len {
+                    entry.last_used = clock;
+                    let contents = Arc::clone(&entry.contents);
+                    inner.hits += 1;
+                    return Ok(contents);
+                }
+            }
+            inner.misses += 1;
+        }
+
+        // read without holding the lock, other files can be served meanwhile
+        let contents = Arc::new(fs::read(path)?);
+
+        let mut inner = self.lock();
+        if let Some(stale) = inner.entries.remove(path) {
+            inner.size -= stale.contents.len() as u64;
+        }
+        while inner.size + contents.len() as u64 > max_size {
+            let Some(oldest) = inner
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(path, _)| path.clone())
+            else {
+                break;
+            };
+            if let Some(evicted) = inner.entries.remove(&oldest) {
+                inner.size -= evicted.contents.len() as u64;
+            }
+        }
+
+        inner.size += contents.len() as u64;
+        let last_used = inner.clock;
+        inner.entries.insert(
+            path.to_string(),
+            Entry {
+                contents: Arc::clone(&contents),
+                modified,
+                last_used,
+            },
+        );
+        Ok(contents)
+    }
+
+    /// Hits and misses of cacheable files since the server started.
+    pub fn stats(&self) -> (u64, u64) {
+        let inner = self.lock();
+        (inner.hits, inner.misses)
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Inner> {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
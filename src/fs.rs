@@ -0,0 +1,245 @@
+//! Helpers for serving files from disk: path mapping, symlink and dotfile
+//! policies, validators and byte ranges.
+
+use crate::config::{FollowSymlinks, NebulaConfig, Site};
+use crate::http_date;
+use crate::mime;
+use std::fs;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub fn error_page<'a>(
+    config: &'a NebulaConfig,
+    site: &Site,
+    status: u16,
+) -> Option<(Vec<u8>, &'a str)> {
+    if status < 400 {
+        return None;
+    }
+
+    let page = config.errors.get(&status.to_string())?;
+    let page_path = format!("{}/{}", site.public_dir, sanitize_path(page));
+    match fs::read(&page_path) {
+        Ok(contents) => Some((contents, mime::content_type(&page_path, &config.mime))),
+        Err(e) => {
+            tracing::warn!("Failed to read error page {}: {}", page_path, e);
+            None
+        }
+    }
+}
+
+// validator derived from the modification time and size of the file
+pub fn file_etag(metadata: &fs::Metadata) -> Option<String> {
+    let modified = metadata.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+    Some(format!(
+        "\"{:x}-{:x}\"",
+        modified.as_nanos(),
+        metadata.len()
+    ))
+}
+
+// `"abc-12"` becomes `"abc-12-gzip"`
+pub fn encoded_etag(etag: &str, coding: &str) -> String {
+    format!("{}-{}\"", etag.trim_end_matches('"'), coding)
+}
+
+// HTTP dates only have second precision, so compare whole seconds
+pub fn modified_after(modified: SystemTime, since: SystemTime) -> bool {
+    let secs = |time: SystemTime| {
+        time.duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0)
+    };
+    secs(modified) > secs(since)
+}
+
+// If-None-Match uses weak comparison, so `W/` prefixes are ignored
+pub fn etag_matches(header: &str, etag: &str) -> bool {
+    header
+        .split(',')
+        .map(str::trim)
+        .any(|candidate| candidate == "*" || candidate.trim_start_matches("W/") == etag)
+}
+
+// If-Match and If-Unmodified-Since of a write against the file as it is
+// now, `None` when it doesn't exist. If-Match compares strongly and `*`
+// only matches an existing file, If-Unmodified-Since is ignored when
+// If-Match was sent
+pub fn write_preconditions_hold(
+    if_match: Option<&str>,
+    if_unmodified_since: Option<&str>,
+    metadata: Option<&fs::Metadata>,
+    etag: Option<&str>,
+) -> bool {
+    if let Some(if_match) = if_match {
+        return if_match
+            .split(',')
+            .map(str::trim)
+            .any(|candidate| (candidate == "*" && metadata.is_some()) || etag == Some(candidate));
+    }
+    let last_modified = metadata.and_then(|metadata| metadata.modified().ok());
+    match (
+        last_modified,
+        if_unmodified_since.and_then(http_date::parse),
+    ) {
+        (Some(modified), Some(since)) => !modified_after(modified, since),
+        _ => true,
+    }
+}
+
+// If-Range only lets the range through for the very same file: an equal
+// strong ETag or exactly the Last-Modified date, weak ETags never match
+pub fn if_range_matches(
+    header: &str,
+    etag: Option<&str>,
+    last_modified: Option<SystemTime>,
+) -> bool {
+    let header = header.trim();
+    if header.starts_with('"') || header.starts_with("W/") {
+        return etag.is_some_and(|etag| header == etag);
+    }
+    match (last_modified, http_date::parse(header)) {
+        (Some(modified), Some(date)) => {
+            !modified_after(modified, date) && !modified_after(date, modified)
+        }
+        _ => false,
+    }
+}
+
+pub enum ByteRange {
+    Full,
+    Partial(u64, u64),
+    /// Several ranges, in order and neither overlapping nor adjacent, sent
+    /// as `multipart/byteranges`.
+    Multiple(Vec<(u64, u64)>),
+    Unsatisfiable,
+}
+
+// more ranges than this in one header are answered with the full body, a
+// few hundred tiny ones would mostly be multipart overhead
+const MAX_RANGES: usize = 32;
+
+// parses a `Range: bytes=` header against a body of `len` bytes. Anything
+// malformed falls back to the full body, ranges past the end are dropped
+// unless that leaves none
+pub fn parse_range(header: &str, len: u64) -> ByteRange {
+    let Some(specs) = header.trim().strip_prefix("bytes=") else {
+        return ByteRange::Full;
+    };
+    let specs: Vec<&str> = specs
+        .split(',')
+        .map(str::trim)
+        .filter(|spec| !spec.is_empty())
+        .collect();
+    if specs.is_empty() || specs.len() > MAX_RANGES {
+        return ByteRange::Full;
+    }
+    let mut ranges = Vec::new();
+    for spec in specs {
+        match parse_range_spec(spec, len) {
+            Some(Some(range)) => ranges.push(range),
+            Some(None) => {}
+            None => return ByteRange::Full,
+        }
+    }
+
+    // overlapping ranges are sent once, clients have to cope with the
+    // order changing anyway
+    ranges.sort_unstable();
+    let mut merged: Vec<(u64, u64)> = Vec::with_capacity(ranges.len());
+    for (start, end) in ranges {
+        match merged.last_mut() {
+            Some(last) if start <= last.1.saturating_add(1) => last.1 = last.1.max(end),
+            _ => merged.push((start, end)),
+        }
+    }
+    match merged.as_slice() {
+        [] => ByteRange::Unsatisfiable,
+        [(start, end)] => ByteRange::Partial(*start, *end),
+        _ => ByteRange::Multiple(merged),
+    }
+}
+
+// one range of a `Range` header, `Some(None)` for one that is valid but
+// outside the body and `None` for a malformed one
+fn parse_range_spec(spec: &str, len: u64) -> Option<Option<(u64, u64)>> {
+    let (start, end) = spec.split_once('-')?;
+    let (start, end) = (start.trim(), end.trim());
+
+    // `bytes=-500` asks for the last 500 bytes
+    if start.is_empty() {
+        return match end.parse::<u64>().ok()? {
+            0 => Some(None),
+            _ if len == 0 => Some(None),
+            suffix => Some(Some((len.saturating_sub(suffix), len - 1))),
+        };
+    }
+
+    let start = start.parse::<u64>().ok()?;
+    let end = match end {
+        "" => None,
+        end => Some(end.parse::<u64>().ok().filter(|end| *end >= start)?),
+    };
+    if start >= len {
+        return Some(None);
+    }
+    Some(Some((start, end.map_or(len - 1, |end| end.min(len - 1)))))
+}
+
+// whether `file` below `root` may be served under the symlink policy, files
+// that don't exist are left to the caller's 404
+pub fn symlinks_permitted(policy: FollowSymlinks, root: &str, file: &str) -> bool {
+    match policy {
+        FollowSymlinks::All => true,
+        FollowSymlinks::SameRoot => match (fs::canonicalize(root), fs::canonicalize(file)) {
+            (Ok(root), Ok(file)) => file.starts_with(root),
+            _ => true,
+        },
+        FollowSymlinks::Off => {
+            let relative = file.strip_prefix(root).unwrap_or(file);
+            let mut current = PathBuf::from(root);
+            relative
+                .split('/')
+                .filter(|component| !component.is_empty())
+                .all(|component| {
+                    current.push(component);
+                    !fs::symlink_metadata(&current).is_ok_and(|meta| meta.file_type().is_symlink())
+                })
+        }
+    }
+}
+
+// the first of `index_files` that exists in `dir`
+pub fn find_index(
+    dir: &str,
+    index_files: &[String],
+    is_file: impl Fn(&str) -> bool,
+) -> Option<String> {
+    index_files
+        .iter()
+        .map(|index| format!("{}/{}", dir.trim_end_matches('/'), sanitize_path(index)))
+        .find(|candidate| is_file(candidate))
+}
+
+// whether a path component is a dotfile, `/.well-known/` is exempt since
+// ACME challenges and similar standards live there
+pub fn is_hidden(path: &str) -> bool {
+    path.split('/').any(|component| {
+        component.starts_with('.')
+            && component != "."
+            && component != ".."
+            && component != ".well-known"
+    })
+}
+
+pub fn sanitize_path(path: &str) -> String {
+    let path = path.trim_start_matches('/');
+    let path_components: Vec<&str> = path.split('/').collect();
+
+    let safe_components: Vec<&str> = path_components
+        .into_iter()
+        .filter(|component| !component.is_empty() && *component != "." && *component != "..")
+        .collect();
+
+    safe_components.join("/")
+}
@@ -0,0 +1,70 @@
+//! Extra response headers from the config, per path prefix and from a
+//! security preset.
+
+use serde::Deserialize;
+use std::collections::BTreeMap;
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum SecurityPreset {
+    #[default]
+    None,
+    /// nosniff, no framing, no referrer to other origins and a CSP.
+    Strict,
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct HeadersConfig {
+    pub security_preset: SecurityPreset,
+    // Content-Security-Policy sent by the strict preset, "" sends none
+    pub content_security_policy: String,
+    // every matching rule applies, later ones override earlier ones
+    pub rules: Vec<HeaderRule>,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct HeaderRule {
+    pub prefix: String,
+    pub set: BTreeMap<String, String>,
+}
+
+impl Default for HeadersConfig {
+    fn default() -> Self {
+        HeadersConfig {
+            security_preset: SecurityPreset::None,
+            content_security_policy: "default-src 'self'".to_string(),
+            rules: Vec::new(),
+        }
+    }
+}
+
+impl HeadersConfig {
+    /// Header fields for a response to `path`.
+    pub fn for_path(&self, path: &str) -> Vec<(&str, &str)> {
+        let mut headers: Vec<(&str, &str)> = Vec::new();
+        if self.security_preset == SecurityPreset::Strict {
+            headers.push(("X-Content-Type-Options", "nosniff"));
+            headers.push(("X-Frame-Options", "DENY"));
+            headers.push(("Referrer-Policy", "strict-origin-when-cross-origin"));
+            if !self.content_security_policy.is_empty() {
+                headers.push(("Content-Security-Policy", &self.content_security_policy));
+            }
+        }
+
+        let rules = self
+            .rules
+            .iter()
+            .filter(|rule| path.starts_with(&rule.prefix));
+        for (name, value) in rules.flat_map(|rule| &rule.set) {
+            headers.retain(|(existing, _)| !existing.eq_ignore_ascii_case(name));
+            headers.push((name, value));
+        }
+
+        headers
+            .into_iter()
+            // an empty value removes a header, e.g. one set by the preset
+            .filter(|(_, value)| !value.is_empty())
+            .collect()
+    }
+}
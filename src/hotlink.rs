@@ -0,0 +1,141 @@
+//! `[hotlink]`: keeps other sites from embedding or linking to files like
+//! images and downloads, judged by the Referer their pages send along.
+
+use crate::config::strip_port;
+use crate::fs::sanitize_path;
+use crate::mime;
+use crate::request::Request;
+use crate::response::Response;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct HotlinkConfig {
+    pub enabled: bool,
+    // protected files, by extension without the dot
+    pub extensions: Vec<String>,
+    // sites that may link, `example.com` covers its subdomains too. the
+    // host a request is for is always allowed
+    pub allowed_domains: Vec<String>,
+    // requests without a Referer get through, browsers and privacy tools
+    // often leave it out for direct visits
+    pub allow_empty_referer: bool,
+    pub action: HotlinkAction,
+    // where "redirect" sends blocked requests
+    pub redirect: String,
+    // the file "placeholder" serves instead, relative to the public
+    // directory
+    pub placeholder: String,
+}
+
+impl Default for HotlinkConfig {
+    fn default() -> Self {
+        HotlinkConfig {
+            enabled: false,
+            extensions: ["jpg", "jpeg", "png", "gif", "webp", "mp4", "zip"]
+                .map(String::from)
+                .to_vec(),
+            allowed_domains: Vec::new(),
+            allow_empty_referer: true,
+            action: HotlinkAction::default(),
+            redirect: String::new(),
+            placeholder: String::new(),
+        }
+    }
+}
+
+/// What blocked requests get.
+#[derive(Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum HotlinkAction {
+    /// A 403.
+    #[default]
+    Forbid,
+    /// A 302 to `redirect`.
+    Redirect,
+    /// The `placeholder` file in place of the one asked for.
+    Placeholder,
+}
+
+impl HotlinkConfig {
+    /// Whether `request` for one of `paths` is linked from a site that
+    /// isn't allowed to.
+    pub fn is_hotlink(&self, request: &Request, paths: &[&str]) -> bool {
+        if !self.enabled || !matches!(request.method.as_str(), "GET" | "HEAD") {
+            return false;
+        }
+        let protected = paths.iter().any(|path| {
+            Path::new(path)
+                .extension()
+                .and_then(|extension| extension.to_str())
+                .is_some_and(|extension| {
+                    self.extensions
+                        .iter()
+                        .any(|protected| protected.eq_ignore_ascii_case(extension))
+                })
+        });
+        if !protected {
+            return false;
+        }
+        let Some(referer) = request
+            .header("referer")
+            .filter(|referer| !referer.is_empty())
+        else {
+            return !self.allow_empty_referer;
+        };
+        let Some(referer_host) = referer_host(referer) else {
+            return true;
+        };
+        let own_host = request.header("host").map(strip_port);
+        if own_host.is_some_and(|host| host.eq_ignore_ascii_case(referer_host)) {
+            return false;
+        }
+        !self.allowed_domains.iter().any(|domain| {
+            let domain = domain.trim_start_matches("*.");
+            referer_host.eq_ignore_ascii_case(domain)
+                || referer_host
+                    .len()
+                    .checked_sub(domain.len() + 1)
+                    .is_some_and(|dot| {
+                        referer_host.as_bytes()[dot] == b'.'
+                            && referer_host[dot + 1..].eq_ignore_ascii_case(domain)
+                    })
+        })
+    }
+
+    /// The answer to a blocked request. Shared caches must not keep it, the
+    /// same URL is fine for the site itself.
+    pub fn response(&self, public_dir: &str, mime_overrides: &HashMap<String, String>) -> Response {
+        let mut response = match self.action {
+            HotlinkAction::Forbid => Response::error(403, "Forbidden"),
+            HotlinkAction::Redirect => Response::redirect(302, &self.redirect),
+            HotlinkAction::Placeholder => {
+                let path = format!("{}/{}", public_dir, sanitize_path(&self.placeholder));
+                match fs::read(&path) {
+                    Ok(contents) => Response::builder()
+                        .header("Content-Type", mime::content_type(&path, mime_overrides))
+                        .body(contents)
+                        .build(),
+                    Err(e) => {
+                        tracing::warn!("Failed to read hotlink placeholder {}: {}", path, e);
+                        Response::error(403, "Forbidden")
+                    }
+                }
+            }
+        };
+        response.headers_mut().insert("Cache-Control", "no-store");
+        response
+    }
+}
+
+// `https://example.com:8443/page` -> `example.com`
+fn referer_host(referer: &str) -> Option<&str> {
+    let (_, rest) = referer.split_once("://")?;
+    let authority = rest.split(['/', '?', '#']).next()?;
+    // `user:password@host` is never sent by browsers, but may be forged
+    let host = authority.rsplit('@').next()?;
+    Some(strip_port(host)).filter(|host| !host.is_empty())
+}
@@ -0,0 +1,147 @@
+//! Formatting and parsing of HTTP dates (RFC 7231 section 7.1.1.1).
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const DAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+/// Formats a timestamp as an IMF-fixdate, e.g. `Sun, 06 Nov 1994 08:49:37 GMT`.
+pub fn format(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+
+    let days = secs / 86400;
+    let secs_of_day = secs % 86400;
+    let (year, month, day) = civil_from_days(days as i64);
+
+    format!(
+        "{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+        DAYS[(days % 7) as usize],
+        day,
+        MONTHS[(month - 1) as usize],
+        year,
+        secs_of_day / 3600,
+        secs_of_day % 3600 / 60,
+        secs_of_day % 60,
+    )
+}
+
+/// Formats a timestamp the way the Common Log Format expects it, e.g.
+/// `10/Oct/2000:13:55:36 +0000`. Times are always logged in UTC.
+pub fn format_common_log(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+
+    let secs_of_day = secs % 86400;
+    let (year, month, day) = civil_from_days((secs / 86400) as i64);
+
+    format!(
+        "{:02}/{}/{}:{:02}:{:02}:{:02} +0000",
+        day,
+        MONTHS[(month - 1) as usize],
+        year,
+        secs_of_day / 3600,
+        secs_of_day % 3600 / 60,
+        secs_of_day % 60,
+    )
+}
+
+/// Formats a timestamp as RFC 3339 in UTC with millisecond precision,
+/// e.g. `2000-10-10T13:55:36.042Z`.
+pub fn format_rfc3339(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+
+    let secs_of_day = secs % 86400;
+    let (year, month, day) = civil_from_days((secs / 86400) as i64);
+
+    format!(
+        "{}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        secs_of_day / 3600,
+        secs_of_day % 3600 / 60,
+        secs_of_day % 60,
+        since_epoch.subsec_millis(),
+    )
+}
+
+/// Parses any of the three date formats HTTP/1.1 recipients must accept:
+/// IMF-fixdate, the obsolete RFC 850 format and ANSI C's asctime format.
+pub fn parse(value: &str) -> Option<SystemTime> {
+    let value = value.trim();
+    let (_, rest) = value.split_once(' ')?;
+    let parts: Vec<&str> = rest.split_whitespace().collect();
+
+    let (day, month, year, time) = match parts.as_slice() {
+        // Sun, 06 Nov 1994 08:49:37 GMT
+        [day, month, year, time, "GMT"] => (*day, *month, year.parse::<i64>().ok()?, *time),
+        // Sunday, 06-Nov-94 08:49:37 GMT
+        [date, time, "GMT"] => {
+            let mut date = date.split('-');
+            let day = date.next()?;
+            let month = date.next()?;
+            let year = date.next()?.parse::<i64>().ok()?;
+            // two digit years are interpreted relative to the epoch
+            let year = if year < 70 { year + 2000 } else { year + 1900 };
+            (day, month, year, *time)
+        }
+        // Sun Nov  6 08:49:37 1994
+        [month, day, time, year] => (*day, *month, year.parse::<i64>().ok()?, *time),
+        _ => return None,
+    };
+
+    let day: u32 = day.parse().ok()?;
+    let month = MONTHS.iter().position(|m| *m == month)? as u32 + 1;
+    if !(1..=31).contains(&day) {
+        return None;
+    }
+
+    let mut time = time.split(':').map(|part| part.parse::<u64>().ok());
+    let hour = time.next()??;
+    let minute = time.next()??;
+    let second = time.next()??;
+    if time.next().is_some() || hour > 23 || minute > 59 || second > 60 {
+        return None;
+    }
+
+    let days = days_from_civil(year, month, day);
+    if days < 0 {
+        return None;
+    }
+    let secs = days as u64 * 86400 + hour * 3600 + minute * 60 + second;
+    Some(UNIX_EPOCH + Duration::from_secs(secs))
+}
+
+// Howard Hinnant's days_from_civil / civil_from_days algorithms
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year.rem_euclid(400);
+    let month = month as i64;
+    let day_of_year =
+        (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day as i64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days.rem_euclid(146097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
@@ -0,0 +1,241 @@
+//! `include = ["conf.d/*.toml"]`: config split over several files that are
+//! merged into one before it is parsed.
+//!
+//! Arrays of tables like `[[vhost]]` or `[[proxy]]` from every file are
+//! appended to each other, other tables are merged key by key. A value set
+//! in two files is a conflict, and so are two vhosts, mounts or proxy rules
+//! for the same name, URL or prefix.
+
+use crate::error::NebulaError;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use toml::{Table, Value};
+
+// what tells entries of an array of tables apart, duplicates are refused
+const IDENTITIES: [(&str, &str); 3] = [("vhost", "name"), ("mount", "url"), ("proxy", "prefix")];
+
+/// Replaces the `include` key of the main config with the contents of the
+/// files it names. Patterns are relative to the directory of `path` and
+/// may use `*` and `?` in the file name, matches are merged in name order.
+pub fn resolve(path: &Path, mut table: Table) -> Result<Table, NebulaError> {
+    let Some(include) = table.remove("include") else {
+        return Ok(table);
+    };
+    let error = |path: &Path, message: String| NebulaError::Config {
+        path: path.to_path_buf(),
+        message,
+    };
+    let patterns = match include {
+        Value::Array(patterns) => patterns,
+        _ => return Err(error(path, "include must be a list of files".to_string())),
+    };
+
+    let base = path.parent().unwrap_or(Path::new(""));
+    // where each value was set, to name both files in a conflict
+    let mut origins = Origins::default();
+    origins.record_all("", &table, path);
+
+    for pattern in patterns {
+        let Value::String(pattern) = pattern else {
+            return Err(error(path, "include must be a list of files".to_string()));
+        };
+        for file in expand(base, &pattern).map_err(|message| error(path, message))? {
+            let content = fs::read_to_string(&file).map_err(|e| error(&file, e.to_string()))?;
+            let included: Table =
+                toml::from_str(&content).map_err(|e| error(&file, e.to_string()))?;
+            if included.contains_key("include") {
+                return Err(error(
+                    &file,
+                    "include is only allowed in the main config".to_string(),
+                ));
+            }
+            merge(&mut table, included, "", &file, &mut origins)
+                .map_err(|message| error(&file, message))?;
+        }
+    }
+    Ok(table)
+}
+
+#[derive(Default)]
+struct Origins {
+    // dotted key, e.g. `server.port` or `vhost name=docs`, to its file
+    keys: HashMap<String, PathBuf>,
+}
+
+impl Origins {
+    fn record_all(&mut self, prefix: &str, table: &Table, file: &Path) {
+        for (key, value) in table {
+            self.record(&join(prefix, key), value, file);
+        }
+    }
+
+    fn record(&mut self, key: &str, value: &Value, file: &Path) {
+        match value {
+            Value::Table(table) => self.record_all(key, table, file),
+            Value::Array(entries) if is_table_array(entries) => {
+                for identity in entries.iter().filter_map(|entry| identity(key, entry)) {
+                    self.keys.insert(identity, file.to_path_buf());
+                }
+            }
+            _ => {
+                self.keys.insert(key.to_string(), file.to_path_buf());
+            }
+        }
+    }
+
+    fn conflict(&self, key: &str, file: &Path) -> String {
+        match self.keys.get(key) {
+            Some(first) => format!(
+                "{} is already set in {}, {} sets it again",
+                key,
+                first.display(),
+                file.display()
+            ),
+            None => format!("{} is set twice in {}", key, file.display()),
+        }
+    }
+}
+
+fn merge(
+    into: &mut Table,
+    from: Table,
+    prefix: &str,
+    file: &Path,
+    origins: &mut Origins,
+) -> Result<(), String> {
+    for (key, value) in from {
+        let dotted = join(prefix, &key);
+        match (into.get_mut(&key), value) {
+            (None, value) => {
+                if let Value::Array(entries) = &value {
+                    check_duplicates(&dotted, entries, file, origins)?;
+                }
+                origins.record(&dotted, &value, file);
+                into.insert(key, value);
+            }
+            (Some(Value::Table(existing)), Value::Table(table)) => {
+                merge(existing, table, &dotted, file, origins)?;
+            }
+            (Some(Value::Array(existing)), Value::Array(entries))
+                if is_table_array(existing) && is_table_array(&entries) =>
+            {
+                check_duplicates(&dotted, &entries, file, origins)?;
+                origins.record(&dotted, &Value::Array(entries.clone()), file);
+                existing.extend(entries);
+            }
+            _ => return Err(origins.conflict(&dotted, file)),
+        }
+    }
+    Ok(())
+}
+
+// entries of an array of tables whose identity is taken already, by an
+// earlier file or an earlier entry in the same one
+fn check_duplicates(
+    key: &str,
+    entries: &[Value],
+    file: &Path,
+    origins: &Origins,
+) -> Result<(), String> {
+    let mut seen = Vec::new();
+    for identity in entries.iter().filter_map(|entry| identity(key, entry)) {
+        if seen.contains(&identity) || origins.keys.contains_key(&identity) {
+            return Err(origins.conflict(&identity, file));
+        }
+        seen.push(identity);
+    }
+    Ok(())
+}
+
+// `vhost name=docs` for a vhost entry with a name, `None` for entries
+// without an identity
+fn identity(key: &str, entry: &Value) -> Option<String> {
+    let (_, field) = IDENTITIES.iter().find(|(array, _)| *array == key)?;
+    let value = entry.get(field)?.as_str()?;
+    Some(format!("{} {}={}", key, field, value))
+}
+
+fn is_table_array(entries: &[Value]) -> bool {
+    !entries.is_empty() && entries.iter().all(Value::is_table)
+}
+
+fn join(prefix: &str, key: &str) -> String {
+    if prefix.is_empty() {
+        key.to_string()
+    } else {
+        format!("{}.{}", prefix, key)
+    }
+}
+
+// the files a pattern names, wildcards are only supported in the last
+// component
+fn expand(base: &Path, pattern: &str) -> Result<Vec<PathBuf>, String> {
+    let path = base.join(pattern);
+    let name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .ok_or_else(|| format!("can't include {}", pattern))?;
+    let dir = path.parent().unwrap_or(Path::new(""));
+    if dir.to_string_lossy().contains(['*', '?']) {
+        return Err(format!(
+            "{}: wildcards are only allowed in file names",
+            pattern
+        ));
+    }
+    if !name.contains(['*', '?']) {
+        return Ok(vec![path]);
+    }
+
+    let dir_listing = if dir.as_os_str().is_empty() {
+        Path::new(".")
+    } else {
+        dir
+    };
+    let entries = fs::read_dir(dir_listing).map_err(|e| format!("{}: {}", dir.display(), e))?;
+    let mut files: Vec<PathBuf> = entries
+        .filter_map(Result::ok)
+        .filter(|entry| entry.file_type().is_ok_and(|kind| kind.is_file()))
+        .filter_map(|entry| entry.file_name().into_string().ok())
+        .filter(|file| wildcard_match(name.as_bytes(), file.as_bytes()))
+        .map(|file| dir.join(file))
+        .collect();
+    files.sort();
+    Ok(files)
+}
+
+// `*` matches any run of characters and `?` a single one, dotfiles are
+// left out like shells do
+fn wildcard_match(pattern: &[u8], name: &[u8]) -> bool {
+    if name.first() == Some(&b'.') && pattern.first() != Some(&b'.') {
+        return false;
+    }
+    let (mut p, mut n) = (0, 0);
+    // where the last `*` was and how much of the name it covers so far
+    let mut star: Option<(usize, usize)> = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some(b'*') => {
+                star = Some((p, n));
+                p += 1;
+            }
+            Some(b'?') => {
+                p += 1;
+                n += 1;
+            }
+            Some(c) if *c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match star {
+                Some((star_p, star_n)) => {
+                    p = star_p + 1;
+                    n = star_n + 1;
+                    star = Some((star_p, star_n + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|c| *c == b'*')
+}
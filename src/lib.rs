@@ -0,0 +1,77 @@
+//! HTTP server built in Rust for learning purposes.
+//!
+//! The `http-nebula` binary is a thin wrapper around [`Server`], which other
+//! programs can use to embed the server:
+//!
+//! ```no_run
+//! let server = nebula::Server::builder()
+//!     .bind(([127, 0, 0, 1], 8080))
+//!     .public_dir("site")
+//!     .build()?;
+//! server.run()
+//! # ; Ok::<(), nebula::NebulaError>(())
+//! ```
+
+mod access;
+mod admin;
+mod auth;
+mod body;
+mod cache_control;
+mod compression;
+pub mod config;
+mod cors;
+mod digest;
+mod error;
+pub mod events;
+mod file_cache;
+mod fs;
+mod headers;
+mod hotlink;
+mod http_date;
+mod include;
+mod listener;
+mod logging;
+mod metrics;
+mod middleware;
+mod mime;
+mod multipart;
+mod negotiate;
+mod open_files;
+mod overrides;
+mod pool;
+#[cfg(unix)]
+mod processes;
+mod proxy;
+mod proxy_protocol;
+mod rate_limit;
+mod request;
+mod response;
+mod rewrite;
+mod router;
+mod server;
+mod signed_url;
+mod state;
+mod systemd;
+mod telemetry;
+mod throttle;
+#[cfg(unix)]
+mod upgrade;
+mod upload;
+mod upstream;
+mod uri;
+mod webdav;
+pub mod websocket;
+mod wire;
+
+pub use config::NebulaConfig;
+pub use error::NebulaError;
+pub use events::{Event, EventSender};
+pub use middleware::{Context, Middleware};
+pub use request::{Headers, Request};
+pub use response::{Response, ResponseBuilder};
+pub use router::Router;
+pub use server::{Server, ServerBuilder};
+pub use signed_url::{sign_url, Unsignable};
+#[cfg(unix)]
+pub use upgrade::is_upgrade;
+pub use websocket::{Message, WebSocket};
@@ -0,0 +1,316 @@
+use crate::config::ListenConfig;
+use socket2::{Domain, Socket, Type};
+use std::fmt;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// Binds a listening socket for one `[[server.listen]]` entry.
+/// `reuse_port` lets other processes bind the same address, the kernel
+/// spreads connections over all of them.
+pub fn bind(config: &ListenConfig, reuse_port: bool) -> io::Result<Listener> {
+    let addr = resolve(&config.address, config.port)?;
+
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+    socket.set_reuse_address(true)?;
+    #[cfg(target_os = "linux")]
+    socket.set_reuse_port(reuse_port)?;
+    #[cfg(not(target_os = "linux"))]
+    let _ = reuse_port;
+    if addr.is_ipv6() {
+        // lets `0.0.0.0:80` and `[::]:80` be bound side by side
+        socket.set_only_v6(config.ipv6_only)?;
+    }
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+
+    Ok(Listener::Tcp(socket.into()))
+}
+
+// accepts bare and bracketed IPv6 addresses as well as hostnames
+fn resolve(address: &str, port: u16) -> io::Result<SocketAddr> {
+    let trimmed = address.trim_start_matches('[').trim_end_matches(']');
+    if let Ok(ip) = trimmed.parse::<IpAddr>() {
+        return Ok(SocketAddr::new(ip, port));
+    }
+
+    (address, port).to_socket_addrs()?.next().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::AddrNotAvailable,
+            format!("{} does not resolve to any address", address),
+        )
+    })
+}
+
+/// A bound socket accepting connections, TCP or a unix domain socket.
+pub enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix {
+        listener: UnixListener,
+        path: PathBuf,
+        // the socket file is removed on drop unless systemd created it
+        owned: bool,
+    },
+}
+
+/// Where a [`Listener`] accepts connections.
+#[derive(Clone, Debug)]
+pub enum LocalAddr {
+    Tcp(SocketAddr),
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+impl fmt::Display for LocalAddr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LocalAddr::Tcp(addr) => write!(f, "http://{}", addr),
+            #[cfg(unix)]
+            LocalAddr::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+impl Listener {
+    pub fn accept(&self) -> io::Result<Stream> {
+        match self {
+            Listener::Tcp(listener) => listener.accept().map(|(stream, _)| Stream::Tcp(stream)),
+            #[cfg(unix)]
+            Listener::Unix { listener, .. } => {
+                listener.accept().map(|(stream, _)| Stream::Unix(stream))
+            }
+        }
+    }
+
+    pub fn local_addr(&self) -> io::Result<LocalAddr> {
+        match self {
+            Listener::Tcp(listener) => listener.local_addr().map(LocalAddr::Tcp),
+            #[cfg(unix)]
+            Listener::Unix { path, .. } => Ok(LocalAddr::Unix(path.clone())),
+        }
+    }
+}
+
+#[cfg(unix)]
+impl std::os::fd::AsFd for Listener {
+    fn as_fd(&self) -> std::os::fd::BorrowedFd<'_> {
+        match self {
+            Listener::Tcp(listener) => listener.as_fd(),
+            Listener::Unix { listener, .. } => listener.as_fd(),
+        }
+    }
+}
+
+/// Takes over a listening socket handed in by another process, systemd or
+/// the nebula before an upgrade.
+///
+/// # Safety
+///
+/// `fd` has to be an open socket nothing else in this process owns.
+#[cfg(unix)]
+pub unsafe fn from_raw_fd(fd: std::os::fd::RawFd) -> io::Result<Listener> {
+    use std::os::fd::{FromRawFd, OwnedFd};
+
+    let socket = Socket::from_raw_fd(fd);
+    socket.set_cloexec(true)?;
+    let addr = socket.local_addr()?;
+    if addr.as_socket().is_some() {
+        Ok(Listener::Tcp(socket.into()))
+    } else if let Some(path) = addr.as_pathname().map(|path| path.to_path_buf()) {
+        Ok(Listener::Unix {
+            listener: UnixListener::from(OwnedFd::from(socket)),
+            path,
+            owned: false,
+        })
+    } else {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("passed descriptor {} is not a TCP or unix socket", fd),
+        ))
+    }
+}
+
+// set once the sockets were handed to a new process, which goes on using
+// the socket files
+#[cfg(unix)]
+static KEEP_SOCKET_FILES: std::sync::atomic::AtomicBool = std::sync::atomic::AtomicBool::new(false);
+
+/// Leaves the socket files of unix listeners in place when they are
+/// dropped.
+#[cfg(unix)]
+pub fn keep_socket_files() {
+    KEEP_SOCKET_FILES.store(true, std::sync::atomic::Ordering::SeqCst);
+}
+
+// the socket file would keep the next start from binding the path
+#[cfg(unix)]
+impl Drop for Listener {
+    fn drop(&mut self) {
+        if let Listener::Unix {
+            path, owned: true, ..
+        } = self
+        {
+            if !KEEP_SOCKET_FILES.load(std::sync::atomic::Ordering::SeqCst) {
+                let _ = fs::remove_file(path);
+            }
+        }
+    }
+}
+
+/// Binds the unix domain socket at `path`, replacing a stale socket file
+/// left by a server that didn't shut down cleanly. `mode` sets the file's
+/// permissions, e.g. `0o660` for the web server's group.
+#[cfg(unix)]
+pub fn bind_unix(path: &str, mode: Option<u32>) -> io::Result<Listener> {
+    use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+
+    if fs::symlink_metadata(path).is_ok_and(|metadata| metadata.file_type().is_socket()) {
+        // a server that is still running answers, a stale socket doesn't
+        if UnixStream::connect(path).is_ok() {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                "another server is listening on this socket",
+            ));
+        }
+        fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path)?;
+    let listener = Listener::Unix {
+        listener,
+        path: PathBuf::from(path),
+        owned: true,
+    };
+    if let Some(mode) = mode {
+        fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+    }
+    Ok(listener)
+}
+
+#[cfg(not(unix))]
+pub fn bind_unix(_path: &str, _mode: Option<u32>) -> io::Result<Listener> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "unix domain sockets are only available on unix",
+    ))
+}
+
+/// An accepted connection, read from and written to like a `TcpStream`.
+pub enum Stream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl Stream {
+    /// The client's address, unix sockets don't have one.
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        match self {
+            Stream::Tcp(stream) => stream.peer_addr().ok(),
+            #[cfg(unix)]
+            Stream::Unix(_) => None,
+        }
+    }
+
+    pub fn try_clone(&self) -> io::Result<Stream> {
+        match self {
+            Stream::Tcp(stream) => stream.try_clone().map(Stream::Tcp),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.try_clone().map(Stream::Unix),
+        }
+    }
+
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.set_read_timeout(timeout),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.set_read_timeout(timeout),
+        }
+    }
+
+    pub fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.set_write_timeout(timeout),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.set_write_timeout(timeout),
+        }
+    }
+
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.shutdown(how),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.shutdown(how),
+        }
+    }
+}
+
+#[cfg(unix)]
+impl std::os::fd::AsFd for Stream {
+    fn as_fd(&self) -> std::os::fd::BorrowedFd<'_> {
+        match self {
+            Stream::Tcp(stream) => stream.as_fd(),
+            Stream::Unix(stream) => stream.as_fd(),
+        }
+    }
+}
+
+impl Read for &Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => (&*stream).read(buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => (&*stream).read(buf),
+        }
+    }
+}
+
+impl Write for &Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => (&*stream).write(buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => (&*stream).write(buf),
+        }
+    }
+
+    fn write_vectored(&mut self, bufs: &[io::IoSlice<'_>]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => (&*stream).write_vectored(bufs),
+            #[cfg(unix)]
+            Stream::Unix(stream) => (&*stream).write_vectored(bufs),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => (&*stream).flush(),
+            #[cfg(unix)]
+            Stream::Unix(stream) => (&*stream).flush(),
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        (&*self).read(buf)
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        (&*self).write(buf)
+    }
+
+    fn write_vectored(&mut self, bufs: &[io::IoSlice<'_>]) -> io::Result<usize> {
+        (&*self).write_vectored(bufs)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        (&*self).flush()
+    }
+}
@@ -0,0 +1,275 @@
+use crate::http_date;
+use serde::Deserialize;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, IsTerminal, Write};
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing_subscriber::EnvFilter;
+
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct LoggingConfig {
+    // "stdout", "off" or a file path to append to
+    pub access_log: String,
+    pub format: LogFormat,
+    // rotation only applies when logging to a file, 0 disables size based rotation
+    pub rotate_size_mb: u64,
+    pub rotate_daily: bool,
+    // rotated files kept around as `access.log.1` (newest) .. `access.log.N`
+    pub keep_files: usize,
+    // filter for the server's own messages, like RUST_LOG
+    pub level: String,
+    pub style: LogStyle,
+    // request and response heads into the log, for debugging
+    pub dump_wire: bool,
+    // how much of each response body the dump shows
+    pub dump_body_bytes: usize,
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        LoggingConfig {
+            access_log: "stdout".to_string(),
+            format: LogFormat::Common,
+            rotate_size_mb: 0,
+            rotate_daily: false,
+            keep_files: 7,
+            level: "info".to_string(),
+            style: LogStyle::Compact,
+            dump_wire: false,
+            dump_body_bytes: 256,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    Common,
+    // Common plus referer and user agent, as popularised by Apache
+    Combined,
+    // one JSON object per line for log shippers
+    Json,
+}
+
+/// How the server's own messages are written.
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum LogStyle {
+    Compact,
+    // several lines each, with the spans a message came from
+    Pretty,
+    Json,
+}
+
+/// Sends the server's own messages to stdout as `config` says, unless an
+/// application embedding the server set up tracing already.
+pub fn init_tracing(config: &LoggingConfig) {
+    // RUST_LOG wins so a server can be debugged without editing its config
+    let filter = EnvFilter::try_from_default_env()
+        .or_else(|_| EnvFilter::try_new(&config.level))
+        .unwrap_or_else(|e| {
+            eprintln!("Invalid logging.level {:?}: {}", config.level, e);
+            EnvFilter::new("info")
+        });
+    // colors only for people, not for log files and journald
+    let subscriber = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_ansi(io::stdout().is_terminal());
+    let _ = match config.style {
+        LogStyle::Compact => subscriber.compact().with_target(false).try_init(),
+        LogStyle::Pretty => subscriber.pretty().try_init(),
+        LogStyle::Json => subscriber.json().with_span_list(true).try_init(),
+    };
+}
+
+/// Whether `level` is a filter `init_tracing` accepts.
+pub fn is_valid_level(level: &str) -> bool {
+    EnvFilter::try_new(level).is_ok()
+}
+
+/// Everything that ends up in one access log line.
+pub struct AccessEntry<'a> {
+    pub remote_addr: Option<SocketAddr>,
+    // the authenticated user, if any
+    pub user: Option<&'a str>,
+    pub method: &'a str,
+    pub target: &'a str,
+    pub version: &'a str,
+    pub status: u16,
+    pub bytes: usize,
+    pub duration: Duration,
+    pub user_agent: Option<&'a str>,
+    pub referer: Option<&'a str>,
+}
+
+/// Writes one line per request, either in Common/Combined Log Format
+/// followed by the time taken in microseconds, or as a JSON object.
+pub struct AccessLog {
+    sink: Option<Mutex<Box<dyn Write + Send>>>,
+    format: LogFormat,
+}
+
+impl AccessLog {
+    pub fn open(config: &LoggingConfig) -> io::Result<AccessLog> {
+        let sink: Option<Box<dyn Write + Send>> = match config.access_log.as_str() {
+            "off" => None,
+            "stdout" => Some(Box::new(io::stdout())),
+            path => Some(Box::new(RotatingFile::open(path, config)?)),
+        };
+
+        Ok(AccessLog {
+            sink: sink.map(Mutex::new),
+            format: config.format,
+        })
+    }
+
+    pub fn log(&self, entry: &AccessEntry) {
+        let Some(sink) = &self.sink else {
+            return;
+        };
+
+        let line = match self.format {
+            LogFormat::Common => format!("{}\n", common_line(entry)),
+            LogFormat::Combined => format!(
+                "{} \"{}\" \"{}\"\n",
+                common_line(entry),
+                entry.referer.unwrap_or("-"),
+                entry.user_agent.unwrap_or("-"),
+            ),
+            LogFormat::Json => format!("{}\n", json_line(entry)),
+        };
+
+        if let Ok(mut sink) = sink.lock() {
+            if let Err(e) = sink.write_all(line.as_bytes()) {
+                tracing::warn!("Failed to write access log: {}", e);
+            }
+        }
+    }
+}
+
+/// Append-only log file that moves itself aside once it grows past the size
+/// limit or the UTC day changes, keeping a bounded number of old files.
+struct RotatingFile {
+    path: String,
+    file: File,
+    size: u64,
+    day: u64,
+    max_size: Option<u64>,
+    daily: bool,
+    keep_files: usize,
+}
+
+impl RotatingFile {
+    fn open(path: &str, config: &LoggingConfig) -> io::Result<RotatingFile> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let size = file.metadata()?.len();
+
+        Ok(RotatingFile {
+            path: path.to_string(),
+            file,
+            size,
+            day: current_day(),
+            max_size: (config.rotate_size_mb > 0).then(|| config.rotate_size_mb * 1024 * 1024),
+            daily: config.rotate_daily,
+            keep_files: config.keep_files,
+        })
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        if self.keep_files == 0 {
+            // nothing to keep, just start over
+            self.file = File::create(&self.path)?;
+        } else {
+            let _ = fs::remove_file(format!("{}.{}", self.path, self.keep_files));
+            for n in (1..self.keep_files).rev() {
+                let _ = fs::rename(
+                    format!("{}.{}", self.path, n),
+                    format!("{}.{}", self.path, n + 1),
+                );
+            }
+            fs::rename(&self.path, format!("{}.1", self.path))?;
+            self.file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&self.path)?;
+        }
+
+        self.size = 0;
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let today = current_day();
+        let day_changed = self.daily && today != self.day;
+        let full = self
+            .max_size
+            .is_some_and(|max| self.size > 0 && self.size + buf.len() as u64 > max);
+
+        if day_changed || full {
+            self.rotate()?;
+        }
+        self.day = today;
+
+        // lines are written whole so a rotation never splits one
+        self.file.write_all(buf)?;
+        self.size += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+fn current_day() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() / 86400)
+        .unwrap_or(0)
+}
+
+fn common_line(entry: &AccessEntry) -> String {
+    let host = entry
+        .remote_addr
+        .map_or("-".to_string(), |addr| addr.ip().to_string());
+    // CLF uses a dash when no body was sent
+    let bytes = if entry.bytes == 0 {
+        "-".to_string()
+    } else {
+        entry.bytes.to_string()
+    };
+
+    format!(
+        "{} - {} [{}] \"{} {} {}\" {} {} {}",
+        host,
+        entry.user.unwrap_or("-"),
+        http_date::format_common_log(SystemTime::now()),
+        entry.method,
+        entry.target,
+        entry.version,
+        entry.status,
+        bytes,
+        entry.duration.as_micros(),
+    )
+}
+
+fn json_line(entry: &AccessEntry) -> String {
+    serde_json::json!({
+        "timestamp": http_date::format_rfc3339(SystemTime::now()),
+        "client_ip": entry.remote_addr.map(|addr| addr.ip().to_string()),
+        "user": entry.user,
+        "method": entry.method,
+        "path": entry.target,
+        "protocol": entry.version,
+        "status": entry.status,
+        "bytes": entry.bytes,
+        "latency_ms": entry.duration.as_micros() as f64 / 1000.0,
+        "user_agent": entry.user_agent,
+        "referer": entry.referer,
+    })
+    .to_string()
+}
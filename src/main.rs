@@ -1,209 +1,162 @@
-use serde::Deserialize;
-use std::fs;
-use std::io::prelude::*;
-use std::net::{TcpListener, TcpStream};
-use std::path::Path;
-use std::thread;
-
-#[derive(Deserialize, Clone)]
-struct NebulaConfig {
-    server: ServerConfig,
-    content: ContentConfig,
-}
-
-#[derive(Deserialize, Clone)]
-struct ServerConfig {
-    address: String,
-    port: u16,
-}
-
-#[derive(Deserialize, Clone)]
-struct ContentConfig {
-    public_dir: String,
-    default_file: String,
-}
+mod cli;
+mod daemon;
 
-impl Default for NebulaConfig {
-    fn default() -> Self {
-        NebulaConfig {
-            server: ServerConfig {
-                address: "127.0.0.1".to_string(),
-                port: 7878,
-            },
-            content: ContentConfig {
-                public_dir: "public".to_string(),
-                default_file: "index.html".to_string(),
-            },
-        }
+use clap::Parser;
+use cli::{Cli, Command};
+use nebula::{config, NebulaConfig, NebulaError, Response, Router, Server, Unsignable};
+use std::path::Path;
+use std::process::ExitCode;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+fn main() -> ExitCode {
+    // Load configuration, command line flags win over the config file and
+    // keep winning after reloads
+    let cli = Cli::parse();
+    if let Some(Command::Check) = cli.command {
+        return check(&cli);
     }
-}
-
-fn load_config() -> NebulaConfig {
-    match fs::read_to_string("nebula.toml") {
-        Ok(content) => match toml::from_str(&content) {
+    let config_path = cli.config_path();
+    let mut config = if cli.strict_config() {
+        match config::try_load_config(&config_path) {
             Ok(config) => config,
             Err(e) => {
-                eprintln!("Error parsing nebula.toml: {}. Using default config.", e);
-                NebulaConfig::default()
+                e.log();
+                return ExitCode::FAILURE;
             }
-        },
-        Err(e) => {
-            eprintln!("Failed to read nebula.toml: {}. Using default config.", e);
-            NebulaConfig::default()
         }
-    }
-}
-
-fn main() -> std::io::Result<()> {
-    // Load configuration
-    let config = load_config();
-
-    // bind the tcp listener to configured address and port
-    let listener_addr = format!("{}:{}", config.server.address, config.server.port);
-    let listener = TcpListener::bind(&listener_addr)?;
-    println!("Server is listening on http://{}", listener_addr);
+    } else {
+        config::load_config(&config_path)
+    };
+    cli.apply(&mut config);
+    let pid_file = config.server.pid_file.clone();
 
-    // accept incoming connections in a loop
-    for stream in listener.incoming() {
-        match stream {
-            Ok(stream) => {
-                // Clone config for the new thread
-                let thread_config = config.clone();
+    if let Some(Command::Sign { path, expires_in }) = &cli.command {
+        return sign(&config, path, *expires_in);
+    }
+    if let Some(command) = &cli.command {
+        return match send_command(command, pid_file.as_deref()) {
+            Ok(()) => ExitCode::SUCCESS,
+            Err(e) => {
+                e.log();
+                ExitCode::FAILURE
+            }
+        };
+    }
 
-                // Spawn a new thread for each connection
-                thread::spawn(move || {
-                    if let Err(e) = handle_connection(stream, &thread_config) {
-                        eprintln!("Error handling connection: {}", e);
-                    }
-                });
+    // a process started by an upgrade takes the place of one that is in the
+    // background already
+    #[cfg(unix)]
+    let daemon = cli.daemon && !nebula::is_upgrade();
+    #[cfg(not(unix))]
+    let daemon = cli.daemon;
+    let served = Server::builder()
+        .config(config)
+        .config_file(config_path)
+        .configure(move |config| cli.apply(config))
+        .handle_signals(true)
+        // a route, so it answers before static files and the SPA fallback
+        .router(Router::new().get("/hello", |_| Response::text("Hello, Rustacean!")))
+        .build()
+        .and_then(|server| {
+            // the sockets are bound by now, so errors binding them still
+            // reach the terminal
+            if daemon {
+                daemon::daemonize()
+                    .map_err(|e| NebulaError::io("Failed to run in the background", e))?;
+            }
+            if let Some(path) = &pid_file {
+                daemon::write_pid_file(Path::new(path))
+                    .map_err(|e| NebulaError::io(format!("Failed to write {}", path), e))?;
+            }
+            let served = server.run();
+            if let Some(path) = &pid_file {
+                daemon::remove_pid_file(Path::new(path));
             }
-            Err(e) => eprintln!("Connection failed: {}", e),
+            served
+        });
+    match served {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            e.log();
+            ExitCode::FAILURE
         }
     }
-    Ok(())
 }
 
-fn handle_connection(mut stream: TcpStream, config: &NebulaConfig) -> Result<(), std::io::Error> {
-    stream.set_read_timeout(Some(std::time::Duration::from_secs(30)))?;
-    stream.set_write_timeout(Some(std::time::Duration::from_secs(30)))?;
-
-    let mut buffer = [0; 1024];
-    stream.read(&mut buffer)?;
-
-    // convert the request bytes to a string for logging
-    let request = String::from_utf8_lossy(&buffer[..]);
-    println!("Request: {}", request);
-
-    // Use the parse_http_request function to extract method and path
-    let (method, path) = parse_http_request(&buffer)
-        .unwrap_or(("GET", "/"));
-    
-    println!("Method: {}, Path: {}", method, path);
-
-    // remove the leading slash and map to default file if empty
-    let file_path = if path == "/" {
-        format!(
-            "{}/{}",
-            config.content.public_dir, config.content.default_file
-        )
-    } else {
-        format!("{}/{}", config.content.public_dir, sanitize_path(&path))
-    };
-
-    // Inside handle_connection after parsing the request
-    let (status_line, content, _) = if method == "GET" {
-        if Path::new(&file_path).exists() {
-            let content_type = get_content_type(&file_path);
-            let is_binary =
-                !content_type.starts_with("text/") && content_type != "application/javascript";
-
-            if is_binary {
-                match fs::read(&file_path) {
-                    Ok(contents) => ("HTTP/1.1 200 OK", contents, true),
-                    Err(e) => (
-                        "HTTP/1.1 500 INTERNAL SERVER ERROR",
-                        Vec::from(format!("Error reading file: {}", e)),
-                        false,
-                    ),
-                }
-            } else {
-                match fs::read_to_string(&file_path) {
-                    Ok(contents) => ("HTTP/1.1 200 OK", contents.into_bytes(), false),
-                    Err(_) => (
-                        "HTTP/1.1 500 INTERNAL SERVER ERROR",
-                        Vec::from("Error reading file"),
-                        false,
-                    ),
-                }
-            }
-        } else if path == "/hello" {
-            ("HTTP/1.1 200 OK", Vec::from("Hello, Rustacean!"), false)
-        } else {
-            ("HTTP/1.1 404 NOT FOUND", Vec::from("Page not found"), false)
+// `nginx -t` for nebula.toml, reporting every problem instead of the first
+fn check(cli: &Cli) -> ExitCode {
+    let config_path = cli.config_path();
+    let mut config = match config::try_load_config(&config_path) {
+        Ok(config) => config,
+        Err(e) => {
+            e.log();
+            return ExitCode::FAILURE;
         }
-    } else {
-        // Handle non-GET methods
-        ("HTTP/1.1 405 METHOD NOT ALLOWED", Vec::from("Method not allowed"), false)
     };
+    cli.apply(&mut config);
 
-    let content_type = get_content_type(&file_path);
-    let response = format!(
-        "{}\r\nContent-Type: {}\r\nContent-Length: {}\r\nServer: Nebula/0.1\r\nCache-Control: max-age=86400\r\n\r\n",
-        status_line,
-        content_type,
-        content.len(),
-    );
-
-    stream.write_all(response.as_bytes())?;
-    stream.write_all(&content)?;
-
-    Ok(())
+    let problems = config.problems();
+    for problem in &problems {
+        eprintln!("{}: {}", config_path.display(), problem);
+    }
+    if !problems.is_empty() {
+        return ExitCode::FAILURE;
+    }
+    println!("{} is valid", config_path.display());
+    ExitCode::SUCCESS
 }
 
-fn parse_http_request(buffer: &[u8]) -> Option<(&str, &str)> {
-    let request = std::str::from_utf8(buffer).ok()?;
-    let request_line = request.lines().next()?;
-    let parts: Vec<&str> = request_line.split_whitespace().collect();
-    
-    if parts.len() >= 2 {
-        Some((parts[0], parts[1])) // (method, path)
-    } else {
-        None
+// a link to `path` that works for `expires_in` seconds
+fn sign(config: &NebulaConfig, path: &str, expires_in: u64) -> ExitCode {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    match nebula::sign_url(config, path, now.saturating_add(expires_in)) {
+        Ok(url) => {
+            println!("{}", url);
+            ExitCode::SUCCESS
+        }
+        Err(Unsignable::InvalidPath) => {
+            eprintln!("{} isn't a valid path", path);
+            ExitCode::FAILURE
+        }
+        Err(Unsignable::NoSecret) => {
+            eprintln!("{} isn't below a [[mount]] with a secret", path);
+            ExitCode::FAILURE
+        }
     }
 }
 
-fn sanitize_path(path: &str) -> String {
-    let path = path.trim_start_matches('/');
-    let path_components: Vec<&str> = path.split('/').collect();
-
-    let safe_components: Vec<&str> = path_components
-        .into_iter()
-        .filter(|component| !component.is_empty() && *component != "." && *component != "..")
-        .collect();
-
-    safe_components.join("/")
+// signals the server named in the PID file
+#[cfg(unix)]
+fn send_command(command: &Command, pid_file: Option<&str>) -> Result<(), NebulaError> {
+    let (name, signal) = match command {
+        Command::Check => unreachable!("checked before loading the config"),
+        Command::Sign { .. } => unreachable!("signed without a running server"),
+        Command::Stop => ("SIGTERM", libc::SIGTERM),
+        Command::Reload => ("SIGHUP", libc::SIGHUP),
+        Command::Upgrade => ("SIGUSR2", libc::SIGUSR2),
+    };
+    let path = pid_file.ok_or_else(|| {
+        NebulaError::io(
+            "Can't find the server",
+            std::io::Error::new(std::io::ErrorKind::NotFound, "server.pid_file is not set"),
+        )
+    })?;
+    let pid = daemon::signal(Path::new(path), signal)
+        .map_err(|e| NebulaError::io(format!("Failed to signal the server in {}", path), e))?;
+    println!("Sent {} to process {}", name, pid);
+    Ok(())
 }
 
-fn get_content_type(path: &str) -> &str {
-    let extension = Path::new(path)
-        .extension()
-        .and_then(|ext| ext.to_str())
-        .unwrap_or("");
-
-    match extension {
-        "html" => "text/html",
-        "css" => "text/css",
-        "js" => "application/javascript",
-        "json" => "application/json",
-        "png" => "image/png",
-        "jpg" | "jpeg" => "image/jpeg",
-        "gif" => "image/gif",
-        "svg" => "image/svg+xml",
-        "ico" => "image/x-icon",
-        "pdf" => "application/pdf",
-        "txt" => "text/plain",
-        "xml" => "application/xml",
-        "webp" => "image/webp",
-        _ => "text/plain",
-    }
+#[cfg(not(unix))]
+fn send_command(_command: &Command, _pid_file: Option<&str>) -> Result<(), NebulaError> {
+    Err(NebulaError::io(
+        "Can't signal the server",
+        std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "signals are only available on unix",
+        ),
+    ))
 }
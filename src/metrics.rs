@@ -0,0 +1,165 @@
+//! Request counters and latency histograms, exposed in the Prometheus
+//! text format at `/_nebula/metrics`.
+
+use crate::state::ServerState;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// upper bounds of the latency buckets in seconds, like the Prometheus
+// client libraries use by default
+const BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// Counters updated once per answered request. Plain atomics, so
+/// recording never waits for a lock.
+pub struct Metrics {
+    // indexed by status class, 1xx to 5xx
+    requests: [AtomicU64; 5],
+    bytes: AtomicU64,
+    // requests at or below each bucket bound, counted into the first one
+    // that fits and summed up when rendered
+    latency: [AtomicU64; BUCKETS.len()],
+    latency_micros: AtomicU64,
+    // requests per second of the last minute, each slot tagged with the
+    // second it counts
+    per_second: [(AtomicU64, AtomicU64); 60],
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Metrics {
+            requests: Default::default(),
+            bytes: AtomicU64::new(0),
+            latency: Default::default(),
+            latency_micros: AtomicU64::new(0),
+            per_second: std::array::from_fn(|_| (AtomicU64::new(0), AtomicU64::new(0))),
+        }
+    }
+}
+
+impl Metrics {
+    pub fn record(&self, status: u16, bytes: u64, duration: Duration) {
+        let class = (status / 100).clamp(1, 5) as usize - 1;
+        self.requests[class].fetch_add(1, Ordering::Relaxed);
+        self.bytes.fetch_add(bytes, Ordering::Relaxed);
+
+        let seconds = duration.as_secs_f64();
+        if let Some(bucket) = BUCKETS.iter().position(|bound| seconds <= *bound) {
+            self.latency[bucket].fetch_add(1, Ordering::Relaxed);
+        }
+        self.latency_micros
+            .fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+
+        // a slot from a minute ago starts over, racing requests may lose a
+        // count or two there, which a rate can live with
+        let now = epoch_secs();
+        let (second, count) = &self.per_second[(now % 60) as usize];
+        if second.swap(now, Ordering::Relaxed) != now {
+            count.store(0, Ordering::Relaxed);
+        }
+        count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// All requests answered so far.
+    pub fn total_requests(&self) -> u64 {
+        self.requests
+            .iter()
+            .map(|count| count.load(Ordering::Relaxed))
+            .sum()
+    }
+
+    /// The average rate over the last full minute.
+    pub fn requests_per_second(&self) -> f64 {
+        let now = epoch_secs();
+        let recent: u64 = self
+            .per_second
+            .iter()
+            .filter(|(second, _)| {
+                let second = second.load(Ordering::Relaxed);
+                second < now && second + 60 >= now
+            })
+            .map(|(_, count)| count.load(Ordering::Relaxed))
+            .sum();
+        recent as f64 / 60.0
+    }
+
+    /// Every metric in the Prometheus text exposition format.
+    pub fn render(&self, state: &ServerState) -> String {
+        let mut out = String::new();
+
+        out.push_str("# HELP nebula_requests_total Requests answered, by status class.\n");
+        out.push_str("# TYPE nebula_requests_total counter\n");
+        let mut total = 0;
+        for (i, count) in self.requests.iter().enumerate() {
+            let count = count.load(Ordering::Relaxed);
+            total += count;
+            let _ = writeln!(
+                out,
+                "nebula_requests_total{{class=\"{}xx\"}} {}",
+                i + 1,
+                count
+            );
+        }
+
+        out.push_str("# HELP nebula_request_duration_seconds Time to answer a request.\n");
+        out.push_str("# TYPE nebula_request_duration_seconds histogram\n");
+        let mut cumulative = 0;
+        for (bound, count) in BUCKETS.iter().zip(&self.latency) {
+            cumulative += count.load(Ordering::Relaxed);
+            let _ = writeln!(
+                out,
+                "nebula_request_duration_seconds_bucket{{le=\"{}\"}} {}",
+                bound, cumulative
+            );
+        }
+        let _ = writeln!(
+            out,
+            "nebula_request_duration_seconds_bucket{{le=\"+Inf\"}} {}",
+            total
+        );
+        let sum = self.latency_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0;
+        let _ = writeln!(out, "nebula_request_duration_seconds_sum {}", sum);
+        let _ = writeln!(out, "nebula_request_duration_seconds_count {}", total);
+
+        out.push_str("# HELP nebula_response_bytes_total Body bytes sent to clients.\n");
+        out.push_str("# TYPE nebula_response_bytes_total counter\n");
+        let _ = writeln!(
+            out,
+            "nebula_response_bytes_total {}",
+            self.bytes.load(Ordering::Relaxed)
+        );
+
+        out.push_str("# HELP nebula_open_connections Client connections currently open.\n");
+        out.push_str("# TYPE nebula_open_connections gauge\n");
+        let _ = writeln!(
+            out,
+            "nebula_open_connections {}",
+            state.active_connections()
+        );
+
+        let (hits, misses) = state.file_cache().stats();
+        out.push_str("# HELP nebula_file_cache_hits_total Files served from memory.\n");
+        out.push_str("# TYPE nebula_file_cache_hits_total counter\n");
+        let _ = writeln!(out, "nebula_file_cache_hits_total {}", hits);
+        out.push_str("# HELP nebula_file_cache_misses_total Cacheable files read from disk.\n");
+        out.push_str("# TYPE nebula_file_cache_misses_total counter\n");
+        let _ = writeln!(out, "nebula_file_cache_misses_total {}", misses);
+        out.push_str("# HELP nebula_file_cache_hit_ratio Share of cache lookups that hit.\n");
+        out.push_str("# TYPE nebula_file_cache_hit_ratio gauge\n");
+        let ratio = match hits + misses {
+            0 => 0.0,
+            lookups => hits as f64 / lookups as f64,
+        };
+        let _ = writeln!(out, "nebula_file_cache_hit_ratio {}", ratio);
+
+        out
+    }
+}
+
+fn epoch_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
@@ -0,0 +1,286 @@
+//! Layers run around every request. The built-in ones add configured
+//! headers and enforce rate limits, access rules, hotlink protection, signed
+//! links, CORS and basic auth, user layers run after them.
+
+use crate::auth::{self, AuthResult};
+use crate::config::NebulaConfig;
+use crate::overrides::{self, Overrides};
+use crate::proxy;
+use crate::request::Request;
+use crate::response::Response;
+use crate::signed_url::{self, Refusal};
+use crate::state::ServerState;
+use std::cell::OnceCell;
+use std::net::SocketAddr;
+
+/// A layer around request handling.
+///
+/// `on_request` runs in order before a request is answered and can answer
+/// it right away by returning a response. `on_response` runs in reverse
+/// order on every response except proxied ones, which are relayed as they
+/// come from the upstream. A layer that answered a request itself doesn't
+/// see its own response, the layers before it do.
+///
+/// ```
+/// use nebula::{Context, Middleware, Request, Response};
+///
+/// struct Maintenance;
+///
+/// impl Middleware for Maintenance {
+///     fn on_request(&self, _ctx: &Context, request: &Request) -> Option<Response> {
+///         request
+///             .path
+///             .starts_with("/api/")
+///             .then(|| Response::error(503, "Back soon"))
+///     }
+/// }
+/// ```
+pub trait Middleware: Send + Sync {
+    fn on_request(&self, _ctx: &Context, _request: &Request) -> Option<Response> {
+        None
+    }
+
+    fn on_response(&self, _ctx: &Context, _request: &Request, _response: &mut Response) {}
+}
+
+/// What a layer can see of a request besides the request itself.
+pub struct Context<'a> {
+    pub(crate) config: &'a NebulaConfig,
+    pub(crate) state: &'a ServerState,
+    pub(crate) remote_addr: Option<SocketAddr>,
+    pub(crate) requested_path: &'a str,
+    pub(crate) path: &'a str,
+    pub(crate) user: &'a OnceCell<String>,
+    // of the vhost, for the .nebula.toml files
+    pub(crate) public_dir: &'a str,
+    pub(crate) overrides: OnceCell<Overrides>,
+}
+
+impl Context<'_> {
+    pub fn config(&self) -> &NebulaConfig {
+        self.config
+    }
+
+    pub fn remote_addr(&self) -> Option<SocketAddr> {
+        self.remote_addr
+    }
+
+    /// The decoded path the client asked for.
+    pub fn requested_path(&self) -> &str {
+        self.requested_path
+    }
+
+    /// The decoded path after rewrites, the one that is served.
+    pub fn path(&self) -> &str {
+        self.path
+    }
+
+    /// The authenticated user, if any.
+    pub fn user(&self) -> Option<&str> {
+        self.user.get().map(String::as_str)
+    }
+
+    /// Records who made the request, shown in the access log. Only the first
+    /// call has an effect.
+    pub fn set_user(&self, name: String) {
+        let _ = self.user.set(name);
+    }
+
+    fn paths(&self) -> [&str; 2] {
+        [self.requested_path, self.path]
+    }
+
+    // from the .nebula.toml files on the way to the served file, read once
+    // per request
+    fn overrides(&self) -> &Overrides {
+        self.overrides.get_or_init(|| {
+            if !self.config.content.directory_overrides
+                || proxy::find_rule(&self.config.proxies, self.path).is_some()
+            {
+                return Overrides::default();
+            }
+            let (root, relative) = self
+                .config
+                .mount_for(self.path)
+                .unwrap_or((self.public_dir, self.path));
+            self.state.directory_overrides().resolve(root, relative)
+        })
+    }
+}
+
+/// The built-in layers followed by the ones added to the server.
+pub fn chain(state: &ServerState) -> Vec<&dyn Middleware> {
+    let builtin: [&dyn Middleware; 8] = [
+        &ExtraHeaders,
+        &RateLimit,
+        &AccessControl,
+        &Hotlink,
+        &SignedUrls,
+        &Cors,
+        &BasicAuth,
+        &DirectoryOverrides,
+    ];
+    builtin
+        .into_iter()
+        .chain(state.middleware().iter().map(|layer| &**layer))
+        .collect()
+}
+
+/// Headers from `[headers]` and then the ones from `.nebula.toml` files,
+/// first in the chain so every response gets them.
+struct ExtraHeaders;
+
+impl Middleware for ExtraHeaders {
+    fn on_response(&self, ctx: &Context, _request: &Request, response: &mut Response) {
+        for (name, value) in ctx.config.headers.for_path(ctx.path) {
+            response.headers_mut().set(name, value);
+        }
+        for (name, value) in &ctx.overrides().headers {
+            response.headers_mut().set(name, value);
+        }
+    }
+}
+
+/// Token buckets per client IP from `[rate_limit]`.
+struct RateLimit;
+
+impl Middleware for RateLimit {
+    fn on_request(&self, ctx: &Context, _request: &Request) -> Option<Response> {
+        let ip = ctx.remote_addr?.ip();
+        let limiter = ctx.state.rate_limiter();
+        let retry_after = limiter
+            .check(&ctx.config.rate_limit, ip, ctx.requested_path)
+            .err()?;
+        let mut response = Response::error(429, "Too many requests");
+        response
+            .headers_mut()
+            .insert("Retry-After", &retry_after.to_string());
+        Some(response)
+    }
+}
+
+/// Client IP allow and deny lists from `[access]`. Both the requested and
+/// the rewritten path are checked, so a rewrite can't get around them.
+struct AccessControl;
+
+impl Middleware for AccessControl {
+    fn on_request(&self, ctx: &Context, _request: &Request) -> Option<Response> {
+        let ip = ctx.remote_addr?.ip();
+        (!ctx.config.access.permits(ip, &ctx.paths())).then(|| Response::error(403, "Forbidden"))
+    }
+}
+
+/// Files of `[hotlink]` linked from other sites.
+struct Hotlink;
+
+impl Middleware for Hotlink {
+    fn on_request(&self, ctx: &Context, request: &Request) -> Option<Response> {
+        let hotlink = &ctx.config.hotlink;
+        hotlink
+            .is_hotlink(request, &ctx.paths())
+            .then(|| hotlink.response(ctx.public_dir, &ctx.config.mime))
+    }
+}
+
+/// Links below a `[[mount]]` with a secret. The link is signed for the
+/// requested path, the mount of either path asks for it, so a rewrite
+/// can't get around it.
+struct SignedUrls;
+
+impl Middleware for SignedUrls {
+    fn on_request(&self, ctx: &Context, request: &Request) -> Option<Response> {
+        let secret = ctx
+            .paths()
+            .into_iter()
+            .rev()
+            .find_map(|path| ctx.config.mount_secret(path))?;
+        match signed_url::verify(secret, ctx.requested_path, request.query.as_deref()) {
+            Ok(()) => None,
+            Err(Refusal::Expired) => Some(Response::error(403, "Link expired")),
+            Err(Refusal::Invalid) => Some(Response::error(403, "Forbidden")),
+        }
+    }
+}
+
+/// Answers CORS preflights and tags responses for allowed origins, on
+/// paths that aren't proxied. Preflights carry no credentials, so this runs
+/// before auth.
+struct Cors;
+
+impl Middleware for Cors {
+    fn on_request(&self, ctx: &Context, request: &Request) -> Option<Response> {
+        if proxy::find_rule(&ctx.config.proxies, ctx.path).is_some() {
+            return None;
+        }
+        let mut response = Response::new(204);
+        for (name, value) in ctx.config.cors.preflight(request, ctx.path)? {
+            response.headers_mut().insert(name, &value);
+        }
+        Some(response)
+    }
+
+    fn on_response(&self, ctx: &Context, request: &Request, response: &mut Response) {
+        for (name, value) in ctx.config.cors.response_headers(request, ctx.path) {
+            response.headers_mut().insert(name, &value);
+        }
+    }
+}
+
+/// HTTP Basic authentication below the prefixes of `[[auth.basic]]`.
+struct BasicAuth;
+
+impl Middleware for BasicAuth {
+    fn on_request(&self, ctx: &Context, request: &Request) -> Option<Response> {
+        let authorization = request.header("authorization");
+        match auth::check(&ctx.config.auth, &ctx.paths(), authorization) {
+            AuthResult::Public => None,
+            AuthResult::Authorized(name) => {
+                ctx.set_user(name);
+                None
+            }
+            AuthResult::Unauthorized { realm } => Some(challenge(realm)),
+        }
+    }
+}
+
+/// The password and the files themselves from `.nebula.toml` files, with
+/// `content.directory_overrides`. Runs after `[[auth.basic]]`, so a
+/// directory can only ask for more.
+struct DirectoryOverrides;
+
+impl Middleware for DirectoryOverrides {
+    fn on_request(&self, ctx: &Context, request: &Request) -> Option<Response> {
+        if !ctx.config.content.directory_overrides {
+            return None;
+        }
+        let named_file = |path: &str| path.rsplit('/').next() == Some(overrides::FILE_NAME);
+        if ctx.paths().into_iter().any(named_file) {
+            return Some(Response::error(404, "Page not found"));
+        }
+        let overrides = ctx.overrides();
+        if overrides.broken {
+            return Some(Response::error(500, "Internal server error"));
+        }
+        let rule = overrides.auth.as_ref()?;
+        match auth::check_rule(rule, request.header("authorization")) {
+            AuthResult::Authorized(name) => {
+                ctx.set_user(name);
+                None
+            }
+            AuthResult::Unauthorized { realm } => Some(challenge(realm)),
+            AuthResult::Public => None,
+        }
+    }
+}
+
+fn challenge(realm: &str) -> Response {
+    let challenge = format!(
+        "Basic realm=\"{}\", charset=\"UTF-8\"",
+        realm.replace('"', "")
+    );
+    let mut response = Response::error(401, "Authentication required");
+    response
+        .headers_mut()
+        .insert("WWW-Authenticate", &challenge);
+    response
+}
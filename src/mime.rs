@@ -0,0 +1,136 @@
+//! Content-Type lookup by file extension.
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::path::Path;
+
+/// Used for extensions nobody knows about, so browsers download unknown
+/// binaries instead of rendering them as text.
+pub const DEFAULT_TYPE: &str = "application/octet-stream";
+
+/// Looks up the Content-Type for `path`. Entries of the `[mime]` table win
+/// over the built-in ones, extensions are compared case-insensitively.
+pub fn content_type<'a>(path: &str, overrides: &'a HashMap<String, String>) -> &'a str {
+    let extension = Path::new(path)
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .unwrap_or("")
+        .to_ascii_lowercase();
+
+    overrides
+        .iter()
+        .find(|(ext, _)| ext.trim_start_matches('.').eq_ignore_ascii_case(&extension))
+        .map(|(_, mime)| mime.as_str())
+        .or_else(|| builtin(&extension))
+        .unwrap_or(DEFAULT_TYPE)
+}
+
+/// Adds `; charset=...` to textual types that don't name a charset yet.
+/// An empty `charset` leaves every type untouched.
+pub fn with_charset<'a>(content_type: &'a str, charset: &str) -> Cow<'a, str> {
+    let mime = content_type.split(';').next().unwrap_or("").trim();
+    let textual = mime.starts_with("text/")
+        || matches!(
+            mime,
+            "application/javascript"
+                | "application/json"
+                | "application/ld+json"
+                | "application/manifest+json"
+                | "application/xml"
+                | "application/xhtml+xml"
+                | "application/rss+xml"
+                | "application/atom+xml"
+                | "image/svg+xml"
+        );
+
+    if charset.is_empty() || !textual || content_type.contains("charset=") {
+        Cow::Borrowed(content_type)
+    } else {
+        Cow::Owned(format!("{}; charset={}", content_type, charset))
+    }
+}
+
+fn builtin(extension: &str) -> Option<&'static str> {
+    let mime = match extension {
+        // text
+        "html" | "htm" => "text/html",
+        "css" => "text/css",
+        "csv" => "text/csv",
+        "txt" | "text" | "log" => "text/plain",
+        "md" | "markdown" => "text/markdown",
+        "ics" => "text/calendar",
+        "vtt" => "text/vtt",
+        "xml" => "application/xml",
+        "xhtml" => "application/xhtml+xml",
+        "rss" => "application/rss+xml",
+        "atom" => "application/atom+xml",
+
+        // scripts and data
+        "js" | "mjs" | "cjs" => "application/javascript",
+        "json" | "map" => "application/json",
+        "jsonld" => "application/ld+json",
+        "webmanifest" => "application/manifest+json",
+        "wasm" => "application/wasm",
+        "yaml" | "yml" => "application/yaml",
+        "toml" => "application/toml",
+
+        // images
+        "png" => "image/png",
+        "apng" => "image/apng",
+        "jpg" | "jpeg" | "jfif" => "image/jpeg",
+        "gif" => "image/gif",
+        "svg" | "svgz" => "image/svg+xml",
+        "ico" => "image/x-icon",
+        "webp" => "image/webp",
+        "avif" => "image/avif",
+        "bmp" => "image/bmp",
+        "tif" | "tiff" => "image/tiff",
+
+        // fonts
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        "ttf" => "font/ttf",
+        "otf" => "font/otf",
+        "eot" => "application/vnd.ms-fontobject",
+
+        // audio and video
+        "mp3" => "audio/mpeg",
+        "ogg" | "oga" => "audio/ogg",
+        "opus" => "audio/opus",
+        "wav" => "audio/wav",
+        "flac" => "audio/flac",
+        "aac" => "audio/aac",
+        "m4a" => "audio/mp4",
+        "weba" => "audio/webm",
+        "mp4" | "m4v" => "video/mp4",
+        "webm" => "video/webm",
+        "ogv" => "video/ogg",
+        "mov" => "video/quicktime",
+        "avi" => "video/x-msvideo",
+        "mkv" => "video/x-matroska",
+        "m3u8" => "application/vnd.apple.mpegurl",
+        "ts" => "video/mp2t",
+
+        // documents and archives
+        "pdf" => "application/pdf",
+        "rtf" => "application/rtf",
+        "doc" => "application/msword",
+        "docx" => "application/vnd.openxmlformats-officedocument.wordprocessingml.document",
+        "xls" => "application/vnd.ms-excel",
+        "xlsx" => "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet",
+        "ppt" => "application/vnd.ms-powerpoint",
+        "pptx" => "application/vnd.openxmlformats-officedocument.presentationml.presentation",
+        "odt" => "application/vnd.oasis.opendocument.text",
+        "epub" => "application/epub+zip",
+        "zip" => "application/zip",
+        "gz" => "application/gzip",
+        "tar" => "application/x-tar",
+        "bz2" => "application/x-bzip2",
+        "xz" => "application/x-xz",
+        "7z" => "application/x-7z-compressed",
+        "rar" => "application/vnd.rar",
+        "br" => "application/x-brotli",
+        _ => return None,
+    };
+    Some(mime)
+}
@@ -0,0 +1,130 @@
+//! Parses `multipart/form-data` bodies (RFC 7578) held in memory.
+
+use crate::error::NebulaError;
+
+/// One field of a form, `filename` is set for file inputs.
+pub struct Part<'a> {
+    pub name: Option<String>,
+    pub filename: Option<String>,
+    pub content_type: Option<String>,
+    pub data: &'a [u8],
+}
+
+/// The boundary from a `multipart/form-data; boundary=...` Content-Type.
+pub fn boundary(content_type: &str) -> Option<String> {
+    let mut params = content_type.split(';');
+    let media_type = params.next()?.trim();
+    if !media_type.eq_ignore_ascii_case("multipart/form-data") {
+        return None;
+    }
+    params
+        .filter_map(|param| param.split_once('='))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("boundary"))
+        .map(|(_, value)| unquote(value.trim()))
+        .filter(|boundary| !boundary.is_empty() && boundary.len() <= 70)
+}
+
+/// Splits `body` into its parts.
+pub fn parse<'a>(body: &'a [u8], boundary: &str) -> Result<Vec<Part<'a>>, NebulaError> {
+    let malformed =
+        |reason: &str| NebulaError::BadRequest(format!("Malformed form data: {}", reason));
+    let delimiter = format!("--{}", boundary);
+    let delimiter = delimiter.as_bytes();
+
+    // anything before the first delimiter is a preamble and ignored
+    let start = find(body, delimiter, 0).ok_or_else(|| malformed("no boundary found"))?;
+    let mut pos = start + delimiter.len();
+    let mut parts = Vec::new();
+
+    loop {
+        if body[pos..].starts_with(b"--") {
+            return Ok(parts);
+        }
+        if !body[pos..].starts_with(b"\r\n") {
+            return Err(malformed("boundary not followed by a line break"));
+        }
+        pos += 2;
+
+        let head_end =
+            find(body, b"\r\n\r\n", pos).ok_or_else(|| malformed("part without headers"))?;
+        let head = String::from_utf8_lossy(&body[pos..head_end]);
+        let data_start = head_end + 4;
+
+        // the data ends right before the line break of the next delimiter
+        let mut next = Vec::with_capacity(delimiter.len() + 2);
+        next.extend_from_slice(b"\r\n");
+        next.extend_from_slice(delimiter);
+        let data_end =
+            find(body, &next, data_start).ok_or_else(|| malformed("unterminated part"))?;
+
+        let mut part = Part {
+            name: None,
+            filename: None,
+            content_type: None,
+            data: &body[data_start..data_end],
+        };
+        for line in head.split("\r\n") {
+            let Some((field, value)) = line.split_once(':') else {
+                continue;
+            };
+            if field.trim().eq_ignore_ascii_case("content-disposition") {
+                part.name = disposition_param(value, "name");
+                part.filename = disposition_param(value, "filename");
+            } else if field.trim().eq_ignore_ascii_case("content-type") {
+                part.content_type = Some(value.trim().to_string());
+            }
+        }
+        parts.push(part);
+        pos = data_end + next.len();
+    }
+}
+
+// `form-data; name="file"; filename="a.txt"` -> the value of `param`
+fn disposition_param(value: &str, param: &str) -> Option<String> {
+    let mut rest = value;
+    while let Some(eq) = rest.find('=') {
+        let name = rest[..eq].rsplit(';').next().unwrap_or("").trim();
+        let after = rest[eq + 1..].trim_start();
+        let (value, remainder) = if let Some(quoted) = after.strip_prefix('"') {
+            // quoted strings may escape quotes with a backslash
+            let mut value = String::new();
+            let mut chars = quoted.char_indices();
+            let mut end = quoted.len();
+            while let Some((i, c)) = chars.next() {
+                match c {
+                    '\\' => value.extend(chars.next().map(|(_, c)| c)),
+                    '"' => {
+                        end = i + 1;
+                        break;
+                    }
+                    c => value.push(c),
+                }
+            }
+            (value, &quoted[end..])
+        } else {
+            let end = after.find(';').unwrap_or(after.len());
+            (after[..end].trim().to_string(), &after[end..])
+        };
+        if name.eq_ignore_ascii_case(param) {
+            return Some(value);
+        }
+        rest = remainder;
+    }
+    None
+}
+
+fn unquote(value: &str) -> String {
+    value
+        .strip_prefix('"')
+        .and_then(|value| value.strip_suffix('"'))
+        .unwrap_or(value)
+        .to_string()
+}
+
+fn find(haystack: &[u8], needle: &[u8], from: usize) -> Option<usize> {
+    haystack
+        .get(from..)?
+        .windows(needle.len())
+        .position(|window| window == needle)
+        .map(|pos| from + pos)
+}
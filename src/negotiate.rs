@@ -0,0 +1,200 @@
+//! Choosing between representations of a resource on disk, like Apache's
+//! MultiViews: with `page.html` and `page.json` next to each other,
+//! `/page` serves the one the Accept header prefers, with `page.en.html`
+//! and `page.de.html` `/page.html` serves the language Accept-Language
+//! prefers.
+
+use crate::mime;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+/// The best of the files named `<path>.<extension>` for `accept`, or the
+/// first of them by name when the client accepts none. `None` when there
+/// are no such files.
+pub fn find_variant(
+    path: &str,
+    accept: Option<&str>,
+    mime_overrides: &HashMap<String, String>,
+) -> Option<String> {
+    let path = Path::new(path);
+    let stem = path.file_name()?.to_str()?;
+    let dir = path.parent()?;
+    let prefix = format!("{}.", stem);
+
+    let mut variants: Vec<String> = fs::read_dir(dir)
+        .ok()?
+        .filter_map(|entry| {
+            let entry = entry.ok()?;
+            let name = entry.file_name().into_string().ok()?;
+            // one more extension, `page.html.gz` is a sidecar of a variant
+            let extension = name.strip_prefix(&prefix)?;
+            if extension.is_empty() || extension.contains('.') {
+                return None;
+            }
+            entry.file_type().ok()?.is_file().then_some(name)
+        })
+        .collect();
+    variants.sort();
+
+    let quality = |name: &String| {
+        let content_type = mime::content_type(name, mime_overrides);
+        accept.map_or(1.0, |accept| media_quality(accept, content_type))
+    };
+    // the first of equally good ones wins
+    let best = variants
+        .iter()
+        .map(|name| (name, quality(name)))
+        .filter(|(_, quality)| *quality > 0.0)
+        .fold(None, |best: Option<(&String, f32)>, candidate| match best {
+            Some(best) if best.1 >= candidate.1 => Some(best),
+            _ => Some(candidate),
+        })
+        .map(|(name, _)| name)
+        .or(variants.first())?;
+    Some(dir.join(best).to_string_lossy().into_owned())
+}
+
+/// The q-value an Accept header gives `content_type`, taken from the most
+/// specific media range that matches it, 0 when none does.
+pub fn media_quality(accept: &str, content_type: &str) -> f32 {
+    let essence = content_type.split(';').next().unwrap_or("").trim();
+    let (kind, subtype) = essence.split_once('/').unwrap_or((essence, ""));
+
+    let mut best: Option<(u8, f32)> = None;
+    for range in accept.split(',') {
+        let mut params = range.split(';');
+        let name = params.next().unwrap_or("").trim();
+        let quality = params
+            .filter_map(|param| param.trim().strip_prefix("q="))
+            .find_map(|q| q.trim().parse::<f32>().ok())
+            .unwrap_or(1.0);
+        let (range_kind, range_subtype) = name.split_once('/').unwrap_or((name, ""));
+
+        let specificity = if range_kind == "*" && range_subtype == "*" {
+            1
+        } else if range_kind.eq_ignore_ascii_case(kind) && range_subtype == "*" {
+            2
+        } else if range_kind.eq_ignore_ascii_case(kind)
+            && range_subtype.eq_ignore_ascii_case(subtype)
+        {
+            3
+        } else {
+            continue;
+        };
+        if best.is_none_or(|(most_specific, _)| specificity > most_specific) {
+            best = Some((specificity, quality));
+        }
+    }
+    best.map_or(0.0, |(_, quality)| quality)
+}
+
+/// The best of the files named `<stem>.<language>.<extension>` for
+/// `path` and `accept_language`, with its language. The default language
+/// is served when the client accepts none of them or doesn't say, the
+/// first by name when there is no variant in the default language either.
+pub fn find_language_variant(
+    path: &str,
+    accept_language: Option<&str>,
+    default_language: &str,
+) -> Option<(String, String)> {
+    let path = Path::new(path);
+    let name = path.file_name()?.to_str()?;
+    let dir = path.parent()?;
+    // `page.html` has its variants as `page.en.html`, `page` as `page.en`
+    let (stem, extension) = match name.rsplit_once('.') {
+        Some((stem, extension)) if !stem.is_empty() => (stem, Some(extension)),
+        _ => (name, None),
+    };
+    let prefix = format!("{}.", stem);
+
+    let mut variants: Vec<(String, String)> = fs::read_dir(dir)
+        .ok()?
+        .filter_map(|entry| {
+            let entry = entry.ok()?;
+            let name = entry.file_name().into_string().ok()?;
+            let rest = name.strip_prefix(&prefix)?;
+            let language = match extension {
+                Some(extension) => rest.strip_suffix(extension)?.strip_suffix('.')?,
+                None => rest,
+            };
+            if !is_language_tag(language) || !entry.file_type().ok()?.is_file() {
+                return None;
+            }
+            let language = language.to_string();
+            Some((name, language))
+        })
+        .collect();
+    variants.sort();
+
+    let is_default = |language: &str| language.eq_ignore_ascii_case(default_language);
+    let best = accept_language
+        .and_then(|accept| {
+            // the default language wins among equally good ones, then the
+            // first by name
+            variants
+                .iter()
+                .map(|variant| (variant, language_quality(accept, &variant.1)))
+                .filter(|(_, quality)| *quality > 0.0)
+                .fold(
+                    None,
+                    |best: Option<(&(String, String), f32)>, candidate| match best {
+                        Some(best)
+                            if best.1 > candidate.1
+                                || (best.1 == candidate.1 && !is_default(&candidate.0 .1)) =>
+                        {
+                            Some(best)
+                        }
+                        _ => Some(candidate),
+                    },
+                )
+                .map(|(variant, _)| variant)
+        })
+        .or_else(|| variants.iter().find(|(_, language)| is_default(language)))
+        .or(variants.first())?;
+    Some((
+        dir.join(&best.0).to_string_lossy().into_owned(),
+        best.1.clone(),
+    ))
+}
+
+/// The q-value an Accept-Language header gives `language`, taken from the
+/// longest range it matches. `en` matches `en` and `en-GB`, `*` anything.
+pub fn language_quality(accept_language: &str, language: &str) -> f32 {
+    let mut best: Option<(usize, f32)> = None;
+    for range in accept_language.split(',') {
+        let mut params = range.split(';');
+        let name = params.next().unwrap_or("").trim();
+        let quality = params
+            .filter_map(|param| param.trim().strip_prefix("q="))
+            .find_map(|q| q.trim().parse::<f32>().ok())
+            .unwrap_or(1.0);
+
+        let matches = name == "*"
+            || language.eq_ignore_ascii_case(name)
+            || language
+                .get(..name.len())
+                .is_some_and(|start| start.eq_ignore_ascii_case(name))
+                && language[name.len()..].starts_with('-');
+        if !matches {
+            continue;
+        }
+        // `*` is the least specific of all
+        let specificity = if name == "*" { 0 } else { name.len() };
+        if best.is_none_or(|(most_specific, _)| specificity > most_specific) {
+            best = Some((specificity, quality));
+        }
+    }
+    best.map_or(0.0, |(_, quality)| quality)
+}
+
+// `en`, `de-AT` or `zh-Hant`: letters first, then letters and digits
+fn is_language_tag(tag: &str) -> bool {
+    let mut subtags = tag.split('-');
+    let primary = subtags.next().unwrap_or("");
+    (1..=8).contains(&primary.len())
+        && primary.bytes().all(|b| b.is_ascii_alphabetic())
+        && subtags.all(|subtag| {
+            (1..=8).contains(&subtag.len()) && subtag.bytes().all(|b| b.is_ascii_alphanumeric())
+        })
+}
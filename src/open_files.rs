@@ -0,0 +1,134 @@
+//! Short-lived cache of file metadata and open descriptors, like nginx's
+//! open_file_cache, sparing hot files a stat and an open per request.
+
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs::{self, File, Metadata};
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct OpenFileCacheConfig {
+    pub enabled: bool,
+    // how long a lookup is trusted, changes on disk can take this long to
+    // show up
+    pub valid_ms: u64,
+    // paths remembered at most, the ones checked longest ago go first
+    pub max_entries: usize,
+}
+
+impl Default for OpenFileCacheConfig {
+    fn default() -> Self {
+        OpenFileCacheConfig {
+            enabled: false,
+            valid_ms: 1000,
+            max_entries: 10_000,
+        }
+    }
+}
+
+struct Entry {
+    checked: Instant,
+    // `None` for paths that don't exist, those are asked for just as often
+    metadata: Option<Metadata>,
+    // opened on the first request for the file's contents
+    file: Option<Arc<File>>,
+}
+
+/// Lookups by path, shared by all workers. With the cache disabled every
+/// call goes to the file system.
+#[derive(Default)]
+pub struct OpenFiles {
+    entries: Mutex<HashMap<String, Entry>>,
+}
+
+impl OpenFiles {
+    /// The metadata of `path`, `None` when it doesn't exist or can't be
+    /// read.
+    pub fn metadata(&self, config: &OpenFileCacheConfig, path: &str) -> Option<Metadata> {
+        if !config.enabled {
+            return fs::metadata(path).ok();
+        }
+        if let Some(entry) = self.fresh(config, path) {
+            return entry.metadata.clone();
+        }
+        let metadata = fs::metadata(path).ok();
+        self.insert(config, path, metadata.clone(), None);
+        metadata
+    }
+
+    pub fn is_file(&self, config: &OpenFileCacheConfig, path: &str) -> bool {
+        self.metadata(config, path).is_some_and(|m| m.is_file())
+    }
+
+    pub fn is_dir(&self, config: &OpenFileCacheConfig, path: &str) -> bool {
+        self.metadata(config, path).is_some_and(|m| m.is_dir())
+    }
+
+    /// `path` opened for reading. The descriptor may be shared with other
+    /// responses, so it must only be read at explicit offsets.
+    pub fn open(&self, config: &OpenFileCacheConfig, path: &str) -> io::Result<Arc<File>> {
+        if !config.enabled {
+            return File::open(path).map(Arc::new);
+        }
+        if let Some(file) = self.fresh(config, path).and_then(|entry| entry.file) {
+            return Ok(file);
+        }
+        let file = Arc::new(File::open(path)?);
+        let metadata = file.metadata().ok();
+        self.insert(config, path, metadata, Some(Arc::clone(&file)));
+        Ok(file)
+    }
+
+    // a copy of the entry for `path` if it is still valid
+    fn fresh(&self, config: &OpenFileCacheConfig, path: &str) -> Option<Entry> {
+        let valid = Duration::from_millis(config.valid_ms);
+        let entries = self.lock();
+        let entry = entries.get(path)?;
+        (entry.checked.elapsed() < valid).then(|| Entry {
+            checked: entry.checked,
+            metadata: entry.metadata.clone(),
+            file: entry.file.clone(),
+        })
+    }
+
+    fn insert(
+        &self,
+        config: &OpenFileCacheConfig,
+        path: &str,
+        metadata: Option<Metadata>,
+        file: Option<Arc<File>>,
+    ) {
+        let mut entries = self.lock();
+        if entries.len() >= config.max_entries && !entries.contains_key(path) {
+            let valid = Duration::from_millis(config.valid_ms);
+            entries.retain(|_, entry| entry.checked.elapsed() < valid);
+            if entries.len() >= config.max_entries {
+                let oldest = entries
+                    .iter()
+                    .min_by_key(|(_, entry)| entry.checked)
+                    .map(|(path, _)| path.clone());
+                if let Some(oldest) = oldest {
+                    entries.remove(&oldest);
+                }
+            }
+        }
+        if config.max_entries == 0 {
+            return;
+        }
+        entries.insert(
+            path.to_string(),
+            Entry {
+                checked: Instant::now(),
+                metadata,
+                file,
+            },
+        );
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<String, Entry>> {
+        self.entries.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
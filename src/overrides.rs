@@ -0,0 +1,134 @@
+//! `.nebula.toml` files in served directories, like `.htaccess`: extra
+//! response headers and a password for the directory and everything below
+//! it. Files are re-read when their modification time changes.
+//!
+//! ```toml
+//! [headers]
+//! X-Robots-Tag = "noindex"
+//!
+//! [auth]
+//! htpasswd = "team.htpasswd"
+//! realm = "Team"
+//! ```
+
+use crate::auth::{self, BasicAuthRule};
+use crate::fs::sanitize_path;
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+/// The name the files have, they are never served.
+pub const FILE_NAME: &str = ".nebula.toml";
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct OverrideFile {
+    headers: BTreeMap<String, String>,
+    auth: Option<OverrideAuth>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct OverrideAuth {
+    // relative to the directory of the file
+    htpasswd: String,
+    realm: Option<String>,
+}
+
+/// What applies to one path, from the file in its directory and the ones
+/// above it. Deeper files win where they set the same thing.
+#[derive(Default)]
+pub struct Overrides {
+    pub headers: Vec<(String, String)>,
+    // on top of any [[auth.basic]] rule, never instead of one
+    pub auth: Option<BasicAuthRule>,
+    // a file on the way couldn't be read, nothing below it is served since
+    // it may have asked for a password
+    pub broken: bool,
+}
+
+type Parsed = Result<Arc<OverrideFile>, String>;
+
+/// Parsed files by path along with the modification time they were read
+/// at.
+#[derive(Default)]
+pub struct OverrideCache {
+    files: Mutex<HashMap<PathBuf, (SystemTime, Parsed)>>,
+}
+
+impl OverrideCache {
+    /// Collects the files from `root` down to the directory `relative`
+    /// is in, or `relative` itself when it is a directory.
+    pub fn resolve(&self, root: &str, relative: &str) -> Overrides {
+        let mut overrides = Overrides::default();
+        let mut dir = PathBuf::from(root);
+        let relative = sanitize_path(relative);
+        let components = std::iter::once("").chain(relative.split('/'));
+        for component in components {
+            dir.push(component);
+            if !dir.is_dir() {
+                break;
+            }
+            let Some(parsed) = self.load(&dir.join(FILE_NAME)) else {
+                continue;
+            };
+            let file = match parsed {
+                Ok(file) => file,
+                Err(_) => {
+                    overrides.broken = true;
+                    break;
+                }
+            };
+
+            for (name, value) in &file.headers {
+                overrides
+                    .headers
+                    .retain(|(existing, _)| !existing.eq_ignore_ascii_case(name));
+                overrides.headers.push((name.clone(), value.clone()));
+            }
+            if let Some(auth) = &file.auth {
+                overrides.auth = Some(BasicAuthRule {
+                    prefix: String::new(),
+                    htpasswd: dir.join(&auth.htpasswd).to_string_lossy().into_owned(),
+                    realm: auth.realm.clone().unwrap_or_else(auth::default_realm),
+                });
+            }
+        }
+        overrides
+    }
+
+    // `None` when there is no such file
+    fn load(&self, path: &Path) -> Option<Parsed> {
+        let modified = match fs::metadata(path).and_then(|metadata| metadata.modified()) {
+            Ok(modified) => modified,
+            Err(_) => {
+                self.lock().remove(path);
+                return None;
+            }
+        };
+        if let Some((cached_at, parsed)) = self.lock().get(path) {
+            if *cached_at == modified {
+                return Some(parsed.clone());
+            }
+        }
+
+        let parsed: Parsed = fs::read_to_string(path)
+            .map_err(|e| e.to_string())
+            .and_then(|content| toml::from_str(&content).map_err(|e| e.to_string()))
+            .map(Arc::new);
+        // reported once per version of the file
+        if let Err(e) = &parsed {
+            tracing::warn!("Failed to load {}: {}", path.display(), e);
+        }
+        self.lock()
+            .insert(path.to_path_buf(), (modified, parsed.clone()));
+        Some(parsed)
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<PathBuf, (SystemTime, Parsed)>> {
+        self.files.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
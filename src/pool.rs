@@ -0,0 +1,92 @@
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// Fixed-size pool of worker threads fed through a bounded queue.
+///
+/// When the queue is full `execute` blocks, which stops the accept loop and
+/// leaves further connections waiting in the kernel backlog instead of piling
+/// up in memory.
+pub struct ThreadPool {
+    workers: Vec<Worker>,
+    sender: Option<SyncSender<Job>>,
+}
+
+impl ThreadPool {
+    pub fn new(size: usize, queue_size: usize) -> ThreadPool {
+        assert!(size > 0, "thread pool needs at least one worker");
+
+        let (sender, receiver) = mpsc::sync_channel(queue_size);
+        let receiver = Arc::new(Mutex::new(receiver));
+
+        let workers = (0..size)
+            .map(|id| Worker::new(id, Arc::clone(&receiver)))
+            .collect();
+
+        ThreadPool {
+            workers,
+            sender: Some(sender),
+        }
+    }
+
+    pub fn execute<F>(&self, f: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        if let Some(sender) = &self.sender {
+            // only fails once every worker is gone, in which case there is
+            // nobody left to run the job anyway
+            if sender.send(Box::new(f)).is_err() {
+                tracing::error!("Thread pool has no workers left, dropping job");
+            }
+        }
+    }
+}
+
+impl Drop for ThreadPool {
+    fn drop(&mut self) {
+        // closing the channel makes every worker fall out of its loop
+        drop(self.sender.take());
+
+        for worker in &mut self.workers {
+            if let Some(thread) = worker.thread.take() {
+                let _ = thread.join();
+            }
+        }
+    }
+}
+
+struct Worker {
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Worker {
+    fn new(id: usize, receiver: Arc<Mutex<Receiver<Job>>>) -> Worker {
+        let thread = thread::Builder::new()
+            .name(format!("nebula-worker-{}", id))
+            .spawn(move || loop {
+                let job = match receiver.lock() {
+                    Ok(receiver) => receiver.recv(),
+                    Err(_) => break,
+                };
+
+                match job {
+                    Ok(job) => {
+                        // a panicking handler must not take the worker down with it
+                        if panic::catch_unwind(AssertUnwindSafe(job)).is_err() {
+                            tracing::error!("Worker {} recovered from a panicked job", id);
+                        }
+                    }
+                    Err(_) => break,
+                }
+            })
+            .expect("failed to spawn worker thread");
+
+        Worker {
+            thread: Some(thread),
+        }
+    }
+}
@@ -0,0 +1,117 @@
+//! `server.processes`: copies of the server forked at startup, each with
+//! its own SO_REUSEPORT sockets so the kernel spreads connections over
+//! them instead of every process queueing behind one accept loop.
+
+use crate::config::ListenConfig;
+use crate::listener::{self, Listener};
+use std::io;
+use std::sync::Arc;
+
+/// Where this process stands among the copies.
+pub enum Role {
+    /// The only process, `server.processes` is 1.
+    Only,
+    /// The one started first, which passes signals on to the others.
+    Parent(Vec<libc::pid_t>),
+    Child,
+}
+
+/// Forks until there are `count` processes. Has to happen before any
+/// thread is started. Children bind their own copy of each TCP listener,
+/// sockets that came from systemd or are unix sockets stay shared.
+#[cfg(target_os = "linux")]
+pub fn spawn(
+    count: usize,
+    listeners: &mut [(Listener, Arc<ListenConfig>)],
+    inherited: bool,
+) -> io::Result<Role> {
+    if count <= 1 {
+        return Ok(Role::Only);
+    }
+    let mut children = Vec::new();
+    for _ in 1..count {
+        // SAFETY: `Server::run` forks before it starts the worker pool, the
+        // signal thread or any other, so this process has a single thread.
+        // No lock or allocator state can be held by a thread the child
+        // doesn't have, and the child can go on like any other process.
+        match unsafe { libc::fork() } {
+            -1 => {
+                let e = io::Error::last_os_error();
+                signal(&children, libc::SIGTERM);
+                return Err(e);
+            }
+            0 => {
+                become_child(listeners, inherited)?;
+                return Ok(Role::Child);
+            }
+            pid => children.push(pid),
+        }
+    }
+    Ok(Role::Parent(children))
+}
+
+#[cfg(target_os = "linux")]
+fn become_child(
+    listeners: &mut [(Listener, Arc<ListenConfig>)],
+    inherited: bool,
+) -> io::Result<()> {
+    // a separate process group keeps Ctrl-C in the terminal to the parent,
+    // and the parent dying takes the children along
+    // SAFETY: both only change attributes of this process and take no
+    // pointers.
+    unsafe {
+        libc::setpgid(0, 0);
+        libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGTERM);
+    }
+    // only the main process may report to systemd. Changing the
+    // environment is only sound before other threads exist, which the fork
+    // above ensures
+    std::env::remove_var("NOTIFY_SOCKET");
+    if inherited {
+        return Ok(());
+    }
+    for (listener, listen) in listeners {
+        if let Listener::Tcp(_) = listener {
+            *listener = listener::bind(listen, true)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn spawn(
+    count: usize,
+    _listeners: &mut [(Listener, Arc<ListenConfig>)],
+    _inherited: bool,
+) -> io::Result<Role> {
+    if count <= 1 {
+        return Ok(Role::Only);
+    }
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "server.processes is only available on Linux",
+    ))
+}
+
+/// Sends `signal` to the children.
+pub fn signal(children: &[libc::pid_t], signal: libc::c_int) {
+    for pid in children {
+        // SAFETY: kill() touches no memory of ours. The pids are children
+        // that are only reaped by `wait`, so none has been reused yet.
+        unsafe { libc::kill(*pid, signal) };
+    }
+}
+
+/// Waits for the children to exit, reporting the ones that failed.
+pub fn wait(children: &[libc::pid_t]) {
+    for pid in children {
+        let mut status = 0;
+        // SAFETY: `status` is a live local the exit status is written to
+        if unsafe { libc::waitpid(*pid, &mut status, 0) } == -1 {
+            continue;
+        }
+        if !libc::WIFEXITED(status) || libc::WEXITSTATUS(status) != 0 {
+            tracing::warn!("Process {} exited with status {}", pid, status);
+        }
+    }
+}
@@ -0,0 +1,474 @@
+use crate::error::NebulaError;
+use crate::request::{ChunkedReader, HeadLimits, ReadError, Request, RequestReader, ResponseHead};
+use crate::response::is_valid_field;
+use crate::telemetry::{self, Span, SpanKind};
+use crate::upstream::{UpstreamGuard, Upstreams};
+use crate::uri;
+use serde::Deserialize;
+use std::io::{self, BufReader, Cursor, Read, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+#[derive(Deserialize, Clone)]
+pub struct ProxyConfig {
+    // URL prefix like `/api/`, the longest matching prefix wins
+    pub prefix: String,
+    // `host:port` of the upstream HTTP server
+    #[serde(default)]
+    pub upstream: Option<String>,
+    // several upstreams to spread requests over, combined with `upstream`
+    #[serde(default)]
+    pub upstreams: Vec<String>,
+    #[serde(default)]
+    pub balance: Balance,
+    // open connections per upstream, 0 means unlimited
+    #[serde(default)]
+    pub max_connections: usize,
+    // forward `/api/users` as `/users`
+    #[serde(default)]
+    pub strip_prefix: bool,
+    // pass the client's Host header on instead of the upstream address
+    #[serde(default)]
+    pub preserve_host: bool,
+    // seconds to wait for the upstream when connecting, reading or writing
+    #[serde(default = "default_timeout")]
+    pub timeout: u64,
+    // `[proxy.health_check]`, upstreams are assumed healthy without one
+    #[serde(default)]
+    pub health_check: Option<HealthCheck>,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct HealthCheck {
+    // answered with a 2xx or 3xx by healthy upstreams
+    #[serde(default = "default_health_path")]
+    pub path: String,
+    // seconds between checks
+    #[serde(default = "default_health_interval")]
+    pub interval: u64,
+    // consecutive failed checks before an upstream leaves the rotation
+    #[serde(default = "default_unhealthy_threshold")]
+    pub unhealthy_threshold: u32,
+    // seconds a single check may take
+    #[serde(default = "default_health_timeout")]
+    pub timeout: u64,
+}
+
+#[derive(Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Balance {
+    #[default]
+    RoundRobin,
+    LeastConnections,
+}
+
+fn default_timeout() -> u64 {
+    60
+}
+
+fn default_health_path() -> String {
+    "/".to_string()
+}
+
+fn default_health_interval() -> u64 {
+    10
+}
+
+fn default_unhealthy_threshold() -> u32 {
+    3
+}
+
+fn default_health_timeout() -> u64 {
+    2
+}
+
+impl ProxyConfig {
+    pub fn upstreams(&self) -> Vec<&str> {
+        self.upstream
+            .iter()
+            .chain(self.upstreams.iter())
+            .map(|upstream| upstream.as_str())
+            .collect()
+    }
+}
+
+pub fn find_rule<'a>(rules: &'a [ProxyConfig], path: &str) -> Option<&'a ProxyConfig> {
+    rules
+        .iter()
+        .filter(|rule| path.starts_with(&rule.prefix))
+        .max_by_key(|rule| rule.prefix.len())
+}
+
+pub enum ProxyOutcome<'a> {
+    /// The upstream response was relayed to the client.
+    Served {
+        status: u16,
+        bytes: u64,
+        keep_alive: bool,
+    },
+    /// The upstream switched to WebSocket and its `101` was relayed, the
+    /// caller tunnels the connection. `buffered` holds what the upstream
+    /// sent after its head.
+    Upgraded {
+        upstream: TcpStream,
+        buffered: Vec<u8>,
+        guard: UpstreamGuard<'a>,
+    },
+    /// Nothing was sent to the client yet, the caller answers with the
+    /// error's status.
+    Failed(NebulaError),
+}
+
+// headers that only apply to a single connection and must not be forwarded
+const HOP_BY_HOP: [&str; 9] = [
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "proxy-connection",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+const RESPONSE_LIMITS: HeadLimits = HeadLimits {
+    max_request_line: 8192,
+    max_header_size: 65536,
+};
+
+/// Forwards the request and its body to one of the upstreams of `rule` and
+/// streams the response back to `client`.
+pub fn forward<'a, S: Read + Write>(
+    rule: &ProxyConfig,
+    upstreams: &'a Upstreams,
+    request: &Request,
+    client: &mut S,
+    reader: &mut RequestReader,
+    remote_addr: Option<SocketAddr>,
+    keep_alive: bool,
+) -> io::Result<ProxyOutcome<'a>> {
+    // nothing has been sent when connecting fails, so the next upstream
+    // can take over
+    let mut tried = Vec::new();
+    let (guard, mut upstream) = loop {
+        let Some(guard) = upstreams.select(rule, &tried) else {
+            // every upstream that was tried refused the connection
+            let (kind, message) = if tried.is_empty() {
+                (io::ErrorKind::NotConnected, "no upstream available")
+            } else {
+                (
+                    io::ErrorKind::ConnectionRefused,
+                    "no upstream accepted the connection",
+                )
+            };
+            return Ok(ProxyOutcome::Failed(NebulaError::Upstream {
+                upstream: rule.prefix.clone(),
+                source: io::Error::new(kind, message),
+            }));
+        };
+        match connect(guard.address(), Duration::from_secs(rule.timeout.max(1))) {
+            Ok(upstream) => break (guard, upstream),
+            Err(e) => {
+                failed(guard.address(), e).log();
+                tried.push(guard.address().to_string());
+            }
+        }
+    };
+    let address = guard.address();
+    let trace = Span::child("proxy", SpanKind::Client);
+    trace.set("server.address", authority(address));
+    trace.set("http.request.method", request.method.as_str());
+    let fail = |e: io::Error| {
+        trace.set_error();
+        ProxyOutcome::Failed(failed(address, e))
+    };
+
+    let head = request_head(rule, address, request, remote_addr);
+    let sent = upstream.write_all(head.as_bytes()).and_then(|_| {
+        let mut body = reader.body(client);
+        if request.is_chunked() {
+            send_chunked(&mut body, &mut upstream)
+        } else {
+            io::copy(&mut body, &mut upstream)
+        }
+    });
+    if let Err(e) = sent {
+        if let Some(error) = reader.take_body_error() {
+            return Ok(ProxyOutcome::Failed(error));
+        }
+        return Ok(fail(e));
+    }
+
+    // skip interim responses like `100 Continue`, the body is already sent,
+    // but not the `101` an upgrade asked for
+    let upgrade = request.is_websocket_upgrade();
+    let mut upstream_reader = RequestReader::default();
+    let response = loop {
+        let head = match upstream_reader.read_head(&mut upstream, &RESPONSE_LIMITS) {
+            Ok(head) => head,
+            // timeouts turn into a 504, see NebulaError::status
+            Err(ReadError::Io(e)) => return Ok(fail(e)),
+            Err(_) => {
+                let e = io::Error::new(io::ErrorKind::InvalidData, "malformed response head");
+                return Ok(fail(e));
+            }
+        };
+        match ResponseHead::parse(&head) {
+            Some(response) if response.status == 101 && upgrade => break response,
+            Some(response) if (100..200).contains(&response.status) => continue,
+            Some(response) => break response,
+            None => {
+                let e = io::Error::new(io::ErrorKind::InvalidData, "malformed response head");
+                return Ok(fail(e));
+            }
+        }
+    };
+    trace.set_status(response.status);
+    // the head is relayed as it came, a bare CR or LF in it must not reach
+    // the client
+    let invalid_field = response
+        .headers
+        .iter()
+        .any(|(name, value)| !is_valid_field(name, value));
+    let invalid_reason = response
+        .reason
+        .bytes()
+        .any(|b| matches!(b, b'\r' | b'\n' | b'\0'));
+    if invalid_field || invalid_reason {
+        let e = io::Error::new(io::ErrorKind::InvalidData, "invalid response header");
+        return Ok(fail(e));
+    }
+
+    if response.status == 101 {
+        let mut head = format!("HTTP/1.1 101 {}\r\n", response.reason);
+        for (name, value) in response.headers.iter() {
+            let lower = name.to_ascii_lowercase();
+            if !HOP_BY_HOP.contains(&lower.as_str()) {
+                head.push_str(&format!("{}: {}\r\n", name, value));
+            }
+        }
+        head.push_str("Upgrade: websocket\r\nConnection: Upgrade\r\n\r\n");
+        client.write_all(head.as_bytes())?;
+        return Ok(ProxyOutcome::Upgraded {
+            upstream,
+            buffered: upstream_reader.take_buffered(),
+            guard,
+        });
+    }
+
+    let no_body = request.method == "HEAD" || response.status == 204 || response.status == 304;
+    let content_length = response
+        .headers
+        .get("content-length")
+        .and_then(|value| value.trim().parse::<u64>().ok());
+    let chunked = response
+        .headers
+        .get("transfer-encoding")
+        .is_some_and(|value| value.to_ascii_lowercase().contains("chunked"));
+    // HTTP/1.0 clients don't know chunked bodies, they get the decoded
+    // bytes up to the end of the connection instead
+    let relay_chunked = chunked && request.version != "HTTP/1.0";
+    // a body that only ends when the upstream closes can't share the client
+    // connection with further requests
+    let keep_alive = keep_alive && (no_body || content_length.is_some() || relay_chunked);
+
+    let mut head = format!("HTTP/1.1 {} {}\r\n", response.status, response.reason);
+    for (name, value) in response.headers.iter() {
+        let lower = name.to_ascii_lowercase();
+        let keep_encoding = relay_chunked && lower == "transfer-encoding";
+        if !HOP_BY_HOP.contains(&lower.as_str()) || keep_encoding {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+    }
+    head.push_str(if keep_alive {
+        "Connection: keep-alive\r\n\r\n"
+    } else {
+        "Connection: close\r\n\r\n"
+    });
+    client.write_all(head.as_bytes())?;
+
+    let bytes = if no_body {
+        0
+    } else if let (Some(len), false) = (content_length, chunked) {
+        upstream_reader.start_body(len);
+        io::copy(&mut upstream_reader.body(&mut upstream), client)?
+    } else if chunked && !relay_chunked {
+        let buffered = upstream_reader.take_buffered();
+        let body = BufReader::new(Cursor::new(buffered).chain(&mut upstream));
+        io::copy(&mut ChunkedReader::new(body), client)?
+    } else {
+        // chunked bodies are relayed as-is, the upstream closes the
+        // connection after the last chunk since we asked it to
+        let buffered = upstream_reader.take_buffered();
+        io::copy(&mut Cursor::new(buffered).chain(&mut upstream), client)?
+    };
+
+    Ok(ProxyOutcome::Served {
+        status: response.status,
+        bytes,
+        keep_alive,
+    })
+}
+
+/// Sends a GET for the health check path and reports whether the upstream
+/// answered with a 2xx or 3xx status.
+pub fn probe(upstream: &str, check: &HealthCheck) -> bool {
+    let status = (|| {
+        let mut stream = connect(upstream, Duration::from_secs(check.timeout.max(1)))?;
+        let head = format!(
+            "GET {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: Nebula/0.1\r\nConnection: close\r\n\r\n",
+            check.path,
+            authority(upstream)
+        );
+        stream.write_all(head.as_bytes())?;
+
+        let head = RequestReader::default()
+            .read_head(&mut stream, &RESPONSE_LIMITS)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "no response head"))?;
+        ResponseHead::parse(&head)
+            .map(|response| response.status)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "malformed response head"))
+    })();
+
+    matches!(status, Ok(200..=399))
+}
+
+fn connect(upstream: &str, timeout: Duration) -> io::Result<TcpStream> {
+    let addr = authority(upstream)
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::AddrNotAvailable, "no upstream address"))?;
+
+    let stream = TcpStream::connect_timeout(&addr, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    Ok(stream)
+}
+
+// `http://app:3000/` and `app:3000` both mean `app:3000`
+fn authority(upstream: &str) -> &str {
+    upstream.trim_start_matches("http://").trim_end_matches('/')
+}
+
+fn request_head(
+    rule: &ProxyConfig,
+    upstream: &str,
+    request: &Request,
+    remote_addr: Option<SocketAddr>,
+) -> String {
+    // the target is the normalized path by now, its prefix is compared
+    // decoded like the rule was matched, so `/%61pi/x` loses `/api` too
+    let target = match uri::percent_decode(&request.path) {
+        Some(path) if rule.strip_prefix => {
+            let rest = path.strip_prefix(&rule.prefix).unwrap_or(&path);
+            let rest = if rest.starts_with('/') {
+                uri::percent_encode_path(rest)
+            } else {
+                format!("/{}", uri::percent_encode_path(rest))
+            };
+            match &request.query {
+                Some(query) => format!("{}?{}", rest, query),
+                None => rest,
+            }
+        }
+        _ => request.target.clone(),
+    };
+
+    let client_host = request.header("host");
+    let host = match client_host {
+        Some(host) if rule.preserve_host => host,
+        _ => authority(upstream),
+    };
+
+    // headers the client marked as connection specific
+    let connection_tokens: Vec<String> = request
+        .header("connection")
+        .map(|value| {
+            value
+                .split(',')
+                .map(|token| token.trim().to_ascii_lowercase())
+                .collect()
+        })
+        .unwrap_or_default();
+
+    let mut head = format!(
+        "{} {} HTTP/1.1\r\nHost: {}\r\n",
+        request.method, target, host
+    );
+    let traceparent = telemetry::traceparent();
+    if let Some(traceparent) = &traceparent {
+        head.push_str(&format!("traceparent: {}\r\n", traceparent));
+    }
+    for (name, value) in request.headers.iter() {
+        let lower = name.to_ascii_lowercase();
+        // replaced by the one of the proxy span when traces are recorded
+        if lower == "traceparent" && traceparent.is_some() {
+            continue;
+        }
+        // a chunked body is chunked anew, whatever length came along with
+        // it doesn't describe it
+        let stale_length = lower == "content-length" && request.is_chunked();
+        // the client's expectation was already met here
+        let expect = lower == "expect";
+        if lower == "host"
+            || lower == "x-forwarded-for"
+            || stale_length
+            || expect
+            || HOP_BY_HOP.contains(&lower.as_str())
+            || connection_tokens.contains(&lower)
+        {
+            continue;
+        }
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+
+    let client_ip = remote_addr.map(|addr| addr.ip().to_string());
+    let forwarded_for = match (request.header("x-forwarded-for"), client_ip) {
+        (Some(existing), Some(ip)) => Some(format!("{}, {}", existing, ip)),
+        (Some(existing), None) => Some(existing.to_string()),
+        (None, ip) => ip,
+    };
+    if let Some(forwarded_for) = forwarded_for {
+        head.push_str(&format!("X-Forwarded-For: {}\r\n", forwarded_for));
+    }
+    if let Some(client_host) = client_host {
+        head.push_str(&format!("X-Forwarded-Host: {}\r\n", client_host));
+    }
+    head.push_str("X-Forwarded-Proto: http\r\n");
+    if request.is_chunked() {
+        head.push_str("Transfer-Encoding: chunked\r\n");
+    }
+    // one request per upstream connection keeps the response framing simple,
+    // an upgraded one carries the WebSocket instead
+    if request.is_websocket_upgrade() {
+        head.push_str("Upgrade: websocket\r\nConnection: Upgrade\r\n\r\n");
+    } else {
+        head.push_str("Connection: close\r\n\r\n");
+    }
+    head
+}
+
+// re-encodes a decoded request body as chunks, one per read
+fn send_chunked<R: Read, W: Write>(body: &mut R, upstream: &mut W) -> io::Result<u64> {
+    let mut buf = [0; 8192];
+    let mut sent = 0;
+    loop {
+        let n = body.read(&mut buf)?;
+        if n == 0 {
+            upstream.write_all(b"0\r\n\r\n")?;
+            return Ok(sent);
+        }
+        write!(upstream, "{:x}\r\n", n)?;
+        upstream.write_all(&buf[..n])?;
+        upstream.write_all(b"\r\n")?;
+        sent += n as u64;
+    }
+}
+
+fn failed(upstream: &str, source: io::Error) -> NebulaError {
+    NebulaError::Upstream {
+        upstream: upstream.to_string(),
+        source,
+    }
+}
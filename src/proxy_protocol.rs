@@ -0,0 +1,107 @@
+//! The PROXY protocol header (versions 1 and 2) that load balancers like
+//! HAProxy or an AWS NLB send ahead of the HTTP request, carrying the
+//! address of the client they accepted the connection from.
+
+use std::io::{self, Read};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+// what a version 2 header starts with, it can't be the start of a request
+const SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+
+// a version 1 header including its CRLF is never longer than this
+const MAX_V1_LEN: usize = 107;
+
+/// Reads the header and nothing after it. Returns the client address, or
+/// `None` when the balancer connected on its own behalf, e.g. for a health
+/// check. A connection without a valid header fails with `InvalidData`.
+pub fn read_header<R: Read>(stream: &mut R) -> io::Result<Option<SocketAddr>> {
+    // the shortest header, `PROXY UNKNOWN\r\n`, is longer than the signature
+    let mut start = [0; 12];
+    stream.read_exact(&mut start)?;
+    if &start == SIGNATURE {
+        read_v2(stream)
+    } else if start.starts_with(b"PROXY ") {
+        read_v1(stream, &start)
+    } else {
+        Err(invalid("missing PROXY protocol header"))
+    }
+}
+
+// `PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\n`
+fn read_v1<R: Read>(stream: &mut R, start: &[u8]) -> io::Result<Option<SocketAddr>> {
+    let mut line = start.to_vec();
+    // byte by byte, the request right behind it must stay in the socket
+    let mut byte = [0; 1];
+    while !line.ends_with(b"\r\n") {
+        if line.len() == MAX_V1_LEN {
+            return Err(invalid("PROXY protocol header too long"));
+        }
+        stream.read_exact(&mut byte)?;
+        line.push(byte[0]);
+    }
+
+    let line = std::str::from_utf8(&line[..line.len() - 2])
+        .map_err(|_| invalid("PROXY protocol header is not ASCII"))?;
+    let fields: Vec<&str> = line.split(' ').collect();
+    match fields[..] {
+        [_, "UNKNOWN", ..] => Ok(None),
+        [_, family @ ("TCP4" | "TCP6"), source, _, port, _] => {
+            let ip: IpAddr = source
+                .parse()
+                .map_err(|_| invalid("invalid PROXY protocol address"))?;
+            let port: u16 = port
+                .parse()
+                .map_err(|_| invalid("invalid PROXY protocol port"))?;
+            if ip.is_ipv4() != (family == "TCP4") {
+                return Err(invalid("invalid PROXY protocol address"));
+            }
+            Ok(Some(SocketAddr::new(ip, port)))
+        }
+        _ => Err(invalid("invalid PROXY protocol header")),
+    }
+}
+
+// the binary header: version and command, address family, the length of
+// the rest and then the addresses, possibly followed by TLVs
+fn read_v2<R: Read>(stream: &mut R) -> io::Result<Option<SocketAddr>> {
+    let mut head = [0; 4];
+    stream.read_exact(&mut head)?;
+    let [version_command, family, len_high, len_low] = head;
+    if version_command >> 4 != 2 {
+        return Err(invalid("unsupported PROXY protocol version"));
+    }
+    let mut rest = vec![0; u16::from_be_bytes([len_high, len_low]) as usize];
+    stream.read_exact(&mut rest)?;
+
+    // LOCAL, the balancer talking for itself
+    if version_command & 0x0f == 0 {
+        return Ok(None);
+    }
+    let short = || invalid("PROXY protocol addresses cut short");
+    match family >> 4 {
+        // AF_INET
+        1 => {
+            let source: [u8; 4] = rest.get(..4).ok_or_else(short)?.try_into().unwrap();
+            let port = rest.get(8..10).ok_or_else(short)?;
+            Ok(Some(SocketAddr::new(
+                IpAddr::V4(Ipv4Addr::from(source)),
+                u16::from_be_bytes([port[0], port[1]]),
+            )))
+        }
+        // AF_INET6
+        2 => {
+            let source: [u8; 16] = rest.get(..16).ok_or_else(short)?.try_into().unwrap();
+            let port = rest.get(32..34).ok_or_else(short)?;
+            Ok(Some(SocketAddr::new(
+                IpAddr::V6(Ipv6Addr::from(source)),
+                u16::from_be_bytes([port[0], port[1]]),
+            )))
+        }
+        // unix sockets and unspecified families carry no IP to use
+        _ => Ok(None),
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
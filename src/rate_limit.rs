@@ -0,0 +1,104 @@
+//! Token bucket rate limiting per client IP.
+
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::Instant;
+
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct RateLimitConfig {
+    pub enabled: bool,
+    // tokens added to each client's bucket per second
+    pub requests_per_second: f64,
+    // bucket size, i.e. how many requests may arrive at once
+    pub burst: f64,
+    // own limits for some prefixes, the longest matching prefix wins
+    pub paths: Vec<PathLimit>,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct PathLimit {
+    pub prefix: String,
+    pub requests_per_second: f64,
+    pub burst: f64,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        RateLimitConfig {
+            enabled: false,
+            requests_per_second: 10.0,
+            burst: 20.0,
+            paths: Vec::new(),
+        }
+    }
+}
+
+// past this many buckets, full ones are dropped since they behave exactly
+// like a fresh bucket
+const PRUNE_THRESHOLD: usize = 10_000;
+
+struct Bucket {
+    tokens: f64,
+    refilled: Instant,
+}
+
+/// Buckets keyed by client IP and the prefix of the limit they count
+/// against, shared by all workers.
+#[derive(Default)]
+pub struct RateLimiter {
+    buckets: Mutex<HashMap<(IpAddr, String), Bucket>>,
+}
+
+impl RateLimiter {
+    /// Takes a token for a request from `ip` to `path`. Returns the number of
+    /// seconds to wait before retrying when the bucket is empty.
+    pub fn check(&self, config: &RateLimitConfig, ip: IpAddr, path: &str) -> Result<(), u64> {
+        if !config.enabled {
+            return Ok(());
+        }
+
+        let (prefix, rate, burst) = match config
+            .paths
+            .iter()
+            .filter(|limit| path.starts_with(&limit.prefix))
+            .max_by_key(|limit| limit.prefix.len())
+        {
+            Some(limit) => (
+                limit.prefix.as_str(),
+                limit.requests_per_second,
+                limit.burst,
+            ),
+            None => ("", config.requests_per_second, config.burst),
+        };
+        let burst = burst.max(1.0);
+        let now = Instant::now();
+
+        let mut buckets = self.buckets.lock().unwrap_or_else(|e| e.into_inner());
+        if buckets.len() > PRUNE_THRESHOLD {
+            buckets.retain(|_, bucket| {
+                bucket.tokens + now.duration_since(bucket.refilled).as_secs_f64() * rate < burst
+            });
+        }
+
+        let bucket = buckets.entry((ip, prefix.to_string())).or_insert(Bucket {
+            tokens: burst,
+            refilled: now,
+        });
+        let elapsed = now.duration_since(bucket.refilled).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * rate).min(burst);
+        bucket.refilled = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else if rate > 0.0 {
+            Err(((1.0 - bucket.tokens) / rate).ceil().max(1.0) as u64)
+        } else {
+            // a zero rate never refills, ask for a minute so clients back off
+            Err(60)
+        }
+    }
+}
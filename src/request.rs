@@ -0,0 +1,869 @@
+use crate::error::NebulaError;
+use crate::listener::Stream;
+use std::io::{self, BufRead, Read, Write};
+use std::time::{Duration, Instant};
+
+/// Upper bounds for the request head, anything larger is rejected before
+/// it can tie up memory.
+pub struct HeadLimits {
+    pub max_request_line: usize,
+    pub max_header_size: usize,
+}
+
+pub enum ReadError {
+    /// The peer closed the connection before sending anything.
+    Closed,
+    RequestLineTooLong,
+    HeadersTooLarge,
+    Io(io::Error),
+}
+
+impl From<io::Error> for ReadError {
+    fn from(e: io::Error) -> Self {
+        ReadError::Io(e)
+    }
+}
+
+/// Reads request heads off a connection, keeping any bytes that arrived
+/// after the end of one head around for the body or the next (pipelined)
+/// request.
+#[derive(Default)]
+pub struct RequestReader {
+    buffer: Vec<u8>,
+    framing: Framing,
+    // why reading a chunked body failed, if it was the client's fault
+    body_error: Option<NebulaError>,
+    // the client waits for a `100 Continue` before sending the body
+    continue_pending: bool,
+}
+
+// how the end of the current body is found
+enum Framing {
+    // body bytes that haven't been read yet
+    Length(u64),
+    // decoded bytes read so far and how many are allowed
+    Chunked {
+        chunk: ChunkState,
+        read: u64,
+        limit: u64,
+    },
+}
+
+impl Default for Framing {
+    fn default() -> Self {
+        Framing::Length(0)
+    }
+}
+
+impl RequestReader {
+    /// Whether data is already waiting, i.e. a pipelined request.
+    pub fn has_buffered(&self) -> bool {
+        !self.buffer.is_empty()
+    }
+
+    /// Reads until the blank line that terminates the head and returns the
+    /// head including that final `\r\n\r\n`.
+    pub fn read_head<R: Read>(
+        &mut self,
+        stream: &mut R,
+        limits: &HeadLimits,
+    ) -> Result<Vec<u8>, ReadError> {
+        let mut chunk = [0; 4096];
+        let mut searched = 0;
+
+        loop {
+            // RFC 9112 asks servers to ignore empty lines before the request line
+            let leading = self
+                .buffer
+                .iter()
+                .take_while(|b| **b == b'\r' || **b == b'\n')
+                .count();
+            if leading > 0 {
+                self.buffer.drain(..leading);
+                searched = 0;
+            }
+
+            let head_end = find_head_end(&self.buffer, searched);
+            // the terminator may straddle two reads
+            searched = self.buffer.len().saturating_sub(3);
+
+            // only the bytes up to the end of the head count against the limits,
+            // a pipelined request right behind it is checked on its own turn
+            let head_len = head_end.unwrap_or(self.buffer.len());
+            let line_len = self.buffer[..head_len]
+                .iter()
+                .position(|b| *b == b'\n')
+                .unwrap_or(head_len);
+            if line_len > limits.max_request_line {
+                return Err(ReadError::RequestLineTooLong);
+            }
+            if head_len > line_len + limits.max_header_size {
+                return Err(ReadError::HeadersTooLarge);
+            }
+
+            if let Some(end) = head_end {
+                let head: Vec<u8> = self.buffer.drain(..end).collect();
+                return Ok(head);
+            }
+
+            let n = stream.read(&mut chunk)?;
+            if n == 0 {
+                return Err(if self.buffer.is_empty() {
+                    ReadError::Closed
+                } else {
+                    ReadError::Io(io::ErrorKind::UnexpectedEof.into())
+                });
+            }
+            self.buffer.extend_from_slice(&chunk[..n]);
+        }
+    }
+}
+
+impl RequestReader {
+    /// Announces that the request whose head was just read carries `len`
+    /// body bytes.
+    pub fn start_body(&mut self, len: u64) {
+        self.framing = Framing::Length(len);
+        self.body_error = None;
+        self.continue_pending = false;
+    }
+
+    /// Announces a chunked body, whose size is only known once it has been
+    /// read. Reads fail once more than `limit` bytes were decoded.
+    pub fn start_chunked_body(&mut self, limit: u64) {
+        self.framing = Framing::Chunked {
+            chunk: ChunkState::default(),
+            read: 0,
+            limit,
+        };
+        self.body_error = None;
+        self.continue_pending = false;
+    }
+
+    /// Holds back `100 Continue` for a client that sent
+    /// `Expect: 100-continue` until the body is actually read, so requests
+    /// refused right away don't make it upload anything.
+    pub fn expect_continue(&mut self) {
+        self.continue_pending = true;
+    }
+
+    /// Why reading the body failed when the client is to blame, i.e. a
+    /// malformed chunked body or one that outgrew its limit.
+    pub fn take_body_error(&mut self) -> Option<NebulaError> {
+        self.body_error.take()
+    }
+
+    /// Hands out everything read past the last head, e.g. the start of a
+    /// response body that is read until EOF.
+    pub fn take_buffered(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.buffer)
+    }
+
+    /// Reader over the body of the current request.
+    pub fn body<'a, R: Read + Write>(&'a mut self, stream: &'a mut R) -> BodyReader<'a, R> {
+        BodyReader {
+            reader: self,
+            stream,
+        }
+    }
+
+    /// Reads the whole body into memory.
+    pub fn read_body<R: Read + Write>(&mut self, stream: &mut R) -> Result<Vec<u8>, NebulaError> {
+        let mut body = Vec::new();
+        match self.body(stream).read_to_end(&mut body) {
+            Ok(_) => Ok(body),
+            Err(e) => Err(self
+                .take_body_error()
+                .unwrap_or_else(|| NebulaError::io("Failed to read request body", e))),
+        }
+    }
+
+    /// Skips whatever part of the body the handler didn't consume, so the
+    /// next request on the connection starts at the right byte. Bodies with
+    /// more than `limit` bytes left aren't worth reading just to throw them
+    /// away, returns false for those and the connection has to close. So
+    /// does a body the client is still holding back for a `100 Continue`.
+    pub fn discard_body<R: Read + Write>(
+        &mut self,
+        stream: &mut R,
+        limit: u64,
+    ) -> io::Result<bool> {
+        match self.framing {
+            Framing::Length(0) => return Ok(true),
+            Framing::Length(remaining) if remaining > limit => return Ok(false),
+            _ if self.continue_pending => return Ok(false),
+            _ => {}
+        }
+        let skipped = io::copy(&mut self.body(stream).take(limit + 1), &mut io::sink());
+        match skipped {
+            Ok(skipped) => Ok(skipped <= limit),
+            Err(_) if self.take_body_error().is_some() => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// Reads the body of the current request up to its end, serving bytes that
+/// were buffered along with the head before touching the stream.
+pub struct BodyReader<'a, R> {
+    reader: &'a mut RequestReader,
+    stream: &'a mut R,
+}
+
+impl<R: Read + Write> Read for BodyReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let RequestReader {
+            buffer,
+            framing,
+            body_error,
+            continue_pending,
+        } = &mut *self.reader;
+
+        let has_more = match framing {
+            Framing::Length(remaining) => *remaining > 0,
+            Framing::Chunked { chunk, .. } => !chunk.done,
+        };
+        if *continue_pending && has_more && !buf.is_empty() {
+            self.stream.write_all(b"HTTP/1.1 100 Continue\r\n\r\n")?;
+            self.stream.flush()?;
+            *continue_pending = false;
+        }
+
+        let (chunk, read, limit) = match framing {
+            Framing::Length(remaining) => {
+                if *remaining == 0 || buf.is_empty() {
+                    return Ok(0);
+                }
+                let max = buf
+                    .len()
+                    .min(usize::try_from(*remaining).unwrap_or(usize::MAX));
+                let n = if buffer.is_empty() {
+                    self.stream.read(&mut buf[..max])?
+                } else {
+                    let n = max.min(buffer.len());
+                    buf[..n].copy_from_slice(&buffer[..n]);
+                    buffer.drain(..n);
+                    n
+                };
+
+                if n == 0 {
+                    return Err(io::ErrorKind::UnexpectedEof.into());
+                }
+                *remaining -= n as u64;
+                return Ok(n);
+            }
+            Framing::Chunked { chunk, read, limit } => (chunk, read, limit),
+        };
+
+        // chunk lines are read through the buffer, whatever follows the
+        // body stays there for the next request
+        let mut buffered = Buffered {
+            buffer,
+            stream: &mut *self.stream,
+        };
+        let n = chunk.read(&mut buffered, buf).inspect_err(|e| {
+            if e.kind() == io::ErrorKind::InvalidData {
+                *body_error = Some(NebulaError::BadRequest(format!(
+                    "Malformed chunked body: {}",
+                    e
+                )));
+            }
+        })?;
+        *read += n as u64;
+        if *read > *limit {
+            *body_error = Some(NebulaError::BodyTooLarge);
+            return Err(malformed("request body too large"));
+        }
+        Ok(n)
+    }
+}
+
+// the bytes buffered after the head followed by the stream, refilling the
+// buffer from the stream when it runs dry
+struct Buffered<'a, R> {
+    buffer: &'a mut Vec<u8>,
+    stream: &'a mut R,
+}
+
+impl<R: Read> Read for Buffered<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let available = self.fill_buf()?;
+        let n = buf.len().min(available.len());
+        buf[..n].copy_from_slice(&available[..n]);
+        self.consume(n);
+        Ok(n)
+    }
+}
+
+impl<R: Read> BufRead for Buffered<'_, R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if self.buffer.is_empty() {
+            let mut chunk = [0; 4096];
+            let n = self.stream.read(&mut chunk)?;
+            self.buffer.extend_from_slice(&chunk[..n]);
+        }
+        Ok(self.buffer)
+    }
+
+    fn consume(&mut self, amount: usize) {
+        self.buffer.drain(..amount);
+    }
+}
+
+/// Decodes a `Transfer-Encoding: chunked` body, ending after the last chunk
+/// and its trailers.
+pub struct ChunkedReader<R> {
+    inner: R,
+    chunk: ChunkState,
+}
+
+impl<R: BufRead> ChunkedReader<R> {
+    pub fn new(inner: R) -> Self {
+        ChunkedReader {
+            inner,
+            chunk: ChunkState::default(),
+        }
+    }
+}
+
+impl<R: BufRead> Read for ChunkedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.chunk.read(&mut self.inner, buf)
+    }
+}
+
+// where a chunked body is between reads
+#[derive(Default)]
+struct ChunkState {
+    // bytes left in the current chunk
+    remaining: u64,
+    started: bool,
+    done: bool,
+}
+
+// longer chunk size or trailer lines are treated as malformed
+const MAX_CHUNK_LINE: u64 = 4096;
+
+impl ChunkState {
+    fn read<R: BufRead>(&mut self, inner: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+        if self.done || buf.is_empty() {
+            return Ok(0);
+        }
+        if self.remaining == 0 {
+            // every chunk's data is followed by a line ending
+            if self.started && !read_chunk_line(inner)?.is_empty() {
+                return Err(malformed("chunk is longer than its size"));
+            }
+            self.started = true;
+
+            let line = read_chunk_line(inner)?;
+            // chunk extensions after `;` carry nothing we use
+            let size = line.split(';').next().unwrap_or("").trim();
+            let size =
+                u64::from_str_radix(size, 16).map_err(|_| malformed("invalid chunk size"))?;
+            if size == 0 {
+                // trailer fields up to the closing blank line are dropped
+                while !read_chunk_line(inner)?.is_empty() {}
+                self.done = true;
+                return Ok(0);
+            }
+            self.remaining = size;
+        }
+
+        let max = buf
+            .len()
+            .min(usize::try_from(self.remaining).unwrap_or(usize::MAX));
+        let n = inner.read(&mut buf[..max])?;
+        if n == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        self.remaining -= n as u64;
+        Ok(n)
+    }
+}
+
+// one line of a chunked body without its line ending
+fn read_chunk_line<R: BufRead>(inner: &mut R) -> io::Result<String> {
+    let mut line = Vec::new();
+    inner.take(MAX_CHUNK_LINE).read_until(b'\n', &mut line)?;
+    if line.last() != Some(&b'\n') {
+        return Err(if line.len() as u64 == MAX_CHUNK_LINE {
+            malformed("chunk line too long")
+        } else {
+            io::ErrorKind::UnexpectedEof.into()
+        });
+    }
+    line.pop();
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    String::from_utf8(line).map_err(|_| malformed("chunk line is not valid UTF-8"))
+}
+
+fn malformed(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Wraps the socket while a head is read so clients can't hold a worker by
+/// trickling bytes: the whole head has to arrive within `timeout` of its
+/// first byte, at `min_rate` bytes per second or faster.
+pub struct HeadDeadline<'a> {
+    stream: &'a Stream,
+    // read timeout while waiting for the first byte
+    idle_timeout: Duration,
+    timeout: Duration,
+    min_rate: u64,
+    started: Option<Instant>,
+    received: u64,
+}
+
+// slow starts are normal, the rate is only enforced after this long
+const RATE_GRACE: Duration = Duration::from_secs(2);
+
+impl<'a> HeadDeadline<'a> {
+    pub fn new(
+        stream: &'a Stream,
+        idle_timeout: Duration,
+        timeout: Duration,
+        min_rate: u64,
+    ) -> Self {
+        HeadDeadline {
+            stream,
+            idle_timeout,
+            timeout,
+            min_rate,
+            started: None,
+            received: 0,
+        }
+    }
+
+    /// Starts the clock now instead of at the first byte, e.g. for a
+    /// pipelined head that is already partly buffered.
+    pub fn start_now(mut self) -> Self {
+        self.started = Some(Instant::now());
+        self
+    }
+}
+
+impl Read for HeadDeadline<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read_timeout = match self.started {
+            Some(started) => {
+                let elapsed = started.elapsed();
+                if elapsed >= self.timeout {
+                    return Err(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        "request head took too long",
+                    ));
+                }
+                let rate = self.received as f64 / elapsed.as_secs_f64();
+                if self.min_rate > 0 && elapsed >= RATE_GRACE && rate < self.min_rate as f64 {
+                    return Err(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        "request head arrived too slowly",
+                    ));
+                }
+                (self.timeout - elapsed).min(self.idle_timeout)
+            }
+            None => self.idle_timeout,
+        };
+        // a zero duration would mean no timeout at all
+        self.stream
+            .set_read_timeout(Some(read_timeout.max(Duration::from_millis(1))))?;
+
+        let mut stream = self.stream;
+        let n = stream.read(buf)?;
+        if n > 0 {
+            self.started.get_or_insert_with(Instant::now);
+            self.received += n as u64;
+        }
+        Ok(n)
+    }
+}
+
+// index just past the `\r\n\r\n` that ends the head
+fn find_head_end(buffer: &[u8], from: usize) -> Option<usize> {
+    buffer[from..]
+        .windows(4)
+        .position(|window| window == b"\r\n\r\n")
+        .map(|pos| from + pos + 4)
+}
+
+/// Header fields in the order they were received or added. Lookups ignore
+/// case since field names are case-insensitive.
+#[derive(Default, Clone)]
+pub struct Headers {
+    entries: Vec<(String, String)>,
+}
+
+impl Headers {
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.entries
+            .iter()
+            .find(|(field, _)| field.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Adds a field, keeping any others of the same name.
+    pub fn insert(&mut self, name: &str, value: &str) {
+        self.entries.push((name.to_string(), value.to_string()));
+    }
+
+    /// Replaces every field of this name with a single one.
+    pub fn set(&mut self, name: &str, value: &str) {
+        self.remove(name);
+        self.insert(name, value);
+    }
+
+    pub fn remove(&mut self, name: &str) {
+        self.entries
+            .retain(|(field, _)| !field.eq_ignore_ascii_case(name));
+    }
+
+    /// The values of every field of this name, in the order they came.
+    pub fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.entries
+            .iter()
+            .filter(move |(field, _)| field.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.get(name).is_some()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.entries
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.as_str()))
+    }
+}
+
+/// Parses header lines up to the blank line that ends a head. Fails on
+/// lines RFC 9112 section 5 says to reject, since a proxy in front may read
+/// them differently: folded lines, lines without a colon and names with
+/// whitespace before it.
+fn parse_header_lines<'a>(lines: impl Iterator<Item = &'a str>) -> Result<Headers, &'static str> {
+    let mut headers = Headers::default();
+    for line in lines.take_while(|line| !line.is_empty()) {
+        if line.starts_with([' ', '\t']) {
+            return Err("Folded header lines are not supported");
+        }
+        let Some((name, value)) = line.split_once(':') else {
+            return Err("Malformed header line");
+        };
+        if name.is_empty() || !name.bytes().all(is_token_byte) {
+            return Err("Malformed header name");
+        }
+        headers.insert(name, value.trim_matches([' ', '\t']));
+    }
+    Ok(headers)
+}
+
+/// Where the body ends has to be clear, a proxy in front that reads it
+/// differently would otherwise let a request be smuggled into the body.
+fn check_framing(headers: &Headers) -> Result<(), &'static str> {
+    let mut lengths = headers.get_all("content-length");
+    if let Some(length) = lengths.next() {
+        // `5, 5` and repeated fields too, nothing needs to send them
+        if lengths.next().is_some()
+            || length.is_empty()
+            || !length.bytes().all(|b| b.is_ascii_digit())
+            || length.parse::<u64>().is_err()
+        {
+            return Err("Invalid Content-Length");
+        }
+    }
+    if headers.contains("transfer-encoding") {
+        if headers.contains("content-length") {
+            return Err("Both Content-Length and Transfer-Encoding");
+        }
+        // chunked has to be the last coding, and it is the only one the
+        // body reader can undo
+        let mut codings = headers
+            .get_all("transfer-encoding")
+            .flat_map(|value| value.split(','))
+            .map(str::trim);
+        let only_chunked = codings
+            .next()
+            .is_some_and(|coding| coding.eq_ignore_ascii_case("chunked"))
+            && codings.next().is_none();
+        if !only_chunked {
+            return Err("Unsupported Transfer-Encoding");
+        }
+    }
+    Ok(())
+}
+
+/// A parsed request head, plus path parameters and body for requests
+/// answered by a route handler.
+pub struct Request {
+    pub method: String,
+    // the raw request-target as sent by the client
+    pub target: String,
+    // target without the query string
+    pub path: String,
+    pub query: Option<String>,
+    pub version: String,
+    pub headers: Headers,
+    // filled in for requests answered by a route handler
+    pub params: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Default for Request {
+    fn default() -> Self {
+        Request {
+            method: "GET".to_string(),
+            target: "/".to_string(),
+            path: "/".to_string(),
+            query: None,
+            version: "HTTP/1.1".to_string(),
+            headers: Headers::default(),
+            params: Vec::new(),
+            body: Vec::new(),
+        }
+    }
+}
+
+impl Request {
+    /// Parses a request head as returned by `RequestReader::read_head`.
+    /// Fails with the reason when the request line is malformed.
+    pub fn parse(head: &[u8]) -> Result<Request, NebulaError> {
+        let bad_request = |reason: &str| Err(NebulaError::BadRequest(reason.to_string()));
+
+        let head = String::from_utf8_lossy(head);
+        let mut lines = head.split("\r\n");
+
+        let request_line = lines.next().unwrap_or("");
+        if !request_line.is_ascii() || request_line.chars().any(|c| c.is_ascii_control()) {
+            return bad_request("Request line contains invalid characters");
+        }
+        let parts: Vec<&str> = request_line.split(' ').collect();
+        let [method, target, version] = parts[..] else {
+            return bad_request("Malformed request line");
+        };
+        if method.is_empty() || !method.bytes().all(is_token_byte) {
+            return bad_request("Malformed request method");
+        }
+        if !is_http_version(version) {
+            return bad_request("Malformed HTTP version");
+        }
+        // absolute-form targets are served like the path they contain
+        let target = match target.split_once("://") {
+            Some((scheme, rest))
+                if scheme.eq_ignore_ascii_case("http") || scheme.eq_ignore_ascii_case("https") =>
+            {
+                let path_start = rest.find(['/', '?']).unwrap_or(rest.len());
+                match &rest[path_start..] {
+                    "" => "/".to_string(),
+                    path if path.starts_with('?') => format!("/{}", path),
+                    path => path.to_string(),
+                }
+            }
+            _ if target.starts_with('/') || (target == "*" && method == "OPTIONS") => {
+                target.to_string()
+            }
+            _ => return bad_request("Malformed request target"),
+        };
+
+        let (path, query) = split_target(&target);
+        let headers = match parse_header_lines(lines) {
+            Ok(headers) => headers,
+            Err(reason) => return bad_request(reason),
+        };
+        if let Err(reason) = check_framing(&headers) {
+            return bad_request(reason);
+        }
+
+        Ok(Request {
+            method: method.to_string(),
+            target,
+            path,
+            query,
+            version: version.to_string(),
+            headers,
+            params: Vec::new(),
+            body: Vec::new(),
+        })
+    }
+
+    /// The same request aimed at another target, e.g. after a rewrite.
+    pub fn with_target(&self, target: &str) -> Request {
+        let (path, query) = split_target(target);
+        Request {
+            method: self.method.clone(),
+            target: target.to_string(),
+            path,
+            query,
+            version: self.version.clone(),
+            headers: self.headers.clone(),
+            params: self.params.clone(),
+            body: self.body.clone(),
+        }
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name)
+    }
+
+    /// A path parameter captured by the route, e.g. `id` for `/users/:id`.
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params
+            .iter()
+            .find(|(param, _)| param == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Length of the request body as announced by Content-Length. Parsed
+    /// requests have at most one, made of digits only.
+    pub fn content_length(&self) -> Option<u64> {
+        self.header("content-length")
+            .and_then(|value| value.parse().ok())
+    }
+
+    /// Whether the client waits for `100 Continue` before sending its
+    /// body. HTTP/1.0 clients can't ask for that.
+    pub fn expects_continue(&self) -> bool {
+        self.version != "HTTP/1.0"
+            && self
+                .header("expect")
+                .is_some_and(|value| value.trim().eq_ignore_ascii_case("100-continue"))
+    }
+
+    /// Whether the body is sent in chunks, which it is when `chunked` is
+    /// the last transfer coding.
+    pub fn is_chunked(&self) -> bool {
+        self.headers
+            .get_all("transfer-encoding")
+            .flat_map(|value| value.split(','))
+            .last()
+            .is_some_and(|coding| coding.trim().eq_ignore_ascii_case("chunked"))
+    }
+
+    /// Whether the client asks to switch the connection to WebSocket.
+    pub fn is_websocket_upgrade(&self) -> bool {
+        let has_token = |name: &str, token: &str| {
+            self.header(name).is_some_and(|value| {
+                value
+                    .split(',')
+                    .any(|item| item.trim().eq_ignore_ascii_case(token))
+            })
+        };
+        self.version != "HTTP/1.0"
+            && has_token("connection", "upgrade")
+            && has_token("upgrade", "websocket")
+    }
+
+    /// HTTP/1.1 connections are persistent unless the client asks to close
+    /// them, HTTP/1.0 clients have to opt in with `Connection: keep-alive`.
+    pub fn wants_keep_alive(&self) -> bool {
+        let has_token = |token: &str| {
+            self.header("connection").is_some_and(|value| {
+                value
+                    .split(',')
+                    .any(|item| item.trim().eq_ignore_ascii_case(token))
+            })
+        };
+
+        if has_token("close") {
+            false
+        } else if has_token("keep-alive") {
+            true
+        } else {
+            self.version != "HTTP/1.0"
+        }
+    }
+}
+
+// the characters allowed in a method or field name, RFC 9110 section 5.6.2
+pub(crate) fn is_token_byte(byte: u8) -> bool {
+    byte.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&byte)
+}
+
+// `HTTP/` followed by a major and minor digit
+fn is_http_version(version: &str) -> bool {
+    matches!(
+        version.strip_prefix("HTTP/").map(str::as_bytes),
+        Some([major, b'.', minor]) if major.is_ascii_digit() && minor.is_ascii_digit()
+    )
+}
+
+fn split_target(target: &str) -> (String, Option<String>) {
+    match target.split_once('?') {
+        Some((path, query)) => (path.to_string(), Some(query.to_string())),
+        None => (target.to_string(), None),
+    }
+}
+
+/// Status line and headers of an upstream response.
+pub struct ResponseHead {
+    pub status: u16,
+    pub reason: String,
+    pub headers: Headers,
+}
+
+impl ResponseHead {
+    pub fn parse(head: &[u8]) -> Option<ResponseHead> {
+        let head = String::from_utf8_lossy(head);
+        let mut lines = head.split("\r\n");
+
+        let mut status_line = lines.next()?.splitn(3, ' ');
+        status_line.next()?.strip_prefix("HTTP/")?;
+        let status = status_line.next()?.parse().ok()?;
+        let reason = status_line.next().unwrap_or("").to_string();
+
+        Some(ResponseHead {
+            status,
+            reason,
+            headers: parse_header_lines(lines).ok()?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(headers: &str) -> Result<Request, String> {
+        let head = format!("GET / HTTP/1.1\r\nHost: x\r\n{}\r\n", headers);
+        Request::parse(head.as_bytes()).map_err(|e| e.to_string())
+    }
+
+    #[test]
+    fn parses_header_fields() {
+        let request = parse("Content-Length:  5 \r\nX-Empty:\r\n").unwrap();
+        assert_eq!(request.content_length(), Some(5));
+        assert_eq!(request.header("x-empty"), Some(""));
+    }
+
+    #[test]
+    fn rejects_whitespace_before_the_colon() {
+        assert!(parse("Content-Length : 5\r\n").is_err());
+        assert!(parse("Transfer-Encoding\t: chunked\r\n").is_err());
+    }
+
+    #[test]
+    fn rejects_folded_lines() {
+        assert!(parse("X-Long: a\r\n Transfer-Encoding: chunked\r\n").is_err());
+        assert!(parse("X-Long: a\r\n\tb\r\n").is_err());
+    }
+
+    #[test]
+    fn rejects_lines_without_a_colon() {
+        assert!(parse("Transfer-Encoding chunked\r\n").is_err());
+        assert!(parse(": value\r\n").is_err());
+    }
+
+    #[test]
+    fn rejects_ambiguous_framing() {
+        assert!(parse("Content-Length: 5, 5\r\n").is_err());
+        assert!(parse("Content-Length: 5\r\nContent-Length: 5\r\n").is_err());
+        assert!(parse("Content-Length: +5\r\n").is_err());
+        assert!(parse("Transfer-Encoding: gzip\r\n").is_err());
+        assert!(parse("Transfer-Encoding: chunked\r\nContent-Length: 5\r\n").is_err());
+        assert!(parse("Transfer-Encoding: chunked\r\n")
+            .unwrap()
+            .is_chunked());
+    }
+}
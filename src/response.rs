@@ -0,0 +1,434 @@
+//! Responses as status, headers and body, written with a correct
+//! Content-Length, Date and Connection header.
+
+use crate::body::Body;
+use crate::config::ServerTokens;
+use crate::events::EventStream;
+use crate::http_date;
+use crate::listener::Stream;
+use crate::request::{is_token_byte, Headers};
+use serde::Serialize;
+use std::cell::Cell;
+use std::io::{self, IoSlice, Read, Write};
+use std::time::SystemTime;
+
+/// A response to be sent, built with [`Response::builder`] or one of the
+/// shortcuts. Content-Length, Date, Server and Connection are filled in
+/// when it is written, so handlers can't get them wrong.
+pub struct Response {
+    status: u16,
+    headers: Headers,
+    body: Body,
+}
+
+/// Builds a [`Response`] step by step.
+///
+/// ```
+/// use nebula::Response;
+///
+/// let response = Response::builder()
+///     .status(201)
+///     .header("Content-Type", "application/json")
+///     .body(r#"{"id":1}"#)
+///     .build();
+/// ```
+pub struct ResponseBuilder {
+    response: Response,
+}
+
+impl ResponseBuilder {
+    pub fn status(mut self, status: u16) -> Self {
+        self.response.status = status;
+        self
+    }
+
+    /// Adds a header, keeping earlier ones of the same name.
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        self.response.headers.insert(name, value);
+        self
+    }
+
+    pub fn body(mut self, body: impl Into<Vec<u8>>) -> Self {
+        self.response.body = Body::Bytes(body.into());
+        self
+    }
+
+    /// Streams `len` bytes from `reader` instead of holding the body in
+    /// memory. Sending fails if the reader ends early.
+    pub fn stream(mut self, reader: impl Read + Send + 'static, len: u64) -> Self {
+        self.response.body = Body::Reader {
+            reader: Box::new(reader),
+            len,
+        };
+        self
+    }
+
+    pub fn build(self) -> Response {
+        self.response
+    }
+}
+
+impl Response {
+    /// Starts a 200 response without headers or body.
+    pub fn builder() -> ResponseBuilder {
+        ResponseBuilder {
+            response: Response::new(200),
+        }
+    }
+
+    /// An empty response with the given status.
+    pub fn new(status: u16) -> Response {
+        Response {
+            status,
+            headers: Headers::default(),
+            body: Body::Bytes(Vec::new()),
+        }
+    }
+
+    /// A 200 with a plain text body.
+    pub fn text(text: impl Into<String>) -> Response {
+        Response::builder()
+            .header("Content-Type", "text/plain; charset=utf-8")
+            .body(text.into())
+            .build()
+    }
+
+    /// A 200 with `value` serialized as JSON.
+    pub fn json<T: Serialize + ?Sized>(value: &T) -> Response {
+        match serde_json::to_vec(value) {
+            Ok(body) => Response::builder()
+                .header("Content-Type", "application/json")
+                .body(body)
+                .build(),
+            Err(e) => Response::error(500, &format!("Failed to serialize response: {}", e)),
+        }
+    }
+
+    /// A 200 sending `stream` as Server-Sent Events until every sender of
+    /// it is dropped or the client goes away. The connection is closed
+    /// afterwards.
+    ///
+    /// ```
+    /// use nebula::{events, Event, Response};
+    ///
+    /// let (sender, stream) = events::channel();
+    /// std::thread::spawn(move || {
+    ///     let mut ticks = 0;
+    ///     while sender.send(Event::new(ticks.to_string()).event("tick")) {
+    ///         ticks += 1;
+    ///         std::thread::sleep(std::time::Duration::from_secs(1));
+    ///     }
+    /// });
+    /// let response = Response::events(stream);
+    /// ```
+    pub fn events(stream: EventStream) -> Response {
+        let mut response = Response::from_body(200, Body::Stream(Box::new(stream)));
+        let headers = response.headers_mut();
+        headers.insert("Content-Type", "text/event-stream");
+        headers.insert("Cache-Control", "no-cache");
+        // nginx and similar proxies would hold events back otherwise
+        headers.insert("X-Accel-Buffering", "no");
+        response
+    }
+
+    /// A plain text message with an error status.
+    pub fn error(status: u16, message: &str) -> Response {
+        Response::builder()
+            .status(status)
+            .header("Content-Type", "text/plain")
+            .body(message)
+            .build()
+    }
+
+    /// An empty redirect to `location`.
+    pub fn redirect(status: u16, location: &str) -> Response {
+        Response::builder()
+            .status(status)
+            .header("Location", location)
+            .build()
+    }
+
+    pub fn status(&self) -> u16 {
+        self.status
+    }
+
+    pub fn set_status(&mut self, status: u16) {
+        self.status = status;
+    }
+
+    pub fn headers(&self) -> &Headers {
+        &self.headers
+    }
+
+    pub fn headers_mut(&mut self) -> &mut Headers {
+        &mut self.headers
+    }
+
+    pub fn set_body(&mut self, body: impl Into<Vec<u8>>) {
+        self.body = Body::Bytes(body.into());
+    }
+
+    /// Whether sending this response has to close the connection, since
+    /// the body has no length.
+    pub(crate) fn closes_connection(&self) -> bool {
+        self.body.is_stream()
+    }
+
+    pub(crate) fn from_body(status: u16, body: Body) -> Response {
+        Response {
+            status,
+            headers: Headers::default(),
+            body,
+        }
+    }
+
+    /// Writes the response, leaving out the body for HEAD requests. A
+    /// `keep_alive` timeout keeps the connection open, `None` closes it.
+    /// Returns the number of body bytes sent.
+    pub(crate) fn write_to<W: Write>(
+        self,
+        out: &mut W,
+        keep_alive: Option<u64>,
+        is_head: bool,
+        server_tokens: ServerTokens,
+    ) -> io::Result<u64> {
+        self.write(out, keep_alive, is_head, server_tokens, Body::write_to)
+    }
+
+    /// Like `write_to`, for a client connection. File bodies are handed to
+    /// the kernel where it can send them without a copy.
+    pub(crate) fn send_to(
+        self,
+        stream: &mut Stream,
+        keep_alive: Option<u64>,
+        is_head: bool,
+        server_tokens: ServerTokens,
+    ) -> io::Result<u64> {
+        self.write(stream, keep_alive, is_head, server_tokens, Body::send_to)
+    }
+
+    // bodies in memory go out in the same write as the head, the others are
+    // left to `write_body`
+    fn write<W: Write>(
+        mut self,
+        out: &mut W,
+        keep_alive: Option<u64>,
+        is_head: bool,
+        server_tokens: ServerTokens,
+        write_body: fn(Body, &mut W) -> io::Result<u64>,
+    ) -> io::Result<u64> {
+        // a line break in a field would let whatever put it there add
+        // headers of its own, or a body
+        let invalid = self
+            .headers
+            .iter()
+            .find(|(name, value)| !is_valid_field(name, value));
+        if let Some((name, _)) = invalid {
+            tracing::error!(
+                "Refusing to send a response with an invalid {:?} header",
+                name
+            );
+            self = Response::error(500, "Internal server error");
+        }
+        let headers = &mut self.headers;
+        for name in [
+            "content-length",
+            "transfer-encoding",
+            "connection",
+            "keep-alive",
+        ] {
+            headers.remove(name);
+        }
+        // 1xx, 204 and 304 responses never have a body, and a 304 leaves the
+        // length to the cached representation
+        let bodyless = self.status < 200 || self.status == 204 || self.status == 304;
+        let keep_alive = keep_alive.filter(|_| !self.body.is_stream());
+        if !bodyless && !self.body.is_stream() {
+            headers.insert("Content-Length", &self.body.len().to_string());
+        }
+        if !headers.contains("date") {
+            headers.insert("Date", &http_date::format(SystemTime::now()));
+        }
+        match server_tokens.header() {
+            Some(server) if !headers.contains("server") => headers.insert("Server", server),
+            _ => {}
+        }
+        match keep_alive {
+            // the connection carries another protocol from here on
+            _ if self.status == 101 => headers.insert("Connection", "Upgrade"),
+            Some(timeout) => {
+                headers.insert("Connection", "keep-alive");
+                headers.insert("Keep-Alive", &format!("timeout={}", timeout));
+            }
+            None => headers.insert("Connection", "close"),
+        }
+
+        let mut head = HEAD_BUFFER.take();
+        head.clear();
+        write!(
+            head,
+            "HTTP/1.1 {} {}\r\n",
+            self.status,
+            reason_phrase(self.status)
+        )?;
+        for (name, value) in headers.iter() {
+            write!(head, "{}: {}\r\n", name, value)?;
+        }
+        head.extend_from_slice(b"\r\n");
+
+        let body = (!is_head && !bodyless).then_some(self.body);
+        let written = match body.as_ref().and_then(Body::as_bytes) {
+            Some(bytes) => {
+                let mut slices = [IoSlice::new(&head), IoSlice::new(bytes)];
+                write_all_vectored(out, &mut slices).map(|_| bytes.len() as u64)
+            }
+            None => out.write_all(&head).map(|_| 0),
+        };
+        if head.capacity() <= MAX_POOLED_HEAD {
+            HEAD_BUFFER.set(head);
+        }
+        let written = written?;
+        match body {
+            Some(body) if body.as_bytes().is_none() => write_body(body, out),
+            _ => Ok(written),
+        }
+    }
+}
+
+// heads larger than this aren't kept around for the next response
+const MAX_POOLED_HEAD: usize = 64 * 1024;
+
+thread_local! {
+    // reused by every response a worker writes
+    static HEAD_BUFFER: Cell<Vec<u8>> = const { Cell::new(Vec::new()) };
+}
+
+// `Write::write_all_vectored` isn't stable yet
+fn write_all_vectored<W: Write>(out: &mut W, mut slices: &mut [IoSlice]) -> io::Result<()> {
+    while !slices.is_empty() {
+        match out.write_vectored(slices) {
+            Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+            Ok(n) => IoSlice::advance_slices(&mut slices, n),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// The reason phrase sent after a status code, in the upper case used
+/// throughout nebula.
+pub fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        100 => "CONTINUE",
+        101 => "SWITCHING PROTOCOLS",
+        200 => "OK",
+        201 => "CREATED",
+        202 => "ACCEPTED",
+        204 => "NO CONTENT",
+        206 => "PARTIAL CONTENT",
+        207 => "MULTI-STATUS",
+        301 => "MOVED PERMANENTLY",
+        302 => "FOUND",
+        303 => "SEE OTHER",
+        304 => "NOT MODIFIED",
+        307 => "TEMPORARY REDIRECT",
+        308 => "PERMANENT REDIRECT",
+        400 => "BAD REQUEST",
+        401 => "UNAUTHORIZED",
+        403 => "FORBIDDEN",
+        404 => "NOT FOUND",
+        405 => "METHOD NOT ALLOWED",
+        408 => "REQUEST TIMEOUT",
+        409 => "CONFLICT",
+        410 => "GONE",
+        411 => "LENGTH REQUIRED",
+        412 => "PRECONDITION FAILED",
+        413 => "CONTENT TOO LARGE",
+        414 => "URI TOO LONG",
+        415 => "UNSUPPORTED MEDIA TYPE",
+        416 => "RANGE NOT SATISFIABLE",
+        417 => "EXPECTATION FAILED",
+        422 => "UNPROCESSABLE CONTENT",
+        426 => "UPGRADE REQUIRED",
+        429 => "TOO MANY REQUESTS",
+        431 => "REQUEST HEADER FIELDS TOO LARGE",
+        500 => "INTERNAL SERVER ERROR",
+        501 => "NOT IMPLEMENTED",
+        502 => "BAD GATEWAY",
+        503 => "SERVICE UNAVAILABLE",
+        504 => "GATEWAY TIMEOUT",
+        505 => "HTTP VERSION NOT SUPPORTED",
+        _ => "UNKNOWN",
+    }
+}
+
+/// Whether a header can be written as is: a name made of token
+/// characters and a value without CR, LF or NUL.
+pub(crate) fn is_valid_field(name: &str, value: &str) -> bool {
+    !name.is_empty()
+        && name.bytes().all(is_token_byte)
+        && !value.bytes().any(|b| matches!(b, b'\r' | b'\n' | b'\0'))
+}
+
+/// Escapes text for HTML and XML documents, attribute values included.
+pub(crate) fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Answers a request we refuse to process and closes the connection.
+pub fn write_error<W: Write>(
+    out: &mut W,
+    status: u16,
+    message: &str,
+    server_tokens: ServerTokens,
+) -> io::Result<()> {
+    Response::error(status, message)
+        .write_to(out, None, false, server_tokens)
+        .map(|_| ())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn wire(response: Response) -> String {
+        let mut out = Vec::new();
+        response
+            .write_to(&mut out, None, false, ServerTokens::Off)
+            .unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn refuses_line_breaks_in_header_values() {
+        let response = Response::builder()
+            .status(302)
+            .header("Location", "/new\r\nSet-Cookie: pwned=1")
+            .build();
+        let wire = wire(response);
+        assert!(wire.starts_with("HTTP/1.1 500 "));
+        assert!(!wire.contains("Set-Cookie"));
+        assert!(!wire.contains("pwned"));
+    }
+
+    #[test]
+    fn refuses_invalid_header_names() {
+        for name in ["X-Bad\r\nSet-Cookie", "X Bad", "X-Bad:", ""] {
+            let wire = wire(Response::builder().header(name, "1").build());
+            assert!(wire.starts_with("HTTP/1.1 500 "), "{:?}", name);
+        }
+        let wire = wire(Response::builder().header("X-Nul", "a\0b").build());
+        assert!(wire.starts_with("HTTP/1.1 500 "));
+    }
+
+    #[test]
+    fn writes_valid_headers_as_given() {
+        let wire = wire(Response::builder().header("X-Fine", "a b, c").build());
+        assert!(wire.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(wire.contains("\r\nX-Fine: a b, c\r\n"));
+    }
+}
@@ -0,0 +1,220 @@
+//! `[[redirect]]` and `[[rewrite]]` rules that map request paths to other
+//! targets before proxy rules and files are resolved.
+
+use crate::uri;
+use regex::Regex;
+use serde::{Deserialize, Deserializer};
+
+#[derive(Deserialize, Clone)]
+pub struct RewriteRule {
+    // matched against the decoded path, without the query string
+    #[serde(deserialize_with = "deserialize_regex")]
+    pub pattern: Regex,
+    // `$1` or `${name}` insert capture groups, may carry a query string
+    pub replacement: String,
+    #[serde(default)]
+    pub flags: Vec<RewriteFlag>,
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum RewriteFlag {
+    /// Stop at this rule instead of passing the result to the next one.
+    Last,
+    /// Send the client a 302 to the result instead of serving it.
+    Redirect,
+}
+
+pub enum Rewrite {
+    /// Serve this target in place of the requested one.
+    Internal(String),
+    Redirect(String),
+}
+
+#[derive(Deserialize, Clone)]
+pub struct RedirectRule {
+    // exact path to redirect
+    #[serde(default)]
+    pub from: Option<String>,
+    // or a regex matched against the decoded path, `to` may use `$1`
+    #[serde(default, deserialize_with = "deserialize_optional_regex")]
+    pub pattern: Option<Regex>,
+    pub to: String,
+    // 301, 302, 307 or 308
+    #[serde(
+        default = "default_redirect_status",
+        deserialize_with = "deserialize_redirect_status"
+    )]
+    pub status: u16,
+}
+
+fn default_redirect_status() -> u16 {
+    301
+}
+
+fn deserialize_redirect_status<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<u16, D::Error> {
+    let status = u16::deserialize(deserializer)?;
+    match status {
+        301 | 302 | 307 | 308 => Ok(status),
+        _ => Err(serde::de::Error::custom(format!(
+            "unsupported redirect status {}, expected 301, 302, 307 or 308",
+            status
+        ))),
+    }
+}
+
+fn deserialize_optional_regex<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Regex>, D::Error> {
+    deserialize_regex(deserializer).map(Some)
+}
+
+fn deserialize_regex<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Regex, D::Error> {
+    let pattern = String::deserialize(deserializer)?;
+    Regex::new(&pattern).map_err(serde::de::Error::custom)
+}
+
+/// Returns the status and location of the first redirect rule matching
+/// `path`. The client's query string is carried over to the location.
+pub fn find_redirect(
+    rules: &[RedirectRule],
+    path: &str,
+    query: Option<&str>,
+) -> Option<(u16, String)> {
+    rules.iter().find_map(|rule| {
+        let location = match (&rule.from, &rule.pattern) {
+            (Some(from), _) if from == path => rule.to.clone(),
+            (_, Some(pattern)) if pattern.is_match(path) => {
+                replace_encoded(pattern, path, &rule.to)
+            }
+            _ => return None,
+        };
+
+        let (path, location_query) = match location.split_once('?') {
+            Some((path, location_query)) => (path, Some(location_query)),
+            None => (location.as_str(), None),
+        };
+        let queries: Vec<&str> = location_query.into_iter().chain(query).collect();
+        Some((rule.status, with_query(path, &queries)))
+    })
+}
+
+/// Runs the rules in order, each one seeing the path the previous one
+/// produced. Query strings added by replacements come before the one the
+/// client sent. Returns `None` when no rule matched.
+pub fn apply(rules: &[RewriteRule], path: &str, query: Option<&str>) -> Option<Rewrite> {
+    let mut path = path.to_string();
+    let mut queries: Vec<String> = Vec::new();
+    let mut matched = false;
+    let mut redirect = false;
+
+    for rule in rules {
+        if !rule.pattern.is_match(&path) {
+            continue;
+        }
+        matched = true;
+
+        let redirects = rule.flags.contains(&RewriteFlag::Redirect);
+        // a path that is served stays decoded for the next rule, one the
+        // client is sent to goes out encoded
+        let replaced = if redirects {
+            replace_encoded(&rule.pattern, &path, &rule.replacement)
+        } else {
+            rule.pattern
+                .replace(&path, rule.replacement.as_str())
+                .into_owned()
+        };
+        path = match replaced.split_once('?') {
+            Some((replaced_path, replaced_query)) => {
+                queries.push(replaced_query.to_string());
+                replaced_path.to_string()
+            }
+            None => replaced,
+        };
+
+        if redirects {
+            redirect = true;
+            break;
+        }
+        if rule.flags.contains(&RewriteFlag::Last) {
+            break;
+        }
+    }
+
+    if !matched {
+        return None;
+    }
+
+    queries.extend(query.map(str::to_string));
+    let queries: Vec<&str> = queries.iter().map(String::as_str).collect();
+    let target = with_query(&path, &queries);
+
+    Some(if redirect {
+        Rewrite::Redirect(target)
+    } else {
+        Rewrite::Internal(target)
+    })
+}
+
+// appends the non-empty query strings to `path`, joined with `&`
+// `replacement` in place of the first match of `pattern` in the decoded
+// `path`, like `Regex::replace`, but what is taken from the path goes in
+// percent-encoded. A redirect to it then can't split the response with an
+// escaped CR LF or carry a decoded `?` into the query.
+fn replace_encoded(pattern: &Regex, path: &str, replacement: &str) -> String {
+    let Some(captures) = pattern.captures(path) else {
+        return path.to_string();
+    };
+    let matched = captures.get(0).expect("group 0 is the whole match");
+    let mut replaced = uri::percent_encode_path(&path[..matched.start()]);
+    let mut rest = replacement;
+    // `$1`, `${1}`, `$name` and `${name}` like the regex crate expands
+    // them, `$$` is a dollar sign
+    while let Some(dollar) = rest.find('$') {
+        replaced.push_str(&rest[..dollar]);
+        rest = &rest[dollar + 1..];
+        if let Some(after) = rest.strip_prefix('$') {
+            replaced.push('$');
+            rest = after;
+            continue;
+        }
+        let (name, after) = match rest.strip_prefix('{') {
+            Some(braced) => match braced.split_once('}') {
+                Some(split) => split,
+                None => ("", rest),
+            },
+            None => {
+                let end = rest
+                    .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                    .unwrap_or(rest.len());
+                rest.split_at(end)
+            }
+        };
+        if name.is_empty() {
+            replaced.push('$');
+            continue;
+        }
+        let group = match name.parse::<usize>() {
+            Ok(index) => captures.get(index),
+            Err(_) => captures.name(name),
+        };
+        if let Some(group) = group {
+            replaced.push_str(&uri::percent_encode_path(group.as_str()));
+        }
+        rest = after;
+    }
+    replaced.push_str(rest);
+    replaced.push_str(&uri::percent_encode_path(&path[matched.end()..]));
+    replaced
+}
+
+fn with_query(path: &str, queries: &[&str]) -> String {
+    let queries: Vec<&str> = queries.iter().copied().filter(|q| !q.is_empty()).collect();
+    if queries.is_empty() {
+        path.to_string()
+    } else {
+        format!("{}?{}", path, queries.join("&"))
+    }
+}
@@ -0,0 +1,1366 @@
+//! Decides how a request is answered: redirects, rewrites, access rules,
+//! route handlers, proxying and finally static files.
+
+use crate::access;
+use crate::admin::{self, Endpoint};
+use crate::body::Body;
+use crate::compression;
+use crate::config::{ContentConfig, ListenConfig, NebulaConfig, Site, TrailingSlash};
+use crate::digest::{self, Hash};
+use crate::error::NebulaError;
+use crate::events::{self, EventSender};
+use crate::fs::{
+    encoded_etag, error_page, etag_matches, find_index, if_range_matches, is_hidden,
+    modified_after, parse_range, sanitize_path, symlinks_permitted, write_preconditions_hold,
+    ByteRange,
+};
+use crate::http_date;
+use crate::listener::Stream;
+use crate::logging::{AccessEntry, AccessLog};
+use crate::middleware::{self, Context, Middleware};
+use crate::mime;
+use crate::negotiate;
+use crate::proxy::{self, ProxyOutcome};
+use crate::request::{Headers, Request, RequestReader};
+use crate::response::Response;
+use crate::rewrite::{self, Rewrite};
+use crate::state::ServerState;
+use crate::telemetry::{self, SpanKind};
+use crate::throttle::Throttled;
+use crate::upload;
+use crate::uri;
+use crate::webdav::{self, Depth};
+use crate::websocket::{self, WebSocket};
+use crate::wire::WireDump;
+use std::cell::OnceCell;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+
+type Handler = dyn Fn(&Request) -> Response + Send + Sync;
+type SocketHandler = dyn Fn(&Request, &mut WebSocket) + Send + Sync;
+
+/// Handlers for method and path patterns, tried before proxy rules and
+/// static files. `:name` segments capture one path segment, a final `*name`
+/// captures the rest of the path.
+///
+/// ```
+/// use nebula::{Response, Router};
+///
+/// let router = Router::new().get("/api/users/:id", |request| {
+///     Response::text(format!("user {}", request.param("id").unwrap_or("")))
+/// });
+/// ```
+#[derive(Default, Clone)]
+pub struct Router {
+    routes: Vec<Route>,
+}
+
+#[derive(Clone)]
+struct Route {
+    method: String,
+    segments: Vec<Segment>,
+    handler: RouteHandler,
+}
+
+#[derive(Clone)]
+enum RouteHandler {
+    Response(Arc<Handler>),
+    // takes over the connection after the WebSocket handshake
+    WebSocket(Arc<SocketHandler>),
+}
+
+#[derive(Clone)]
+enum Segment {
+    Literal(String),
+    Param(String),
+    Rest(String),
+}
+
+enum RouteMatch<'a> {
+    Found(&'a Route, Vec<(String, String)>),
+    // the path matched, but only for other methods
+    MethodNotAllowed(Vec<&'a str>),
+    None,
+}
+
+impl Router {
+    pub fn new() -> Router {
+        Router::default()
+    }
+
+    /// Registers `handler` for `method` requests matching `pattern`. Routes
+    /// are tried in the order they were added. GET routes answer HEAD too.
+    pub fn route<F>(self, method: &str, pattern: &str, handler: F) -> Router
+    where
+        F: Fn(&Request) -> Response + Send + Sync + 'static,
+    {
+        self.add(method, pattern, RouteHandler::Response(Arc::new(handler)))
+    }
+
+    /// Registers a WebSocket endpoint. Requests matching `pattern` that
+    /// complete the handshake are handed to `handler` together with the
+    /// upgraded connection, which is closed when the handler returns.
+    /// Each connection takes up a worker until then.
+    ///
+    /// ```
+    /// use nebula::Router;
+    ///
+    /// let router = Router::new().websocket("/echo", |_, socket| {
+    ///     while let Ok(Some(message)) = socket.recv() {
+    ///         if socket.send(&message).is_err() {
+    ///             break;
+    ///         }
+    ///     }
+    /// });
+    /// ```
+    pub fn websocket<F>(self, pattern: &str, handler: F) -> Router
+    where
+        F: Fn(&Request, &mut WebSocket) + Send + Sync + 'static,
+    {
+        self.add("GET", pattern, RouteHandler::WebSocket(Arc::new(handler)))
+    }
+
+    fn add(mut self, method: &str, pattern: &str, handler: RouteHandler) -> Router {
+        let segments = pattern
+            .split('/')
+            .filter(|segment| !segment.is_empty())
+            .map(|segment| {
+                if let Some(name) = segment.strip_prefix(':') {
+                    Segment::Param(name.to_string())
+                } else if let Some(name) = segment.strip_prefix('*') {
+                    Segment::Rest(name.to_string())
+                } else {
+                    Segment::Literal(segment.to_string())
+                }
+            })
+            .collect();
+        self.routes.push(Route {
+            method: method.to_ascii_uppercase(),
+            segments,
+            handler,
+        });
+        self
+    }
+
+    pub fn get<F>(self, pattern: &str, handler: F) -> Router
+    where
+        F: Fn(&Request) -> Response + Send + Sync + 'static,
+    {
+        self.route("GET", pattern, handler)
+    }
+
+    pub fn post<F>(self, pattern: &str, handler: F) -> Router
+    where
+        F: Fn(&Request) -> Response + Send + Sync + 'static,
+    {
+        self.route("POST", pattern, handler)
+    }
+
+    pub fn put<F>(self, pattern: &str, handler: F) -> Router
+    where
+        F: Fn(&Request) -> Response + Send + Sync + 'static,
+    {
+        self.route("PUT", pattern, handler)
+    }
+
+    pub fn delete<F>(self, pattern: &str, handler: F) -> Router
+    where
+        F: Fn(&Request) -> Response + Send + Sync + 'static,
+    {
+        self.route("DELETE", pattern, handler)
+    }
+
+    /// Registers a Server-Sent Events stream for GET requests matching
+    /// `pattern`. `handler` gets the sender of a new stream for every
+    /// request and returns right away, keeping the sender or moving it to a
+    /// thread. Each open stream takes up a worker until it ends.
+    ///
+    /// ```
+    /// use nebula::{Event, Router};
+    ///
+    /// let router = Router::new().events("/clock", |_, sender| {
+    ///     std::thread::spawn(move || {
+    ///         while sender.send(Event::new("tick")) {
+    ///             std::thread::sleep(std::time::Duration::from_secs(1));
+    ///         }
+    ///     });
+    /// });
+    /// ```
+    pub fn events<F>(self, pattern: &str, handler: F) -> Router
+    where
+        F: Fn(&Request, EventSender) + Send + Sync + 'static,
+    {
+        self.route("GET", pattern, move |request| {
+            let (sender, stream) = events::channel();
+            handler(request, sender);
+            Response::events(stream)
+        })
+    }
+
+    fn find(&self, method: &str, path: &str) -> RouteMatch<'_> {
+        let mut allowed = Vec::new();
+        for route in &self.routes {
+            let Some(params) = route.matches(path) else {
+                continue;
+            };
+            if route.method == method || (method == "HEAD" && route.method == "GET") {
+                return RouteMatch::Found(route, params);
+            }
+            allowed.push(route.method.as_str());
+        }
+
+        if allowed.is_empty() {
+            RouteMatch::None
+        } else {
+            RouteMatch::MethodNotAllowed(allowed)
+        }
+    }
+}
+
+impl Route {
+    // the captured parameters when `path` matches
+    fn matches(&self, path: &str) -> Option<Vec<(String, String)>> {
+        let parts: Vec<&str> = path.split('/').filter(|part| !part.is_empty()).collect();
+        let mut params = Vec::new();
+
+        for (i, segment) in self.segments.iter().enumerate() {
+            match segment {
+                Segment::Rest(name) => {
+                    params.push((name.clone(), parts.get(i..)?.join("/")));
+                    return Some(params);
+                }
+                Segment::Param(name) => params.push((name.clone(), parts.get(i)?.to_string())),
+                Segment::Literal(literal) => {
+                    if parts.get(i) != Some(&literal.as_str()) {
+                        return None;
+                    }
+                }
+            }
+        }
+        (parts.len() == self.segments.len()).then_some(params)
+    }
+}
+
+/// The connection a request came in on.
+pub struct Connection<'a> {
+    pub listen: &'a ListenConfig,
+    // taken from the PROXY protocol header on listeners that expect one
+    pub peer_addr: Option<SocketAddr>,
+}
+
+// numbers the requests in the log, counting from server start
+static NEXT_REQUEST_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Answers one request. Returns whether the connection may be kept open.
+pub fn handle_request(
+    stream: &mut Stream,
+    reader: &mut RequestReader,
+    request: &Request,
+    config: &NebulaConfig,
+    state: &ServerState,
+    connection: &Connection,
+    keep_alive: bool,
+) -> Result<bool, std::io::Error> {
+    let out = Responder::new(request, config, state, connection);
+    let _entered = out.span.enter();
+    let listen = connection.listen;
+
+    // `OPTIONS *` asks about the server as a whole
+    if request.target == "*" {
+        return out.send(stream, server_options(config, state), keep_alive);
+    }
+
+    // decode escapes like `%20` and resolve dot segments before the path
+    // gets anywhere near the filesystem
+    let Some(path) = uri::normalize_path(&request.path) else {
+        let error = NebulaError::BadRequest("Invalid percent-encoding in request path".to_string());
+        return out.send(stream, error.to_response(), false);
+    };
+
+    // the admin listener leaves the site to the public ones
+    if listen.admin && !path.starts_with("/_nebula/") {
+        return out.send(stream, Response::error(404, "Page not found"), keep_alive);
+    }
+
+    // rewritten requests are served as if the client had asked for the new
+    // target, the access log keeps the original one
+    let requested_path = path;
+    let rewritten;
+    let (request, path) =
+        match redirect_or_rewrite(config, &requested_path, request.query.as_deref()) {
+            Err(redirect) => return out.send(stream, redirect, keep_alive),
+            Ok(Some(target)) => {
+                rewritten = request.with_target(&target);
+                let path = uri::remove_dot_segments(&rewritten.path);
+                (&rewritten, path)
+            }
+            Ok(None) => (request, requested_path.clone()),
+        };
+
+    let site = config.site_for(request.header("host"), listen.vhosts.as_deref());
+    let ctx = Context {
+        config,
+        state,
+        remote_addr: out.remote_addr,
+        requested_path: &requested_path,
+        path: &path,
+        user: &out.user,
+        public_dir: site.public_dir,
+        overrides: OnceCell::new(),
+    };
+    let layers = middleware::chain(state);
+    for (i, layer) in layers.iter().enumerate() {
+        if let Some(mut response) = layer.on_request(&ctx, request) {
+            for layer in layers[..i].iter().rev() {
+                layer.on_response(&ctx, request, &mut response);
+            }
+            // refused requests may have left a body nobody is going to read
+            let keep_alive = keep_alive && response.status() < 400;
+            return out.send(stream, response, keep_alive);
+        }
+    }
+
+    // mounts serve other directories below their URL, the rest of the site
+    // comes from its public_dir
+    let (root, relative) = config.mount_for(&path).unwrap_or((site.public_dir, &path));
+    let ex = Exchange {
+        out: &out,
+        request,
+        path: &path,
+        requested_path: &requested_path,
+        listen,
+        peer_addr: connection.peer_addr,
+        root,
+        relative,
+        site,
+        ctx,
+        layers,
+        keep_alive,
+    };
+
+    let answer = if let Some(answer) = route(&ex, stream, reader)? {
+        answer
+    } else if let Some(answer) = admin_endpoint(&ex, stream, reader)? {
+        answer
+    } else if let Some(answer) = upload_form(&ex, stream, reader)? {
+        answer
+    } else if let Some(answer) = forward(&ex, stream, reader)? {
+        answer
+    } else if let Some(answer) = write_or_dav(&ex, stream, reader) {
+        answer
+    } else {
+        static_file(&ex)
+    };
+    match answer {
+        Answer::Send(response, keep_alive) => out.send(stream, response, keep_alive),
+        Answer::Sent(keep_alive) => Ok(keep_alive),
+    }
+}
+
+// writes the answer to a request and logs it
+struct Responder<'a> {
+    // as the client sent it, before any rewrite
+    request: &'a Request,
+    config: &'a NebulaConfig,
+    state: &'a ServerState,
+    started: Instant,
+    span: tracing::Span,
+    // the OpenTelemetry span, open until the request is answered
+    trace: telemetry::Span,
+    access_log: Arc<AccessLog>,
+    // the client's address, which is further out when the peer is a trusted
+    // proxy. Its port is unknown then
+    remote_addr: Option<SocketAddr>,
+    // set once basic auth let the request through
+    user: OnceCell<String>,
+    // the bandwidth cap from [limits], by the path the client asked for
+    bytes_per_second: Option<u64>,
+}
+
+impl<'a> Responder<'a> {
+    fn new(
+        request: &'a Request,
+        config: &'a NebulaConfig,
+        state: &'a ServerState,
+        connection: &Connection,
+    ) -> Responder<'a> {
+        let started = Instant::now();
+        let span = tracing::info_span!(
+            "request",
+            id = NEXT_REQUEST_ID.fetch_add(1, Ordering::Relaxed),
+            method = %request.method,
+            path = %request.path,
+            status = tracing::field::Empty,
+            duration_ms = tracing::field::Empty,
+        );
+        let remote_addr = connection.peer_addr.map(|peer| {
+            let trusted = &config.server.trusted_proxies;
+            match access::client_ip(peer.ip(), trusted, &request.headers) {
+                ip if ip == peer.ip() => peer,
+                ip => SocketAddr::new(ip, 0),
+            }
+        });
+        Responder {
+            request,
+            config,
+            state,
+            started,
+            span,
+            trace: telemetry::Span::server(state.telemetry(), request, remote_addr),
+            access_log: state.access_log(),
+            remote_addr,
+            user: OnceCell::new(),
+            bytes_per_second: uri::normalize_path(&request.path)
+                .and_then(|path| config.limits.bytes_per_second(&path)),
+        }
+    }
+
+    fn log(&self, status: u16, bytes: usize) {
+        let request = self.request;
+        let duration = self.started.elapsed();
+        self.trace.set_status(status);
+        self.span.record("status", status);
+        self.span.record("duration_ms", duration.as_millis() as u64);
+        tracing::debug!(bytes, "Request served");
+        self.state.metrics().record(status, bytes as u64, duration);
+        self.access_log.log(&AccessEntry {
+            remote_addr: self.remote_addr,
+            user: self.user.get().map(String::as_str),
+            method: &request.method,
+            target: &request.target,
+            version: &request.version,
+            status,
+            bytes,
+            duration,
+            user_agent: request.header("user-agent"),
+            referer: request.header("referer"),
+        });
+    }
+
+    // writes a response and logs it, `keep_alive` decides whether the
+    // connection stays open afterwards
+    fn send(&self, stream: &mut Stream, response: Response, keep_alive: bool) -> io::Result<bool> {
+        let config = self.config;
+        let status = response.status();
+        // HEAD is answered exactly like GET, minus the body
+        let is_head = self.request.method == "HEAD";
+        let keep_alive = keep_alive && !response.closes_connection();
+        let timeout = keep_alive.then_some(config.server.keep_alive_timeout);
+        let server_tokens = config.server.server_tokens;
+        let bytes = if config.logging.dump_wire {
+            // without sendfile, the dump has to see the bytes
+            let body_limit = config.logging.dump_body_bytes;
+            let mut dump = WireDump::new(&mut *stream, body_limit);
+            let bytes = match self.bytes_per_second {
+                Some(rate) => {
+                    let mut throttled = Throttled::new(&mut dump, rate);
+                    response.write_to(&mut throttled, timeout, is_head, server_tokens)
+                }
+                None => response.write_to(&mut dump, timeout, is_head, server_tokens),
+            };
+            dump.finish();
+            bytes?
+        } else {
+            match self.bytes_per_second {
+                Some(rate) => {
+                    let mut throttled = Throttled::new(&mut *stream, rate);
+                    response.write_to(&mut throttled, timeout, is_head, server_tokens)?
+                }
+                None => response.send_to(stream, timeout, is_head, server_tokens)?,
+            }
+        };
+        self.log(status, bytes as usize);
+        Ok(keep_alive)
+    }
+}
+
+// what the stages after the middleware know about a request
+struct Exchange<'a> {
+    out: &'a Responder<'a>,
+    // after rewrites
+    request: &'a Request,
+    path: &'a str,
+    requested_path: &'a str,
+    listen: &'a ListenConfig,
+    peer_addr: Option<SocketAddr>,
+    // the directory the path is served from and the path below it
+    root: &'a str,
+    relative: &'a str,
+    site: Site<'a>,
+    ctx: Context<'a>,
+    layers: Vec<&'a dyn Middleware>,
+    keep_alive: bool,
+}
+
+impl Exchange<'_> {
+    fn config(&self) -> &NebulaConfig {
+        self.out.config
+    }
+
+    fn state(&self) -> &ServerState {
+        self.out.state
+    }
+
+    fn method(&self) -> &str {
+        &self.request.method
+    }
+
+    // the middleware sees the response on its way out, the innermost layer
+    // first
+    fn finish(&self, mut response: Response) -> Response {
+        for layer in self.layers.iter().rev() {
+            layer.on_response(&self.ctx, self.request, &mut response);
+        }
+        response
+    }
+
+    fn respond(&self, response: Response) -> Answer {
+        Answer::Send(self.finish(response), self.keep_alive)
+    }
+
+    // like `respond`, but an error closes the connection, the body the
+    // client sent along may not have been read
+    fn respond_or_close(&self, response: Response) -> Answer {
+        let keep_alive = self.keep_alive && response.status() < 400;
+        Answer::Send(self.finish(response), keep_alive)
+    }
+}
+
+// how one of the stages answered a request
+enum Answer {
+    // a response still to be written, and whether the connection may stay
+    // open after it
+    Send(Response, bool),
+    // the stage wrote to the client itself, and whether the connection may
+    // stay open
+    Sent(bool),
+}
+
+// the answer to a request whose body can't be read, or the I/O error to
+// give up with
+fn body_error(error: NebulaError) -> io::Result<Option<Answer>> {
+    match error {
+        NebulaError::Io { source, .. } => Err(source),
+        error => Ok(Some(Answer::Send(error.to_response(), false))),
+    }
+}
+
+// `OPTIONS *`, every method something on the server answers to
+fn server_options(config: &NebulaConfig, state: &ServerState) -> Response {
+    let routed = state
+        .router()
+        .routes
+        .iter()
+        .map(|route| route.method.as_str());
+    let admin = config.admin.reload.then_some("POST");
+    let files = file_methods(&config.content);
+    let mut response = Response::new(204);
+    let allow = allow_header(files.into_iter().chain(routed).chain(admin));
+    response.headers_mut().insert("Allow", &allow);
+    response
+}
+
+// `[[redirect]]` rules and rewrites with the redirect flag give the
+// redirect to answer with as the error, other rewrites the target to serve
+// instead
+fn redirect_or_rewrite(
+    config: &NebulaConfig,
+    path: &str,
+    query: Option<&str>,
+) -> Result<Option<String>, Response> {
+    if let Some((status, location)) = rewrite::find_redirect(&config.redirects, path, query) {
+        return Err(Response::redirect(status, &location));
+    }
+    match rewrite::apply(&config.rewrites, path, query) {
+        // queries from earlier rules may still hold decoded captures
+        Some(Rewrite::Redirect(location)) if location.chars().any(char::is_control) => {
+            let error = NebulaError::BadRequest("Invalid redirect target".to_string());
+            Err(error.to_response())
+        }
+        Some(Rewrite::Redirect(location)) => Err(Response::redirect(302, &location)),
+        Some(Rewrite::Internal(target)) => Ok(Some(target)),
+        None => Ok(None),
+    }
+}
+
+// the handlers of the `Router`
+fn route(
+    ex: &Exchange,
+    stream: &mut Stream,
+    reader: &mut RequestReader,
+) -> io::Result<Option<Answer>> {
+    let request = ex.request;
+    let method = ex.method();
+    let (route, params) = match ex.state().router().find(method, ex.path) {
+        RouteMatch::Found(route, params) => (route, params),
+        RouteMatch::MethodNotAllowed(allowed) => {
+            let allow = allow_header(allowed);
+            if method == "OPTIONS" {
+                let mut response = Response::new(204);
+                response.headers_mut().insert("Allow", &allow);
+                return Ok(Some(Answer::Send(response, ex.keep_alive)));
+            }
+            let mut response = Response::error(405, "Method not allowed");
+            response.headers_mut().insert("Allow", &allow);
+            return Ok(Some(Answer::Send(response, false)));
+        }
+        RouteMatch::None => return Ok(None),
+    };
+
+    let handler = match &route.handler {
+        RouteHandler::Response(handler) => handler,
+        RouteHandler::WebSocket(handler) => {
+            let response = websocket::handshake(request).unwrap_or_else(|error| error);
+            let response = ex.finish(response);
+            let upgraded = response.status() == 101;
+            ex.out.send(stream, response, false)?;
+            if upgraded {
+                let mut routed = request.with_target(&request.target);
+                routed.params = params;
+                let max_message = ex.config().server.body_limit();
+                let mut socket = WebSocket::new(stream, reader.take_buffered(), max_message)?;
+                handler(&routed, &mut socket);
+            }
+            return Ok(Some(Answer::Sent(false)));
+        }
+    };
+    let body = match reader.read_body(stream) {
+        Ok(body) => body,
+        Err(error) => return body_error(error),
+    };
+    let mut routed = request.with_target(&request.target);
+    routed.params = params;
+    routed.body = body;
+
+    let mut response = handler(&routed);
+    for layer in ex.layers.iter().rev() {
+        layer.on_response(&ex.ctx, &routed, &mut response);
+    }
+    Ok(Some(Answer::Send(response, ex.keep_alive)))
+}
+
+// the `/_nebula/` endpoints
+fn admin_endpoint(
+    ex: &Exchange,
+    stream: &mut Stream,
+    reader: &mut RequestReader,
+) -> io::Result<Option<Answer>> {
+    let config = ex.config();
+    let method = ex.method();
+    let (response, allow) = if let Some(endpoint) = admin::find(config, ex.listen, ex.path) {
+        let response = match (endpoint, method) {
+            (Endpoint::Echo, _) => match reader.read_body(stream) {
+                Ok(body) => admin::echo(ex.request, ex.path, &body, ex.out.remote_addr),
+                Err(error) => return body_error(error),
+            },
+            (endpoint, "GET" | "HEAD") => admin::respond(endpoint, config, ex.state()),
+            (_, "OPTIONS") => Response::new(204),
+            _ => Response::error(405, "Method not allowed"),
+        };
+        (response, "GET")
+    } else if config.admin.reload
+        && ex.path == "/_nebula/reload"
+        && admin::served_on(config, ex.listen)
+    {
+        let response = match method {
+            "POST" => match ex.state().reload() {
+                Ok(()) => Response::text("Configuration reloaded"),
+                Err(e) => {
+                    e.log();
+                    Response::error(500, &format!("Reload failed: {}", e))
+                }
+            },
+            "OPTIONS" => Response::new(204),
+            _ => Response::error(405, "Method not allowed"),
+        };
+        (response, "POST")
+    } else {
+        return Ok(None);
+    };
+
+    let mut response = response;
+    if matches!(response.status(), 204 | 405) {
+        response
+            .headers_mut()
+            .insert("Allow", &allow_header([allow]));
+    }
+    Ok(Some(ex.respond(response)))
+}
+
+// `[upload]`, files posted from a form
+fn upload_form(
+    ex: &Exchange,
+    stream: &mut Stream,
+    reader: &mut RequestReader,
+) -> io::Result<Option<Answer>> {
+    let config = ex.config();
+    if !config.upload.enabled || ex.path != config.upload.path {
+        return Ok(None);
+    }
+    let mut response = match ex.method() {
+        // like writes, so the endpoint isn't anyone's file host
+        "POST" if ex.out.user.get().is_none() && !config.upload.allow_anonymous => {
+            Response::error(403, "Uploading requires an authenticated user")
+        }
+        "POST" => {
+            let body = match reader.read_body(stream) {
+                Ok(body) => body,
+                Err(error) => return body_error(error),
+            };
+            upload::form_upload(&config.upload, ex.request.header("content-type"), &body)
+                .unwrap_or_else(|error| {
+                    error.log();
+                    error.to_response()
+                })
+        }
+        "OPTIONS" => Response::new(204),
+        _ => Response::error(405, "Method not allowed"),
+    };
+    if matches!(response.status(), 204 | 405) {
+        response.headers_mut().insert("Allow", "OPTIONS, POST");
+    }
+    Ok(Some(ex.respond_or_close(response)))
+}
+
+// `[[proxy]]` rules, the upstream writes the response
+fn forward(
+    ex: &Exchange,
+    stream: &mut Stream,
+    reader: &mut RequestReader,
+) -> io::Result<Option<Answer>> {
+    let Some(rule) = proxy::find_rule(&ex.config().proxies, ex.path) else {
+        return Ok(None);
+    };
+    // the upstream gets the path the rule matched instead of the raw
+    // target, so `/api/%2e%2e/admin` can't reach past the prefix
+    let target = match &ex.request.query {
+        Some(query) => format!("{}?{}", uri::percent_encode_path(ex.path), query),
+        None => uri::percent_encode_path(ex.path),
+    };
+    let forwarded = ex.request.with_target(&target);
+    let outcome = proxy::forward(
+        rule,
+        ex.state().upstreams(),
+        &forwarded,
+        stream,
+        reader,
+        ex.peer_addr,
+        ex.keep_alive,
+    )?;
+    let answer = match outcome {
+        ProxyOutcome::Served {
+            status,
+            bytes,
+            keep_alive,
+        } => {
+            ex.out.log(status, bytes as usize);
+            Answer::Sent(keep_alive)
+        }
+        ProxyOutcome::Upgraded {
+            upstream,
+            buffered,
+            guard,
+        } => {
+            ex.out.log(101, 0);
+            let tunnelled = websocket::tunnel(stream, reader.take_buffered(), upstream, buffered);
+            drop(guard);
+            tunnelled?;
+            Answer::Sent(false)
+        }
+        ProxyOutcome::Failed(error) => {
+            error.log();
+            Answer::Send(error.to_response(), false)
+        }
+    };
+    Ok(Some(answer))
+}
+
+// PROPFIND, and the methods that change files in writable mode
+fn write_or_dav(ex: &Exchange, stream: &mut Stream, reader: &mut RequestReader) -> Option<Answer> {
+    let config = ex.config();
+    let state = ex.state();
+    let request = ex.request;
+    let method = ex.method();
+    let (root, relative, path) = (ex.root, ex.relative, ex.path);
+    let writable = config.content.writable;
+    let webdav = config.content.webdav;
+    if webdav && method == "PROPFIND" {
+        let response = if config.content.deny_dotfiles && is_hidden(path) {
+            Response::error(404, "Page not found")
+        } else {
+            let depth = Depth::parse(request.header("depth"));
+            webdav::propfind(
+                root,
+                relative,
+                path,
+                depth,
+                config,
+                state.digests(),
+                writable,
+            )
+        };
+        return Some(ex.respond(response));
+    }
+
+    let dav_write = webdav && matches!(method, "MKCOL" | "COPY" | "MOVE" | "LOCK" | "UNLOCK");
+    if !writable || !(method == "PUT" || method == "DELETE" || dav_write) {
+        return None;
+    }
+    let target_path = format!("{}/{}", root, sanitize_path(relative));
+    let target = std::fs::metadata(&target_path).ok();
+    // anonymous uploads would turn the server into anyone's file host
+    let refusal = if ex.out.user.get().is_none() {
+        Some(Response::error(
+            403,
+            "Writing requires an authenticated user",
+        ))
+    } else if is_hidden(path) {
+        Some(Response::error(403, "Forbidden"))
+    } else if !write_preconditions_hold(
+        request.header("if-match"),
+        request.header("if-unmodified-since"),
+        target.as_ref(),
+        target
+            .as_ref()
+            .and_then(|metadata| state.digests().etag(&config.etag, &target_path, metadata))
+            .as_deref(),
+    ) {
+        // someone else changed the file since the client last saw it
+        Some(Response::error(412, "Precondition failed"))
+    } else {
+        None
+    };
+    let written = match (refusal, method) {
+        (Some(response), _) => Ok(response),
+        (None, "PUT") => upload::put(root, relative, &mut reader.body(stream)),
+        (None, "DELETE") => upload::delete(root, relative, webdav),
+        (None, "MKCOL") => webdav::mkcol(root, relative),
+        (None, "LOCK") => webdav::lock(root, relative, path),
+        (None, "UNLOCK") => Ok(Response::new(204)),
+        (None, _) => {
+            let destination = request
+                .header("destination")
+                .and_then(webdav::destination_path);
+            match destination {
+                Some(destination) if is_hidden(&destination) => {
+                    Ok(Response::error(403, "Forbidden"))
+                }
+                Some(destination) => {
+                    let (to_root, to) = config
+                        .mount_for(&destination)
+                        .unwrap_or((ex.site.public_dir, &destination));
+                    // moving between mounts would need a copy across
+                    // filesystems, clients fall back to GET and PUT
+                    if to_root != root {
+                        Ok(Response::error(502, "Destination is on another mount"))
+                    } else {
+                        let overwrite = request.header("overwrite") != Some("F");
+                        let depth = Depth::parse(request.header("depth"));
+                        webdav::transfer(root, relative, to, overwrite, method == "MOVE", depth)
+                    }
+                }
+                None => Ok(Response::error(
+                    400,
+                    "Missing or invalid Destination header",
+                )),
+            }
+        }
+    };
+    let response = written.unwrap_or_else(|e| {
+        let e = reader.take_body_error().unwrap_or(e);
+        e.log();
+        e.to_response()
+    });
+    Some(ex.respond_or_close(response))
+}
+
+// the file a static request is answered with
+struct ResolvedFile {
+    path: String,
+    // a variant picked by the Accept header
+    negotiated: bool,
+    // the language of a variant picked by Accept-Language
+    language: Option<String>,
+}
+
+// directories are served through the first of their index files that
+// exists, missing files through a variant or the SPA shell
+fn resolve_file(ex: &Exchange, file_path: String, is_dir: bool) -> ResolvedFile {
+    let config = ex.config();
+    let request = ex.request;
+    let site = &ex.site;
+    let open_files = ex.state().open_files();
+    let is_file = |path: &str| open_files.is_file(&config.open_file_cache, path);
+    let is_get = matches!(ex.method(), "GET" | "HEAD");
+
+    let file_path = if is_dir {
+        find_index(&file_path, site.index_files, is_file).unwrap_or(file_path)
+    } else {
+        file_path
+    };
+
+    // `/report` may stand for `report.pdf` and `report.txt`
+    let variant = if config.content.negotiate && is_get && !is_dir && !is_file(&file_path) {
+        negotiate::find_variant(&file_path, request.header("accept"), &config.mime)
+    } else {
+        None
+    };
+    let negotiated = variant.is_some();
+    let file_path = variant.unwrap_or(file_path);
+
+    // `about.html` may stand for `about.en.html` and `about.de.html`, a
+    // directory for `index.en.html` and so on
+    let language = if config.content.negotiate_language && is_get && !is_file(&file_path) {
+        let accept_language = request.header("accept-language");
+        let default_language = &config.content.default_language;
+        if is_dir {
+            site.index_files.iter().find_map(|index| {
+                let index = format!(
+                    "{}/{}",
+                    file_path.trim_end_matches('/'),
+                    sanitize_path(index)
+                );
+                negotiate::find_language_variant(&index, accept_language, default_language)
+            })
+        } else {
+            negotiate::find_language_variant(&file_path, accept_language, default_language)
+        }
+    } else {
+        None
+    };
+    let (file_path, language) = match language {
+        Some((variant, language)) => (variant, Some(language)),
+        None => (file_path, None),
+    };
+
+    // single page apps do their routing on the client, so every unknown
+    // path gets the app shell instead of a 404
+    let spa_shell = if site.spa_fallback && !is_file(&file_path) {
+        find_index(ex.root, site.index_files, is_file)
+    } else {
+        None
+    };
+    ResolvedFile {
+        path: spa_shell.unwrap_or(file_path),
+        negotiated,
+        language,
+    }
+}
+
+// relative links in an index page only resolve against the directory when
+// its URL ends in a slash, so directories get one like nginx does
+fn trailing_slash_redirect(ex: &Exchange, file_path: &str, is_dir: bool) -> Option<Response> {
+    let config = ex.config();
+    let path = ex.path;
+    let open_files = ex.state().open_files();
+    let dotfile = config.content.deny_dotfiles && is_hidden(path);
+    let is_get = matches!(ex.method(), "GET" | "HEAD");
+    if !is_get || path != ex.requested_path || path == "/" || dotfile {
+        return None;
+    }
+    let ends_with_slash = path.ends_with('/');
+    // from the normalized path, which starts with a single slash, so
+    // `//evil.example` can't turn into a protocol-relative location
+    let location = match config.content.trailing_slash {
+        TrailingSlash::Add if is_dir && !ends_with_slash => {
+            format!("{}/", uri::percent_encode_path(path))
+        }
+        TrailingSlash::Remove
+            if ends_with_slash
+                && open_files
+                    .metadata(&config.open_file_cache, file_path)
+                    .is_some() =>
+        {
+            uri::percent_encode_path(path.trim_end_matches('/'))
+        }
+        _ => return None,
+    };
+    if location.is_empty() {
+        return None;
+    }
+    let location = match &ex.request.query {
+        Some(query) => format!("{}?{}", location, query),
+        None => location,
+    };
+    Some(Response::redirect(301, &location))
+}
+
+// the contents of a static file, small files and ones worth compressing on
+// the fly are loaded into memory, everything else is streamed from disk
+fn read_file(
+    ex: &Exchange,
+    file_path: &str,
+    served_path: &str,
+    precompressed: bool,
+    compress: bool,
+) -> Result<Body, String> {
+    let config = ex.config();
+    let state = ex.state();
+    let open_files = state.open_files();
+    let content_type = mime::content_type(file_path, &config.mime);
+    let is_binary = precompressed
+        || (!content_type.starts_with("text/") && content_type != "application/javascript");
+
+    let metadata = open_files.metadata(&config.open_file_cache, served_path);
+    let len = metadata.as_ref().map_or(0, |metadata| metadata.len());
+    let in_memory = len <= config.file_cache.max_entry_kb * 1024
+        || (compress
+            && len <= compression::MAX_COMPRESS_SIZE
+            && config
+                .compression
+                .should_compress(content_type, len as usize));
+    let read = telemetry::Span::child("read file", SpanKind::Internal);
+    read.set("file.path", served_path);
+    let body = if in_memory {
+        match &metadata {
+            Some(metadata) => state
+                .file_cache()
+                .read(served_path, metadata, &config.file_cache)
+                .map(Body::Shared),
+            None => Err(io::ErrorKind::NotFound.into()),
+        }
+    } else {
+        open_files
+            .open(&config.open_file_cache, served_path)
+            .map(|file| {
+                let mapped = if config.performance.use_mmap && len > 0 {
+                    Body::map(&file, len)
+                        .map_err(|e| tracing::warn!("Failed to map {}: {}", served_path, e))
+                        .ok()
+                } else {
+                    None
+                };
+                mapped.unwrap_or(Body::File {
+                    file,
+                    offset: 0,
+                    len,
+                })
+            })
+    };
+    if body.is_err() {
+        read.set_error();
+    }
+    drop(read);
+
+    match body {
+        // text is only sent when it's valid UTF-8
+        Ok(Body::Shared(contents)) if !is_binary && std::str::from_utf8(&contents).is_err() => {
+            Err("Error reading file".to_string())
+        }
+        Ok(body) => Ok(body),
+        Err(e) => Err(format!("Error reading file: {}", e)),
+    }
+}
+
+// static files, with conditional and range requests and compression
+fn static_file(ex: &Exchange) -> Answer {
+    let config = ex.config();
+    let state = ex.state();
+    let request = ex.request;
+    let method = ex.method();
+    let (root, path, site) = (ex.root, ex.path, &ex.site);
+    let open_files = state.open_files();
+    let open_file_cache = &config.open_file_cache;
+    let is_file = |path: &str| open_files.is_file(open_file_cache, path);
+
+    // remove the leading slash
+    let file_path = format!("{}/{}", root, sanitize_path(ex.relative));
+    let is_dir = open_files.is_dir(open_file_cache, &file_path);
+    if let Some(redirect) = trailing_slash_redirect(ex, &file_path, is_dir) {
+        return Answer::Send(redirect, ex.keep_alive);
+    }
+    let ResolvedFile {
+        path: file_path,
+        negotiated,
+        language,
+    } = resolve_file(ex, file_path, is_dir);
+
+    // dotfiles like `.env` or `.git/config` and symlinks the policy doesn't
+    // allow are answered as if missing
+    let is_get = method == "GET" || method == "HEAD";
+    let dotfile = config.content.deny_dotfiles && is_hidden(path);
+    let follow_symlinks = config.content.follow_symlinks;
+    let hidden = dotfile || !symlinks_permitted(follow_symlinks, root, &file_path);
+
+    // a resumed download only gets the rest while the file is the one it
+    // started with, after a change If-Range asks for all of it again
+    let range = request
+        .header("range")
+        .filter(|_| match request.header("if-range") {
+            Some(if_range) => {
+                let metadata = open_files.metadata(open_file_cache, &file_path);
+                if_range_matches(
+                    if_range,
+                    metadata
+                        .as_ref()
+                        .and_then(|metadata| {
+                            state.digests().etag(&config.etag, &file_path, metadata)
+                        })
+                        .as_deref(),
+                    metadata.and_then(|metadata| metadata.modified().ok()),
+                )
+            }
+            None => true,
+        });
+    let accept_encoding = request.header("accept-encoding");
+
+    // a precompressed sidecar like `app.js.br` beats compressing on the fly
+    let precompressed = match &accept_encoding {
+        Some(accept) if is_get && range.is_none() && config.compression.precompressed => {
+            compression::find_precompressed(&file_path, accept, is_file)
+                .filter(|(_, sidecar)| symlinks_permitted(follow_symlinks, root, sidecar))
+        }
+        _ => None,
+    };
+    let served_path = precompressed
+        .as_ref()
+        .map_or(file_path.as_str(), |(_, sidecar)| sidecar.as_str());
+
+    // `is_file` marks responses that carry a static file from disk
+    let (status, content, is_file) = if method == "OPTIONS" {
+        (204, Body::from(""), false)
+    } else if !is_get {
+        (405, Body::from("Method not allowed"), false)
+    } else if hidden || !is_file(&file_path) {
+        (404, Body::from("Page not found"), false)
+    } else {
+        let compress = precompressed.is_none() && range.is_none() && accept_encoding.is_some();
+        match read_file(
+            ex,
+            &file_path,
+            served_path,
+            precompressed.is_some(),
+            compress,
+        ) {
+            Ok(body) => (200, body, true),
+            Err(message) => (500, Body::from(message), false),
+        }
+    };
+
+    let mut headers = Headers::default();
+    if status == 204 || status == 405 {
+        headers.insert("Allow", &allow_header(file_methods(&config.content)));
+    }
+    // class 2 means locks, which are only handed out for writing
+    if config.content.webdav && method == "OPTIONS" {
+        let classes = if config.content.writable { "1, 2" } else { "1" };
+        headers.insert("DAV", classes);
+        headers.insert("MS-Author-Via", "DAV");
+    }
+    let content_type = if is_file {
+        mime::content_type(&file_path, &config.mime)
+    } else {
+        "text/plain"
+    };
+
+    // whole static files are compressed on the fly, ranges always refer to
+    // the uncompressed bytes
+    let compressible = is_file
+        && content.len() <= compression::MAX_COMPRESS_SIZE
+        && config
+            .compression
+            .should_compress(content_type, content.len() as usize);
+    let sidecar_encoding = if is_file {
+        precompressed.as_ref().map(|(encoding, _)| *encoding)
+    } else {
+        None
+    };
+    let encoding = match (&accept_encoding, sidecar_encoding) {
+        (_, Some(encoding)) => Some(encoding),
+        (Some(accept), None) if compressible && range.is_none() => compression::negotiate(accept),
+        _ => None,
+    };
+    if compressible || sidecar_encoding.is_some() {
+        headers.insert("Vary", "Accept-Encoding");
+    }
+    if negotiated && is_file {
+        headers.insert("Vary", "Accept");
+    }
+    if let Some(language) = language.as_deref().filter(|_| is_file) {
+        headers.insert("Content-Language", language);
+        headers.insert("Vary", "Accept-Language");
+    }
+
+    let metadata = if is_file {
+        open_files.metadata(open_file_cache, served_path)
+    } else {
+        None
+    };
+    // each encoding is a distinct representation and needs its own validator
+    let etag = metadata
+        .as_ref()
+        .and_then(|metadata| state.digests().etag(&config.etag, served_path, metadata))
+        .map(|etag| match encoding {
+            Some(encoding) => encoded_etag(&etag, encoding.name()),
+            None => etag,
+        });
+    let last_modified = metadata
+        .as_ref()
+        .and_then(|metadata| metadata.modified().ok());
+
+    if let Some(etag) = &etag {
+        headers.insert("ETag", etag);
+    }
+    if let Some(last_modified) = last_modified {
+        headers.insert("Last-Modified", &http_date::format(last_modified));
+    }
+
+    // the client already has the current version cached, If-Modified-Since
+    // is only consulted when no If-None-Match was sent
+    let not_modified = match request.header("if-none-match") {
+        Some(if_none_match) => etag
+            .as_ref()
+            .is_some_and(|etag| etag_matches(if_none_match, etag)),
+        None => match (last_modified, request.header("if-modified-since")) {
+            (Some(last_modified), Some(since)) => {
+                http_date::parse(since).is_some_and(|since| !modified_after(last_modified, since))
+            }
+            _ => false,
+        },
+    };
+
+    // static files can be requested in parts, e.g. for seeking in videos,
+    // several at once come as multipart/byteranges
+    let mut multipart_type = None;
+    let (status, content) = if not_modified {
+        (304, Body::Bytes(Vec::new()))
+    } else if is_file {
+        headers.insert("Accept-Ranges", "bytes");
+
+        match range.map(|range| parse_range(range, content.len())) {
+            Some(ByteRange::Partial(start, end)) => {
+                let content_range = format!("bytes {}-{}/{}", start, end, content.len());
+                headers.insert("Content-Range", &content_range);
+                (206, content.slice(start, end))
+            }
+            Some(ByteRange::Multiple(ranges)) => {
+                let boundary = format!("{:016x}", RandomState::new().build_hasher().finish());
+                let part_type = mime::with_charset(content_type, site.charset);
+                match content.byteranges(&ranges, &part_type, &boundary) {
+                    Some(parts) => {
+                        multipart_type =
+                            Some(format!("multipart/byteranges; boundary={}", boundary));
+                        (206, parts)
+                    }
+                    None => (500, Body::from("Error reading file")),
+                }
+            }
+            Some(ByteRange::Unsatisfiable) => {
+                headers.insert("Content-Range", &format!("bytes */{}", content.len()));
+                (416, Body::from("Requested range not satisfiable"))
+            }
+            Some(ByteRange::Full) | None => (status, content),
+        }
+    } else {
+        (status, content)
+    };
+
+    // swap the plaintext message for the configured page of this status
+    let (content, content_type) = match error_page(config, site, status) {
+        Some((page, page_type)) => (Body::Bytes(page), page_type),
+        None => (content, content_type),
+    };
+
+    let content = match encoding {
+        Some(encoding) if sidecar_encoding.is_some() && !not_modified => {
+            headers.insert("Content-Encoding", encoding.name());
+            content
+        }
+        Some(encoding) if !not_modified => {
+            let compress = telemetry::Span::child("compress", SpanKind::Internal);
+            compress.set("http.response.content_encoding", encoding.name());
+            let compressed = content
+                .as_bytes()
+                .map(|bytes| compression::compress(encoding, bytes, &config.compression));
+            drop(compress);
+            match compressed {
+                Some(Ok(compressed)) => {
+                    headers.insert("Content-Encoding", encoding.name());
+                    Body::Bytes(compressed)
+                }
+                Some(Err(e)) => {
+                    tracing::warn!("Failed to compress {}: {}", file_path, e);
+                    content
+                }
+                None => content,
+            }
+        }
+        _ => content,
+    };
+
+    // the digest is of the whole file in the encoding it is sent in, also
+    // for ranges of it
+    if config.etag.repr_digest && is_file && matches!(status, 200 | 206) {
+        let compressed_here = sidecar_encoding.is_none() && headers.contains("Content-Encoding");
+        let digest = match (&metadata, content.as_bytes()) {
+            (_, Some(compressed)) if compressed_here => Some(digest::sha256(compressed)),
+            (Some(metadata), _) if !compressed_here => state
+                .digests()
+                .digest(served_path, metadata, Hash::Sha256)
+                .map_err(|e| tracing::warn!("Failed to hash {}: {}", served_path, e))
+                .ok(),
+            _ => None,
+        };
+        if let Some(digest) = digest {
+            headers.insert("Repr-Digest", &digest::repr_digest(&digest));
+        }
+    }
+
+    let mut response = Response::from_body(status, content);
+    let response_headers = response.headers_mut();
+    if status != 204 {
+        let content_type = match &multipart_type {
+            Some(multipart_type) => multipart_type.into(),
+            None => mime::with_charset(content_type, site.charset),
+        };
+        response_headers.insert("Content-Type", &content_type);
+    }
+    // errors aren't worth caching, they should go away once fixed, and
+    // neither are answers to OPTIONS
+    if status < 400 && status != 204 {
+        let file = is_file.then_some(file_path.as_str());
+        if let Some(value) = config.cache_control.value_for(path, file) {
+            response_headers.insert("Cache-Control", value);
+        }
+    }
+    for (name, value) in headers.iter() {
+        response_headers.insert(name, value);
+    }
+    ex.respond(response)
+}
+
+// the methods static files answer to, which depends on writable mode and
+// WebDAV being on
+fn file_methods(content: &ContentConfig) -> Vec<&'static str> {
+    let mut methods = vec!["GET"];
+    if content.writable {
+        methods.extend(["PUT", "DELETE"]);
+    }
+    if content.webdav {
+        methods.push("PROPFIND");
+    }
+    if content.webdav && content.writable {
+        methods.extend(["MKCOL", "COPY", "MOVE", "LOCK", "UNLOCK"]);
+    }
+    methods
+}
+
+// the Allow header for a resource answering `methods`, HEAD comes with GET
+// and OPTIONS is always answered
+fn allow_header<'a>(methods: impl IntoIterator<Item = &'a str>) -> String {
+    let mut allowed = Vec::new();
+    for method in methods.into_iter().chain(["OPTIONS"]) {
+        let implied = (method == "GET").then_some("HEAD");
+        for method in std::iter::once(method).chain(implied) {
+            if !allowed.contains(&method) {
+                allowed.push(method);
+            }
+        }
+    }
+    allowed.join(", ")
+}
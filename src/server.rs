@@ -0,0 +1,737 @@
+//! The listening side: sockets, the accept loops feeding the worker pool
+//! and the keep-alive loop of each connection.
+
+use crate::config::{self, ListenConfig, NebulaConfig};
+use crate::error::NebulaError;
+use crate::listener::{self, Listener, LocalAddr, Stream};
+use crate::logging;
+use crate::middleware::Middleware;
+use crate::pool::ThreadPool;
+#[cfg(unix)]
+use crate::processes::{self, Role};
+use crate::proxy_protocol;
+use crate::request::{HeadDeadline, HeadLimits, ReadError, Request, RequestReader};
+use crate::response::write_error;
+use crate::router::{self, Connection, Router};
+use crate::state::{ConfigOverride, ServerState};
+use crate::systemd;
+#[cfg(unix)]
+use crate::upgrade;
+use crate::upstream;
+use crate::wire;
+use std::io;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+// unread request bodies up to this size are skipped to keep the connection
+const MAX_DISCARDED_BODY: u64 = 64 * 1024;
+
+// how long a new binary has to start serving before an upgrade is given up
+#[cfg(unix)]
+const UPGRADE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A configured server with its sockets bound, ready to [`run`](Server::run).
+///
+/// ```no_run
+/// nebula::Server::builder()
+///     .bind(([127, 0, 0, 1], 8080))
+///     .public_dir("site")
+///     .build()?
+///     .run()
+/// # ; Ok::<(), nebula::NebulaError>(())
+/// ```
+pub struct Server {
+    state: Arc<ServerState>,
+    listeners: Vec<(Listener, Arc<ListenConfig>)>,
+    // whether the listeners came from systemd
+    inherited: bool,
+    handle_signals: bool,
+}
+
+/// Collects the settings for a [`Server`]. Anything not set comes from the
+/// base config, [`NebulaConfig::default`] unless one is given.
+#[derive(Default)]
+pub struct ServerBuilder {
+    config: Option<NebulaConfig>,
+    config_file: Option<PathBuf>,
+    overrides: Vec<ConfigOverride>,
+    binds: Vec<SocketAddr>,
+    router: Router,
+    middleware: Vec<Arc<dyn Middleware>>,
+    handle_signals: bool,
+}
+
+impl ServerBuilder {
+    /// Starts from `config` instead of the defaults.
+    pub fn config(mut self, config: NebulaConfig) -> Self {
+        self.config = Some(config);
+        self
+    }
+
+    /// The file reloads re-read, see [`Server`]. It is also loaded as the
+    /// base config unless [`config`](Self::config) was given.
+    pub fn config_file(mut self, path: impl Into<PathBuf>) -> Self {
+        self.config_file = Some(path.into());
+        self
+    }
+
+    /// Changes the config now and again after every reload, so settings made
+    /// in code survive a reload.
+    pub fn configure<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&mut NebulaConfig) + Send + Sync + 'static,
+    {
+        self.overrides.push(Box::new(f));
+        self
+    }
+
+    /// Listens on `addr`. Can be called several times, the addresses replace
+    /// any listeners from the config.
+    pub fn bind(mut self, addr: impl Into<SocketAddr>) -> Self {
+        self.binds.push(addr.into());
+        self
+    }
+
+    /// Route handlers answering before proxy rules and static files.
+    pub fn router(mut self, router: Router) -> Self {
+        self.router = router;
+        self
+    }
+
+    /// Adds a layer after the built-in ones and those added before.
+    pub fn middleware(mut self, layer: impl Middleware + 'static) -> Self {
+        self.middleware.push(Arc::new(layer));
+        self
+    }
+
+    pub fn public_dir(self, dir: impl Into<String>) -> Self {
+        let dir = dir.into();
+        self.configure(move |config| config.content.public_dir = dir.clone())
+    }
+
+    pub fn workers(self, workers: usize) -> Self {
+        self.configure(move |config| config.server.workers = Some(workers))
+    }
+
+    /// Reloads on SIGHUP and shuts down gracefully on SIGINT/SIGTERM. Off by
+    /// default since signal handlers are process wide.
+    pub fn handle_signals(mut self, enabled: bool) -> Self {
+        self.handle_signals = enabled;
+        self
+    }
+
+    /// Applies the settings and binds every listener.
+    pub fn build(mut self) -> Result<Server, NebulaError> {
+        if !self.binds.is_empty() {
+            let binds = std::mem::take(&mut self.binds);
+            self.overrides.push(Box::new(move |config| {
+                config.server.listen = binds
+                    .iter()
+                    .map(|addr| ListenConfig::new(&addr.ip().to_string(), addr.port()))
+                    .collect();
+            }));
+        }
+
+        let mut config = match (self.config, &self.config_file) {
+            (Some(config), _) => config,
+            (None, Some(path)) => config::try_load_config(path)?,
+            (None, None) => NebulaConfig::default(),
+        };
+        for apply in &self.overrides {
+            apply(&mut config);
+        }
+        logging::init_tracing(&config.logging);
+
+        // sockets passed in by systemd take the place of the configured
+        // ones, otherwise every listener is bound before serving anything
+        let inherited = systemd::listen_fds()
+            .map_err(|e| NebulaError::io("Failed to take over sockets from systemd", e))?;
+        // or the ones of the process this one replaces in an upgrade
+        #[cfg(unix)]
+        let (inherited, source) = if inherited.is_empty() {
+            let passed = upgrade::listen_fds().map_err(|e| {
+                NebulaError::io("Failed to take over sockets from the previous process", e)
+            })?;
+            (passed, "the previous process")
+        } else {
+            (inherited, "systemd")
+        };
+        #[cfg(not(unix))]
+        let source = "systemd";
+        let mut listeners = Vec::new();
+        let is_inherited = !inherited.is_empty();
+        let reuse_port = config.server.processes > 1;
+        if is_inherited {
+            for listener in inherited {
+                let addr = listener.local_addr().map_err(|e| {
+                    NebulaError::io(format!("Failed to take over sockets from {}", source), e)
+                })?;
+                let listen = inherited_listen(&config, &addr);
+                let serving = if listen.admin {
+                    "Admin endpoints are"
+                } else {
+                    "Server is"
+                };
+                tracing::info!("{} listening on {} (from {})", serving, addr, source);
+                listeners.push((listener, Arc::new(listen)));
+            }
+        } else {
+            for listen in config.server.listeners() {
+                let context = format!("Failed to bind {}:{}", listen.address, listen.port);
+                let listener = listener::bind(&listen, reuse_port)
+                    .map_err(|e| NebulaError::io(&context, e))?;
+                let addr = listener
+                    .local_addr()
+                    .map_err(|e| NebulaError::io(&context, e))?;
+                tracing::info!("Server is listening on {}", addr);
+                listeners.push((listener, Arc::new(listen)));
+            }
+            if let Some(path) = &config.server.unix_socket {
+                let context = format!("Failed to bind {}", path);
+                let listener = listener::bind_unix(path, config.server.unix_socket_mode)
+                    .map_err(|e| NebulaError::io(&context, e))?;
+                tracing::info!("Server is listening on unix:{}", path);
+                listeners.push((listener, Arc::new(ListenConfig::new(path, 0))));
+            }
+            if let Some(listen) = config.admin.listener() {
+                let context = format!("Failed to bind {}:{}", listen.address, listen.port);
+                let listener = listener::bind(&listen, reuse_port)
+                    .map_err(|e| NebulaError::io(&context, e))?;
+                let addr = listener
+                    .local_addr()
+                    .map_err(|e| NebulaError::io(&context, e))?;
+                tracing::info!("Admin endpoints are listening on {}", addr);
+                listeners.push((listener, Arc::new(listen)));
+            }
+        }
+
+        let state = ServerState::new(
+            config,
+            self.config_file,
+            self.overrides,
+            self.router,
+            self.middleware,
+        )?;
+        Ok(Server {
+            state: Arc::new(state),
+            listeners,
+            inherited: is_inherited,
+            handle_signals: self.handle_signals,
+        })
+    }
+}
+
+// the config for a socket from systemd: the listener with the same port,
+// the admin one included, or the defaults
+fn inherited_listen(config: &NebulaConfig, addr: &LocalAddr) -> ListenConfig {
+    let mut configured = config.server.listeners();
+    configured.extend(config.admin.listener());
+    match addr {
+        LocalAddr::Tcp(addr) => configured
+            .into_iter()
+            .find(|listen| listen.port == addr.port())
+            .unwrap_or_else(|| ListenConfig::new(&addr.ip().to_string(), addr.port())),
+        #[cfg(unix)]
+        LocalAddr::Unix(path) => ListenConfig::new(&path.to_string_lossy(), 0),
+    }
+}
+
+impl Server {
+    pub fn builder() -> ServerBuilder {
+        ServerBuilder::default()
+    }
+
+    /// The bound TCP addresses, e.g. to find the port picked for port 0.
+    pub fn local_addrs(&self) -> io::Result<Vec<SocketAddr>> {
+        let mut addrs = Vec::new();
+        for (listener, _) in &self.listeners {
+            if let LocalAddr::Tcp(addr) = listener.local_addr()? {
+                addrs.push(addr);
+            }
+        }
+        Ok(addrs)
+    }
+
+    /// Serves until a shutdown signal arrives and open connections have
+    /// drained. Without signal handling this only returns on errors.
+    pub fn run(self) -> Result<(), NebulaError> {
+        let state = self.state;
+        let config = state.config();
+        let mut listeners = self.listeners;
+
+        #[cfg(unix)]
+        let role = processes::spawn(config.server.processes, &mut listeners, self.inherited)
+            .map_err(|e| NebulaError::io("Failed to start the server processes", e))?;
+        #[cfg(unix)]
+        if let Role::Parent(children) = &role {
+            tracing::info!("Serving from {} processes", children.len() + 1);
+        }
+
+        state.start_telemetry();
+
+        let workers = config
+            .server
+            .workers
+            .unwrap_or_else(|| {
+                thread::available_parallelism()
+                    .map(|n| n.get())
+                    .unwrap_or(1)
+            })
+            .max(1);
+        let pool = Arc::new(ThreadPool::new(workers, config.server.queue_size));
+        tracing::info!("Serving with {} worker threads", workers);
+
+        #[cfg(unix)]
+        if self.handle_signals {
+            spawn_signal_handler(Arc::clone(&state), &listeners, &role)
+                .map_err(|e| NebulaError::io("Failed to install signal handlers", e))?;
+        }
+
+        let health_state = Arc::clone(&state);
+        thread::Builder::new()
+            .name("nebula-health".to_string())
+            .spawn(move || upstream::run_health_checks(&health_state))
+            .map_err(|e| NebulaError::io("Failed to start health checks", e))?;
+
+        // every listener gets its own accept loop feeding the shared pool,
+        // connections queue up in the sockets until then
+        systemd::notify("READY=1");
+        #[cfg(unix)]
+        upgrade::ready();
+        state.set_accepting(listeners.len());
+        let accept_threads: Vec<_> = listeners
+            .into_iter()
+            .map(|(listener, listen)| {
+                let state = Arc::clone(&state);
+                let pool = Arc::clone(&pool);
+                thread::spawn(move || accept_loop(listener, listen, &state, &pool))
+            })
+            .collect();
+        for thread in accept_threads {
+            let _ = thread.join();
+        }
+
+        // give in-flight requests a chance to finish before exiting
+        let drain_timeout = Duration::from_secs(state.config().server.drain_timeout);
+        let deadline = Instant::now() + drain_timeout;
+        while state.active_connections() > 0 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(50));
+        }
+        #[cfg(unix)]
+        if let Role::Parent(children) = &role {
+            processes::wait(children);
+        }
+
+        let remaining = state.active_connections();
+        if remaining > 0 {
+            return Err(NebulaError::io(
+                "Shutdown failed",
+                io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!("drain timeout reached with {} open connections", remaining),
+                ),
+            ));
+        }
+
+        drop(pool);
+        if let Some(telemetry) = state.telemetry() {
+            telemetry.flush(Duration::from_secs(2));
+        }
+        tracing::info!("Server shut down cleanly");
+        Ok(())
+    }
+}
+
+// accepts connections until shutdown, blocking whenever the worker queue
+// is full
+fn accept_loop(
+    listener: Listener,
+    listen: Arc<ListenConfig>,
+    state: &Arc<ServerState>,
+    pool: &ThreadPool,
+) {
+    loop {
+        let stream = listener.accept();
+        if state.is_shutting_down() {
+            break;
+        }
+
+        match stream {
+            Ok(mut stream) => {
+                let peer = stream.peer_addr();
+                let ip = peer.map(|addr| addr.ip());
+                let Some(guard) = state.track_connection(ip, &state.config().server) else {
+                    // refuse right here so a flood never reaches the workers
+                    let _ = stream.set_write_timeout(Some(Duration::from_secs(1)));
+                    let tokens = state.config().server.server_tokens;
+                    let _ = write_error(&mut stream, 503, "Too many connections", tokens);
+                    continue;
+                };
+                let state = Arc::clone(state);
+                let listen = Arc::clone(&listen);
+
+                pool.execute(move || {
+                    if let Err(e) = handle_connection(stream, &state, &listen) {
+                        let context = match peer {
+                            Some(peer) => format!("Connection from {}", peer),
+                            None => "Connection".to_string(),
+                        };
+                        NebulaError::io(context, e).log();
+                    }
+                    drop(guard);
+                });
+            }
+            Err(e) => NebulaError::io("Accepting a connection failed", e).log(),
+        }
+    }
+    state.stopped_accepting();
+}
+
+// SIGHUP reloads the configuration file, SIGINT and SIGTERM start a graceful
+// shutdown and a second one exits immediately, SIGUSR2 hands the sockets to
+// a new binary before shutting down. With server.processes the first
+// process passes them on to the others
+#[cfg(unix)]
+fn spawn_signal_handler(
+    state: Arc<ServerState>,
+    listeners: &[(Listener, Arc<ListenConfig>)],
+    role: &Role,
+) -> io::Result<()> {
+    use signal_hook::consts::{SIGHUP, SIGINT, SIGTERM, SIGUSR2};
+    use signal_hook::iterator::Signals;
+    use std::os::fd::AsFd;
+
+    let listen_addrs = listeners
+        .iter()
+        .map(|(listener, _)| listener.local_addr())
+        .collect::<io::Result<Vec<LocalAddr>>>()?;
+    // with SO_REUSEPORT a wake-up connection can end up with any process,
+    // shutting the socket down wakes this one's accept() for sure
+    let reuse_port = match role {
+        Role::Only => Vec::new(),
+        _ => listeners
+            .iter()
+            .filter_map(|(listener, _)| match listener {
+                Listener::Tcp(listener) => Some(listener.try_clone()),
+                _ => None,
+            })
+            .collect::<io::Result<Vec<_>>>()?,
+    };
+    // copies of the sockets for an upgrade, which would have to hand over
+    // those of every process otherwise
+    let handover = match role {
+        Role::Only => listeners
+            .iter()
+            .map(|(listener, _)| listener.as_fd().try_clone_to_owned())
+            .collect::<io::Result<Vec<_>>>()?,
+        _ => Vec::new(),
+    };
+    let (children, is_child) = match role {
+        Role::Parent(children) => (children.clone(), false),
+        Role::Child => (Vec::new(), true),
+        Role::Only => (Vec::new(), false),
+    };
+
+    let mut signals = Signals::new([SIGHUP, SIGINT, SIGTERM, SIGUSR2])?;
+    thread::spawn(move || {
+        for signal in signals.forever() {
+            if signal == SIGUSR2 {
+                if handover.is_empty() {
+                    tracing::warn!("Upgrades aren't possible with server.processes");
+                    continue;
+                }
+                if state.is_shutting_down() {
+                    continue;
+                }
+                tracing::info!("Upgrading, starting the new binary");
+                let child = match upgrade::start(&handover, UPGRADE_TIMEOUT) {
+                    Ok(child) => child,
+                    Err(e) => {
+                        NebulaError::io("Upgrade failed, the running server goes on", e).log();
+                        continue;
+                    }
+                };
+                listener::keep_socket_files();
+                state.begin_shutdown();
+                systemd::notify(&format!("MAINPID={}", child.id()));
+                tracing::info!(
+                    "Process {} took over, waiting for open connections to finish",
+                    child.id()
+                );
+                // the new process accepts from the same sockets and may get
+                // the wake-up connections, so they go on until this one's
+                // accept loops noticed
+                while state.accepting() > 0 {
+                    wake_accept_loops(&listen_addrs);
+                    thread::sleep(Duration::from_millis(20));
+                }
+                continue;
+            }
+
+            if signal == SIGHUP {
+                systemd::notify("RELOADING=1");
+                if let Err(e) = state.reload() {
+                    e.log();
+                    tracing::warn!("Keeping the current config");
+                }
+                systemd::notify("READY=1");
+                processes::signal(&children, libc::SIGHUP);
+                continue;
+            }
+
+            if state.begin_shutdown() {
+                // children hear of a shutdown from the parent and from
+                // systemd alike, only the parent's second signal forces it
+                if is_child {
+                    continue;
+                }
+                tracing::warn!("Forced shutdown");
+                processes::signal(&children, libc::SIGKILL);
+                std::process::exit(130);
+            }
+            tracing::info!("Shutting down, waiting for open connections to finish");
+            systemd::notify("STOPPING=1");
+            processes::signal(&children, libc::SIGTERM);
+            for listener in &reuse_port {
+                use std::os::fd::AsRawFd;
+                // SAFETY: the fd belongs to a clone of the listener that this
+                // thread owns and borrows here, so it stays open for the
+                // call. shutdown() neither closes nor frees it.
+                unsafe { libc::shutdown(listener.as_raw_fd(), libc::SHUT_RD) };
+            }
+
+            wake_accept_loops(&listen_addrs);
+        }
+    });
+    Ok(())
+}
+
+// the accept loops are blocked in accept(), a throwaway connection wakes
+// each of them up so they notice the shutdown
+#[cfg(unix)]
+fn wake_accept_loops(listen_addrs: &[LocalAddr]) {
+    for addr in listen_addrs {
+        match addr {
+            LocalAddr::Tcp(addr) => {
+                let _ = std::net::TcpStream::connect(wake_addr(*addr));
+            }
+            LocalAddr::Unix(path) => {
+                let _ = std::os::unix::net::UnixStream::connect(path);
+            }
+        }
+    }
+}
+
+// a listener on the unspecified address is reachable through loopback
+#[cfg(unix)]
+fn wake_addr(addr: SocketAddr) -> SocketAddr {
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+    match addr.ip() {
+        IpAddr::V4(ip) if ip.is_unspecified() => {
+            SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), addr.port())
+        }
+        IpAddr::V6(ip) if ip.is_unspecified() => {
+            SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), addr.port())
+        }
+        _ => addr,
+    }
+}
+
+fn handle_connection(
+    mut stream: Stream,
+    state: &ServerState,
+    listen: &ListenConfig,
+) -> io::Result<()> {
+    // the whole connection is served with the config it started with
+    let config = &*state.config();
+
+    stream.set_write_timeout(Some(Duration::from_secs(30)))?;
+
+    // a zero timeout or request limit disables keep-alive entirely
+    let keep_alive_enabled =
+        config.server.keep_alive_timeout > 0 && config.server.max_requests_per_connection > 1;
+    let mut requests_served = 0;
+
+    let limits = HeadLimits {
+        max_request_line: config.server.max_request_line,
+        max_header_size: config.server.max_header_size,
+    };
+    let mut reader = RequestReader::default();
+
+    // the header has to arrive as promptly as a request head
+    let peer_addr = if listen.proxy_protocol {
+        let timeout = Duration::from_secs(config.server.header_timeout.max(1));
+        stream.set_read_timeout(Some(timeout))?;
+        match proxy_protocol::read_header(&mut stream) {
+            Ok(Some(addr)) => Some(addr),
+            Ok(None) => stream.peer_addr(),
+            // whatever sent this isn't the balancer, or it went away
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(e) => return Err(e),
+        }
+    } else {
+        stream.peer_addr()
+    };
+    let connection = Connection { listen, peer_addr };
+    let peer = peer_addr.map_or_else(|| "unix".to_string(), |addr| addr.to_string());
+    let _span = tracing::info_span!("connection", peer = %peer).entered();
+
+    loop {
+        // stop reusing connections once the server is draining
+        if requests_served > 0 && state.is_shutting_down() {
+            return Ok(());
+        }
+
+        // the first request gets the full read timeout, idle keep-alive
+        // connections are only held open for keep_alive_timeout
+        let idle_timeout = if requests_served == 0 {
+            Duration::from_secs(30)
+        } else {
+            Duration::from_secs(config.server.keep_alive_timeout)
+        };
+        let mut deadline = HeadDeadline::new(
+            &stream,
+            idle_timeout,
+            Duration::from_secs(config.server.header_timeout.max(1)),
+            config.server.min_header_rate,
+        );
+        if reader.has_buffered() {
+            deadline = deadline.start_now();
+        }
+        let head = reader.read_head(&mut deadline, &limits);
+        // bodies get the regular socket timeout again
+        stream.set_read_timeout(Some(Duration::from_secs(30)))?;
+
+        let buffer = match head {
+            Ok(head) => head,
+            // the client closed the connection
+            Err(ReadError::Closed) => return Ok(()),
+            Err(ReadError::Io(e))
+                if requests_served > 0
+                    && !reader.has_buffered()
+                    && matches!(
+                        e.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) =>
+            {
+                // idle keep-alive connection timed out
+                return Ok(());
+            }
+            // part of a head arrived, but not all of it in time
+            Err(ReadError::Io(e))
+                if reader.has_buffered()
+                    && matches!(
+                        e.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) =>
+            {
+                return write_error(
+                    &mut stream,
+                    408,
+                    "Request timeout",
+                    config.server.server_tokens,
+                );
+            }
+            Err(ReadError::Io(e)) => return Err(e),
+            Err(ReadError::RequestLineTooLong) => {
+                return write_error(
+                    &mut stream,
+                    414,
+                    "URI too long",
+                    config.server.server_tokens,
+                );
+            }
+            Err(ReadError::HeadersTooLarge) => {
+                return write_error(
+                    &mut stream,
+                    431,
+                    "Request header fields too large",
+                    config.server.server_tokens,
+                );
+            }
+        };
+        requests_served += 1;
+        if config.logging.dump_wire {
+            wire::dump_request(&buffer);
+        }
+
+        let request = match Request::parse(&buffer) {
+            Ok(request) => request,
+            Err(e) => {
+                e.log();
+                // the rest of the stream can't be trusted to be framed right
+                e.to_response()
+                    .write_to(&mut stream, None, false, config.server.server_tokens)?;
+                return Ok(());
+            }
+        };
+
+        // HTTP/1.x minor versions are compatible, anything else isn't
+        if !request.version.starts_with("HTTP/1.") {
+            return write_error(
+                &mut stream,
+                505,
+                "HTTP version not supported",
+                config.server.server_tokens,
+            );
+        }
+
+        // chunked bodies are checked against the limit while they are read,
+        // announced lengths right away
+        let body_limit = config.server.body_limit();
+        if request.is_chunked() {
+            reader.start_chunked_body(body_limit);
+        } else {
+            let len = request.content_length().unwrap_or(0);
+            if len > body_limit {
+                NebulaError::BodyTooLarge.to_response().write_to(
+                    &mut stream,
+                    None,
+                    false,
+                    config.server.server_tokens,
+                )?;
+                return Ok(());
+            }
+            reader.start_body(len);
+        }
+        // the only expectation there is, anything else can't be met
+        if request.version != "HTTP/1.0" && request.header("expect").is_some() {
+            if !request.expects_continue() {
+                return write_error(
+                    &mut stream,
+                    417,
+                    "Expectation failed",
+                    config.server.server_tokens,
+                );
+            }
+            reader.expect_continue();
+        }
+
+        let keep_alive = keep_alive_enabled
+            && !state.is_shutting_down()
+            && requests_served < config.server.max_requests_per_connection
+            && request.wants_keep_alive();
+
+        // the handler may decide to close the connection after all
+        if !router::handle_request(
+            &mut stream,
+            &mut reader,
+            &request,
+            config,
+            state,
+            &connection,
+            keep_alive,
+        )? {
+            return Ok(());
+        }
+
+        // skip an unread body so the next request starts at the right byte
+        if !reader.discard_body(&mut stream, MAX_DISCARDED_BODY)? {
+            return Ok(());
+        }
+    }
+}
@@ -0,0 +1,82 @@
+//! Links that expire: below a `[[mount]]` with a `secret`, a request needs
+//! `?expires=<unix time>&sig=<signature>` with an HMAC-SHA256 of both over
+//! the path, made with `http-nebula sign`. Files that are otherwise private
+//! can be shared that way without handing out a password.
+
+use crate::config::NebulaConfig;
+use crate::uri;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use hmac::{Hmac, KeyInit, Mac};
+use sha2::Sha256;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Why a request for a signed path is refused.
+pub enum Refusal {
+    /// No signature, or one that doesn't fit the path and time.
+    Invalid,
+    Expired,
+}
+
+/// Why [`sign_url`] can't make a link.
+#[derive(Debug)]
+pub enum Unsignable {
+    /// The path has a malformed escape or a NUL byte, no request for it
+    /// gets that far.
+    InvalidPath,
+    /// The path isn't below a mount with a secret.
+    NoSecret,
+}
+
+/// `path` with the query that lets it through until `expires`, in seconds
+/// since the epoch. The path is normalized like request paths are, so
+/// `private/x` and `/private//x` sign `/private/x`.
+pub fn sign_url(config: &NebulaConfig, path: &str, expires: u64) -> Result<String, Unsignable> {
+    let path = uri::normalize_path(path).ok_or(Unsignable::InvalidPath)?;
+    let secret = config.mount_secret(&path).ok_or(Unsignable::NoSecret)?;
+    Ok(format!(
+        "{}?expires={}&sig={}",
+        uri::percent_encode_path(&path),
+        expires,
+        URL_SAFE_NO_PAD.encode(signature(secret, &path, expires).finalize().into_bytes())
+    ))
+}
+
+/// Checks the `expires` and `sig` of `query` for the decoded `path`, other
+/// parameters are left alone.
+pub fn verify(secret: &str, path: &str, query: Option<&str>) -> Result<(), Refusal> {
+    let param = |name: &str| {
+        query?
+            .split('&')
+            .filter_map(|pair| pair.split_once('='))
+            .find(|(key, _)| *key == name)
+            .map(|(_, value)| value)
+    };
+    let expires = param("expires")
+        .and_then(|expires| expires.parse::<u64>().ok())
+        .ok_or(Refusal::Invalid)?;
+    let sig = param("sig")
+        .and_then(|sig| URL_SAFE_NO_PAD.decode(sig).ok())
+        .ok_or(Refusal::Invalid)?;
+    // compared in constant time, so the signature can't be guessed byte by
+    // byte
+    signature(secret, path, expires)
+        .verify_slice(&sig)
+        .map_err(|_| Refusal::Invalid)?;
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    if now > expires {
+        return Err(Refusal::Expired);
+    }
+    Ok(())
+}
+
+fn signature(secret: &str, path: &str, expires: u64) -> Hmac<Sha256> {
+    // keys of any length are fine for HMAC
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("any key length");
+    mac.update(format!("{}\n{}", expires, path).as_bytes());
+    mac
+}
@@ -0,0 +1,297 @@
+use crate::config::{self, NebulaConfig, ServerConfig};
+use crate::digest::Digests;
+use crate::error::NebulaError;
+use crate::events::{Broadcast, Event, EventStream};
+use crate::file_cache::FileCache;
+use crate::logging::AccessLog;
+use crate::metrics::Metrics;
+use crate::middleware::Middleware;
+use crate::open_files::OpenFiles;
+use crate::overrides::OverrideCache;
+use crate::rate_limit::RateLimiter;
+use crate::router::Router;
+use crate::telemetry::Exporter;
+use crate::upstream::Upstreams;
+use std::collections::HashMap;
+use std::io;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, OnceLock, RwLock};
+use std::time::{Duration, Instant};
+
+/// A change made to every config the server runs with, loaded or reloaded.
+pub type ConfigOverride = Box<dyn Fn(&mut NebulaConfig) + Send + Sync>;
+
+/// State shared by every connection. The config and access log sit behind
+/// locks so a reload can swap them while connections keep the snapshot they
+/// started with.
+pub struct ServerState {
+    config: RwLock<Arc<NebulaConfig>>,
+    access_log: RwLock<Arc<AccessLog>>,
+    // re-read by reloads, with the overrides applied on top
+    config_file: Option<PathBuf>,
+    overrides: Vec<ConfigOverride>,
+    router: Router,
+    middleware: Vec<Arc<dyn Middleware>>,
+    shutting_down: AtomicBool,
+    // accept loops that haven't noticed the shutdown yet
+    accepting: AtomicUsize,
+    // accepted connections that haven't been closed yet, queued ones included
+    active_connections: AtomicUsize,
+    // the same, per client IP
+    connections_per_ip: Mutex<HashMap<IpAddr, usize>>,
+    upstreams: Upstreams,
+    file_cache: FileCache,
+    open_files: OpenFiles,
+    // content hashes for ETags
+    digests: Digests,
+    directory_overrides: OverrideCache,
+    rate_limiter: RateLimiter,
+    metrics: Metrics,
+    // from the config the server started with, started by `run` once the
+    // processes are forked, since the thread would not survive the fork
+    telemetry: OnceLock<Arc<Exporter>>,
+    started: Instant,
+    // clients of /_nebula/events
+    reload_events: Broadcast,
+    // why the last reload failed, cleared by one that works
+    last_reload_error: Mutex<Option<String>>,
+}
+
+impl ServerState {
+    pub fn new(
+        config: NebulaConfig,
+        config_file: Option<PathBuf>,
+        overrides: Vec<ConfigOverride>,
+        router: Router,
+        middleware: Vec<Arc<dyn Middleware>>,
+    ) -> Result<ServerState, NebulaError> {
+        let access_log = AccessLog::open(&config.logging)
+            .map_err(|e| NebulaError::io("Failed to open access log", e))?;
+
+        Ok(ServerState {
+            config: RwLock::new(Arc::new(config)),
+            access_log: RwLock::new(Arc::new(access_log)),
+            config_file,
+            overrides,
+            router,
+            middleware,
+            shutting_down: AtomicBool::new(false),
+            accepting: AtomicUsize::new(0),
+            active_connections: AtomicUsize::new(0),
+            connections_per_ip: Mutex::new(HashMap::new()),
+            upstreams: Upstreams::default(),
+            file_cache: FileCache::default(),
+            open_files: OpenFiles::default(),
+            digests: Digests::default(),
+            directory_overrides: OverrideCache::default(),
+            rate_limiter: RateLimiter::default(),
+            metrics: Metrics::default(),
+            telemetry: OnceLock::new(),
+            started: Instant::now(),
+            reload_events: Broadcast::default(),
+            last_reload_error: Mutex::new(None),
+        })
+    }
+
+    pub fn config(&self) -> Arc<NebulaConfig> {
+        let config = self.config.read().unwrap_or_else(|e| e.into_inner());
+        Arc::clone(&config)
+    }
+
+    pub fn access_log(&self) -> Arc<AccessLog> {
+        let access_log = self.access_log.read().unwrap_or_else(|e| e.into_inner());
+        Arc::clone(&access_log)
+    }
+
+    pub fn router(&self) -> &Router {
+        &self.router
+    }
+
+    pub fn middleware(&self) -> &[Arc<dyn Middleware>] {
+        &self.middleware
+    }
+
+    pub fn upstreams(&self) -> &Upstreams {
+        &self.upstreams
+    }
+
+    pub fn file_cache(&self) -> &FileCache {
+        &self.file_cache
+    }
+
+    pub fn open_files(&self) -> &OpenFiles {
+        &self.open_files
+    }
+
+    pub fn digests(&self) -> &Digests {
+        &self.digests
+    }
+
+    pub fn directory_overrides(&self) -> &OverrideCache {
+        &self.directory_overrides
+    }
+
+    pub fn rate_limiter(&self) -> &RateLimiter {
+        &self.rate_limiter
+    }
+
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    pub fn telemetry(&self) -> Option<&Arc<Exporter>> {
+        self.telemetry.get()
+    }
+
+    /// Starts exporting spans when the config asks for it. Has to happen in
+    /// every process, after forking.
+    pub fn start_telemetry(&self) {
+        let config = self.config();
+        if config.telemetry.enabled {
+            self.telemetry
+                .get_or_init(|| Exporter::start(&config.telemetry));
+        }
+    }
+
+    pub fn uptime(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    /// A stream of the events sent for reloads from now on.
+    pub fn reload_events(&self) -> EventStream {
+        self.reload_events.subscribe()
+    }
+
+    /// Re-reads the config file and reopens the access log. On any error
+    /// the running configuration stays in place.
+    ///
+    /// Listener address, port and worker count are only read at startup.
+    pub fn reload(&self) -> Result<(), NebulaError> {
+        let reloaded = self.try_reload();
+        let error = reloaded.as_ref().err().map(|e| e.to_string());
+        let event = match &error {
+            None => Event::new("ok").event("reload"),
+            Some(error) => Event::new(error.as_str()).event("reload_failed"),
+        };
+        self.reload_events.send(event);
+        *self.lock_last_reload_error() = error;
+        reloaded
+    }
+
+    pub fn last_reload_error(&self) -> Option<String> {
+        self.lock_last_reload_error().clone()
+    }
+
+    fn lock_last_reload_error(&self) -> std::sync::MutexGuard<'_, Option<String>> {
+        self.last_reload_error
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn try_reload(&self) -> Result<(), NebulaError> {
+        let Some(config_file) = &self.config_file else {
+            return Err(NebulaError::io(
+                "Reload failed",
+                io::Error::new(io::ErrorKind::NotFound, "no config file to reload from"),
+            ));
+        };
+        let mut config = config::try_load_config(config_file)?;
+        for apply in &self.overrides {
+            apply(&mut config);
+        }
+
+        let access_log = AccessLog::open(&config.logging)
+            .map_err(|e| NebulaError::io("Failed to open access log", e))?;
+
+        *self.config.write().unwrap_or_else(|e| e.into_inner()) = Arc::new(config);
+        *self.access_log.write().unwrap_or_else(|e| e.into_inner()) = Arc::new(access_log);
+
+        tracing::info!("Configuration reloaded from {}", config_file.display());
+        Ok(())
+    }
+
+    /// Marks the server as draining. Returns whether a shutdown was already
+    /// in progress.
+    pub fn begin_shutdown(&self) -> bool {
+        self.shutting_down.swap(true, Ordering::SeqCst)
+    }
+
+    pub fn is_shutting_down(&self) -> bool {
+        self.shutting_down.load(Ordering::SeqCst)
+    }
+
+    pub fn accepting(&self) -> usize {
+        self.accepting.load(Ordering::SeqCst)
+    }
+
+    pub fn set_accepting(&self, loops: usize) {
+        self.accepting.store(loops, Ordering::SeqCst);
+    }
+
+    pub fn stopped_accepting(&self) {
+        self.accepting.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    pub fn active_connections(&self) -> usize {
+        self.active_connections.load(Ordering::SeqCst)
+    }
+
+    /// Counts a connection from `ip` as active until the returned guard is
+    /// dropped. Returns `None` when that would exceed `max_connections` or
+    /// `max_connections_per_ip`.
+    pub fn track_connection(
+        self: &Arc<Self>,
+        ip: Option<IpAddr>,
+        limits: &ServerConfig,
+    ) -> Option<ConnectionGuard> {
+        let active = self.active_connections.fetch_add(1, Ordering::SeqCst);
+        if limits.max_connections > 0 && active >= limits.max_connections {
+            self.active_connections.fetch_sub(1, Ordering::SeqCst);
+            return None;
+        }
+
+        if let Some(ip) = ip {
+            let mut per_ip = self.lock_connections_per_ip();
+            let count = per_ip.entry(ip).or_insert(0);
+            if limits.max_connections_per_ip > 0 && *count >= limits.max_connections_per_ip {
+                drop(per_ip);
+                self.active_connections.fetch_sub(1, Ordering::SeqCst);
+                return None;
+            }
+            *count += 1;
+        }
+
+        Some(ConnectionGuard {
+            state: Arc::clone(self),
+            ip,
+        })
+    }
+
+    fn lock_connections_per_ip(&self) -> std::sync::MutexGuard<'_, HashMap<IpAddr, usize>> {
+        self.connections_per_ip
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+pub struct ConnectionGuard {
+    state: Arc<ServerState>,
+    ip: Option<IpAddr>,
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        if let Some(ip) = self.ip {
+            let mut per_ip = self.state.lock_connections_per_ip();
+            if let Some(count) = per_ip.get_mut(&ip) {
+                *count -= 1;
+                if *count == 0 {
+                    per_ip.remove(&ip);
+                }
+            }
+        }
+        self.state.active_connections.fetch_sub(1, Ordering::SeqCst);
+    }
+}
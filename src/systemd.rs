@@ -0,0 +1,84 @@
+//! systemd integration: sockets handed over by socket activation, so
+//! restarts don't refuse a single connection, and the sd_notify messages
+//! a `Type=notify` unit reports its status with.
+
+use crate::listener::Listener;
+use std::io;
+
+/// The listening sockets systemd passed in, empty when the process wasn't
+/// socket activated. The variables are removed afterwards so they aren't
+/// mistaken as meant for a process started from here.
+#[cfg(unix)]
+pub fn listen_fds() -> io::Result<Vec<Listener>> {
+    use std::env;
+
+    // the first passed descriptor, after stdin, stdout and stderr
+    const LISTEN_FDS_START: i32 = 3;
+
+    // LISTEN_PID guards against variables inherited from a parent that
+    // was activated itself
+    let for_us = env::var("LISTEN_PID").is_ok_and(|pid| pid == std::process::id().to_string());
+    let count = env::var("LISTEN_FDS")
+        .ok()
+        .and_then(|n| n.parse::<i32>().ok());
+    // changing the environment is only sound while the process has a
+    // single thread, which it has while the server is being built
+    for name in ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+        env::remove_var(name);
+    }
+    let Some(count) = count.filter(|_| for_us) else {
+        return Ok(Vec::new());
+    };
+
+    // SAFETY: each OwnedFd has to be the only owner of its descriptor.
+    // LISTEN_PID says systemd passed 3..3+count to this very process, the
+    // variables are gone now so nothing reads them a second time, and
+    // nothing else wraps these numbers: the upgrade sockets that also start
+    // at 3 are only taken when systemd passed none
+    (LISTEN_FDS_START..LISTEN_FDS_START + count)
+        .map(|fd| unsafe { crate::listener::from_raw_fd(fd) })
+        .collect()
+}
+
+#[cfg(not(unix))]
+pub fn listen_fds() -> io::Result<Vec<Listener>> {
+    Ok(Vec::new())
+}
+
+/// Sends `state`, e.g. `READY=1`, to the service manager. Does nothing
+/// when not running under systemd, and failures are only reported since
+/// serving matters more than the unit status.
+pub fn notify(state: &str) {
+    #[cfg(unix)]
+    if let Err(e) = send(state) {
+        crate::error::NebulaError::io("Failed to notify systemd", e).log();
+    }
+    #[cfg(not(unix))]
+    let _ = state;
+}
+
+#[cfg(unix)]
+fn send(state: &str) -> io::Result<()> {
+    use std::os::unix::net::UnixDatagram;
+
+    let Some(path) = std::env::var_os("NOTIFY_SOCKET") else {
+        return Ok(());
+    };
+    let socket = UnixDatagram::unbound()?;
+    let path = path.to_string_lossy();
+    // `@` stands for a socket in the abstract namespace
+    if let Some(name) = path.strip_prefix('@') {
+        #[cfg(target_os = "linux")]
+        {
+            use std::os::linux::net::SocketAddrExt;
+            let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+            return socket.send_to_addr(state.as_bytes(), &addr).map(|_| ());
+        }
+        #[cfg(not(target_os = "linux"))]
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("abstract socket {} needs linux", name),
+        ));
+    }
+    socket.send_to(state.as_bytes(), &*path).map(|_| ())
+}
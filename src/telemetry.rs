@@ -0,0 +1,585 @@
+//! OpenTelemetry traces from `[telemetry]`: a server span per request with
+//! child spans for reading files, compressing and proxying, sent in batches
+//! to an OTLP/HTTP collector as JSON. Requests carrying a W3C `traceparent`
+//! join the caller's trace, and proxied ones pass it on.
+//!
+//! Every request is served on one thread from start to end, so the span
+//! children attach to is kept in a thread local.
+
+use crate::request::Request;
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender, TrySendError};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct TelemetryConfig {
+    pub enabled: bool,
+    // the traces endpoint of an OTLP/HTTP collector, plain http only
+    pub endpoint: String,
+    pub service_name: String,
+    // share of new traces that are recorded, requests that come with a
+    // traceparent follow the caller's decision
+    pub sample_ratio: f64,
+}
+
+impl Default for TelemetryConfig {
+    fn default() -> Self {
+        TelemetryConfig {
+            enabled: false,
+            endpoint: "http://127.0.0.1:4318/v1/traces".to_string(),
+            service_name: "http-nebula".to_string(),
+            sample_ratio: 1.0,
+        }
+    }
+}
+
+// spans waiting for the exporter, more are dropped
+const QUEUE_SIZE: usize = 4096;
+// spans per request to the collector
+const BATCH_SIZE: usize = 512;
+// how long a span may wait for its batch to fill up
+const BATCH_DELAY: Duration = Duration::from_secs(2);
+const EXPORT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The OTLP span kinds used here.
+#[derive(Clone, Copy)]
+pub enum SpanKind {
+    Internal = 1,
+    Server = 2,
+    Client = 3,
+}
+
+enum Value {
+    Str(String),
+    Int(i64),
+}
+
+struct SpanRecord {
+    trace_id: [u8; 16],
+    span_id: [u8; 8],
+    parent: Option<[u8; 8]>,
+    name: String,
+    kind: SpanKind,
+    start: SystemTime,
+    end: SystemTime,
+    attributes: Vec<(&'static str, Value)>,
+    error: bool,
+}
+
+enum Message {
+    Span(SpanRecord),
+    // sends what is queued and answers once it is done
+    Flush(SyncSender<()>),
+}
+
+/// Collects finished spans and exports them from a thread of its own.
+pub struct Exporter {
+    config: TelemetryConfig,
+    sender: SyncSender<Message>,
+}
+
+impl Exporter {
+    pub fn start(config: &TelemetryConfig) -> Arc<Exporter> {
+        let (sender, receiver) = mpsc::sync_channel(QUEUE_SIZE);
+        let export_config = config.clone();
+        thread::spawn(move || export_loop(&export_config, receiver));
+        Arc::new(Exporter {
+            config: config.clone(),
+            sender,
+        })
+    }
+
+    /// Waits up to `timeout` for the queued spans to be sent, before the
+    /// process exits. A queue that stays full only costs the timeout.
+    pub fn flush(&self, timeout: Duration) {
+        let deadline = Instant::now() + timeout;
+        let (done, wait) = mpsc::sync_channel(1);
+        let mut message = Message::Flush(done);
+        loop {
+            match self.sender.try_send(message) {
+                Ok(()) => break,
+                Err(TrySendError::Full(unsent)) if Instant::now() < deadline => {
+                    message = unsent;
+                    thread::sleep(Duration::from_millis(10));
+                }
+                Err(_) => return,
+            }
+        }
+        let _ = wait.recv_timeout(deadline.saturating_duration_since(Instant::now()));
+    }
+
+    fn export(&self, record: SpanRecord) {
+        // a collector that can't keep up loses spans, requests never wait
+        if let Err(TrySendError::Disconnected(_)) = self.sender.try_send(Message::Span(record)) {
+            tracing::warn!("Telemetry exporter stopped, dropping span");
+        }
+    }
+}
+
+#[derive(Clone)]
+struct Active {
+    trace_id: [u8; 16],
+    span_id: [u8; 8],
+    exporter: Arc<Exporter>,
+}
+
+thread_local! {
+    // the innermost open span on this thread
+    static CURRENT: RefCell<Option<Active>> = const { RefCell::new(None) };
+}
+
+struct Open {
+    record: SpanRecord,
+    exporter: Arc<Exporter>,
+    previous: Option<Active>,
+}
+
+/// An open span, recorded when it is dropped. Does nothing when telemetry
+/// is off or the trace isn't sampled.
+pub struct Span {
+    open: RefCell<Option<Open>>,
+}
+
+impl Span {
+    /// The span of a request, joining the trace of its `traceparent`.
+    pub fn server(
+        exporter: Option<&Arc<Exporter>>,
+        request: &Request,
+        remote_addr: Option<SocketAddr>,
+    ) -> Span {
+        let Some(exporter) = exporter else {
+            return Span::none();
+        };
+        let (trace_id, parent, sampled) =
+            match request.header("traceparent").and_then(parse_traceparent) {
+                Some((trace_id, parent, sampled)) => (trace_id, Some(parent), sampled),
+                None => {
+                    let sampled =
+                        (random_u64() as f64 / u64::MAX as f64) < exporter.config.sample_ratio;
+                    (random_trace_id(), None, sampled)
+                }
+            };
+        if !sampled {
+            return Span::none();
+        }
+        let span = Span::start(
+            exporter,
+            trace_id,
+            parent,
+            &request.method,
+            SpanKind::Server,
+        );
+        span.set("http.request.method", request.method.as_str());
+        span.set("url.path", request.path.as_str());
+        if let Some(query) = &request.query {
+            span.set("url.query", query.as_str());
+        }
+        if let Some(addr) = remote_addr {
+            span.set("client.address", addr.ip().to_string());
+        }
+        if let Some(agent) = request.header("user-agent") {
+            span.set("user_agent.original", agent);
+        }
+        span
+    }
+
+    /// A span inside the one that is open on this thread, if any.
+    pub fn child(name: &str, kind: SpanKind) -> Span {
+        let Some(parent) = CURRENT.with(|current| current.borrow().clone()) else {
+            return Span::none();
+        };
+        Span::start(
+            &parent.exporter,
+            parent.trace_id,
+            Some(parent.span_id),
+            name,
+            kind,
+        )
+    }
+
+    fn none() -> Span {
+        Span {
+            open: RefCell::new(None),
+        }
+    }
+
+    fn start(
+        exporter: &Arc<Exporter>,
+        trace_id: [u8; 16],
+        parent: Option<[u8; 8]>,
+        name: &str,
+        kind: SpanKind,
+    ) -> Span {
+        let span_id = random_u64().max(1).to_be_bytes();
+        let active = Active {
+            trace_id,
+            span_id,
+            exporter: Arc::clone(exporter),
+        };
+        let previous = CURRENT.with(|current| current.replace(Some(active)));
+        let now = SystemTime::now();
+        Span {
+            open: RefCell::new(Some(Open {
+                record: SpanRecord {
+                    trace_id,
+                    span_id,
+                    parent,
+                    name: name.to_string(),
+                    kind,
+                    start: now,
+                    end: now,
+                    attributes: Vec::new(),
+                    error: false,
+                },
+                exporter: Arc::clone(exporter),
+                previous,
+            })),
+        }
+    }
+
+    pub fn set(&self, key: &'static str, value: impl Into<AttributeValue>) {
+        if let Some(open) = self.open.borrow_mut().as_mut() {
+            let value = value.into().0;
+            open.record
+                .attributes
+                .retain(|(existing, _)| *existing != key);
+            open.record.attributes.push((key, value));
+        }
+    }
+
+    /// Marks the span as failed.
+    pub fn set_error(&self) {
+        if let Some(open) = self.open.borrow_mut().as_mut() {
+            open.record.error = true;
+        }
+    }
+
+    /// The response status of a server span, 5xx count as errors.
+    pub fn set_status(&self, status: u16) {
+        self.set("http.response.status_code", i64::from(status));
+        if status >= 500 {
+            self.set_error();
+        }
+    }
+}
+
+impl Drop for Span {
+    fn drop(&mut self) {
+        let Some(mut open) = self.open.get_mut().take() else {
+            return;
+        };
+        CURRENT.with(|current| *current.borrow_mut() = open.previous.take());
+        open.record.end = SystemTime::now();
+        open.exporter.export(open.record);
+    }
+}
+
+/// What `Span::set` takes.
+pub struct AttributeValue(Value);
+
+impl From<&str> for AttributeValue {
+    fn from(value: &str) -> Self {
+        AttributeValue(Value::Str(value.to_string()))
+    }
+}
+
+impl From<String> for AttributeValue {
+    fn from(value: String) -> Self {
+        AttributeValue(Value::Str(value))
+    }
+}
+
+impl From<i64> for AttributeValue {
+    fn from(value: i64) -> Self {
+        AttributeValue(Value::Int(value))
+    }
+}
+
+/// The `traceparent` for a request made from the open span, such as a
+/// proxied one.
+pub fn traceparent() -> Option<String> {
+    CURRENT.with(|current| {
+        let current = current.borrow();
+        let active = current.as_ref()?;
+        Some(format!(
+            "00-{}-{}-01",
+            hex(&active.trace_id),
+            hex(&active.span_id)
+        ))
+    })
+}
+
+// `00-<trace id>-<parent id>-<flags>`, later versions may add fields
+fn parse_traceparent(value: &str) -> Option<([u8; 16], [u8; 8], bool)> {
+    let mut parts = value.trim().split('-');
+    let version = parts.next()?;
+    let trace_id = parts.next()?;
+    let parent = parts.next()?;
+    let flags = parts.next()?;
+    if version.len() != 2 || version.eq_ignore_ascii_case("ff") || flags.len() != 2 {
+        return None;
+    }
+    if version == "00" && parts.next().is_some() {
+        return None;
+    }
+    u8::from_str_radix(version, 16).ok()?;
+    let trace_id: [u8; 16] = unhex(trace_id)?.try_into().ok()?;
+    let parent: [u8; 8] = unhex(parent)?.try_into().ok()?;
+    let flags = u8::from_str_radix(flags, 16).ok()?;
+    // all zeros is invalid for both ids
+    if trace_id == [0; 16] || parent == [0; 8] {
+        return None;
+    }
+    Some((trace_id, parent, flags & 1 == 1))
+}
+
+fn random_u64() -> u64 {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u64(COUNTER.fetch_add(1, Ordering::Relaxed));
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    hasher.write_u128(nanos);
+    hasher.finish()
+}
+
+fn random_trace_id() -> [u8; 16] {
+    let mut id = [0; 16];
+    id[..8].copy_from_slice(&random_u64().to_be_bytes());
+    id[8..].copy_from_slice(&random_u64().max(1).to_be_bytes());
+    id
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn unhex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) || !text.is_ascii() {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&text[i..i + 2], 16).ok())
+        .collect()
+}
+
+fn export_loop(config: &TelemetryConfig, receiver: Receiver<Message>) {
+    let mut batch = Vec::new();
+    let mut deadline = Instant::now() + BATCH_DELAY;
+    // reported once until an export works again
+    let mut failing = false;
+    loop {
+        let timeout = deadline.saturating_duration_since(Instant::now());
+        let flushed = match receiver.recv_timeout(timeout) {
+            Ok(Message::Span(record)) => {
+                batch.push(record);
+                if batch.len() < BATCH_SIZE {
+                    continue;
+                }
+                None
+            }
+            Ok(Message::Flush(done)) => Some(done),
+            Err(RecvTimeoutError::Timeout) => None,
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
+        if !batch.is_empty() {
+            match send(config, &batch) {
+                Ok(()) => failing = false,
+                Err(e) if !failing => {
+                    tracing::warn!("Failed to export spans to {}: {}", config.endpoint, e);
+                    failing = true;
+                }
+                Err(_) => {}
+            }
+            batch.clear();
+        }
+        if let Some(done) = flushed {
+            let _ = done.send(());
+        }
+        deadline = Instant::now() + BATCH_DELAY;
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ExportRequest<'a> {
+    resource_spans: [ResourceSpans<'a>; 1],
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ResourceSpans<'a> {
+    resource: Resource<'a>,
+    scope_spans: [ScopeSpans; 1],
+}
+
+#[derive(Serialize)]
+struct Resource<'a> {
+    attributes: [KeyValue<'a>; 1],
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ScopeSpans {
+    scope: Scope,
+    spans: Vec<OtlpSpan>,
+}
+
+#[derive(Serialize)]
+struct Scope {
+    name: &'static str,
+    version: &'static str,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct OtlpSpan {
+    trace_id: String,
+    span_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    parent_span_id: Option<String>,
+    name: String,
+    kind: u8,
+    // 64 bit numbers are strings in OTLP's JSON
+    start_time_unix_nano: String,
+    end_time_unix_nano: String,
+    attributes: Vec<KeyValue<'static>>,
+    status: Status,
+}
+
+#[derive(Serialize)]
+struct Status {
+    // 0 unset, 2 error
+    code: u8,
+}
+
+#[derive(Serialize)]
+struct KeyValue<'a> {
+    key: &'a str,
+    value: AnyValue,
+}
+
+#[derive(Serialize)]
+enum AnyValue {
+    #[serde(rename = "stringValue")]
+    String(String),
+    #[serde(rename = "intValue")]
+    Int(String),
+}
+
+fn unix_nanos(time: SystemTime) -> String {
+    let nanos = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    nanos.to_string()
+}
+
+fn to_otlp(record: &SpanRecord) -> OtlpSpan {
+    OtlpSpan {
+        trace_id: hex(&record.trace_id),
+        span_id: hex(&record.span_id),
+        parent_span_id: record.parent.map(|parent| hex(&parent)),
+        name: record.name.clone(),
+        kind: record.kind as u8,
+        start_time_unix_nano: unix_nanos(record.start),
+        end_time_unix_nano: unix_nanos(record.end),
+        attributes: record
+            .attributes
+            .iter()
+            .map(|(key, value)| KeyValue {
+                key,
+                value: match value {
+                    Value::Str(text) => AnyValue::String(text.clone()),
+                    Value::Int(number) => AnyValue::Int(number.to_string()),
+                },
+            })
+            .collect(),
+        status: Status {
+            code: if record.error { 2 } else { 0 },
+        },
+    }
+}
+
+// one POST per batch on a connection of its own
+fn send(config: &TelemetryConfig, batch: &[SpanRecord]) -> io::Result<()> {
+    let export = ExportRequest {
+        resource_spans: [ResourceSpans {
+            resource: Resource {
+                attributes: [KeyValue {
+                    key: "service.name",
+                    value: AnyValue::String(config.service_name.clone()),
+                }],
+            },
+            scope_spans: [ScopeSpans {
+                scope: Scope {
+                    name: "nebula",
+                    version: env!("CARGO_PKG_VERSION"),
+                },
+                spans: batch.iter().map(to_otlp).collect(),
+            }],
+        }],
+    };
+    let body = serde_json::to_vec(&export).map_err(io::Error::other)?;
+
+    let (authority, path) = split_endpoint(&config.endpoint)?;
+    let addr = authority
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::AddrNotAvailable, "no collector address"))?;
+    let mut stream = TcpStream::connect_timeout(&addr, EXPORT_TIMEOUT)?;
+    stream.set_read_timeout(Some(EXPORT_TIMEOUT))?;
+    stream.set_write_timeout(Some(EXPORT_TIMEOUT))?;
+    write!(
+        stream,
+        "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\n\
+         Content-Length: {}\r\nConnection: close\r\n\r\n",
+        path,
+        authority,
+        body.len()
+    )?;
+    stream.write_all(&body)?;
+
+    let mut status_line = String::new();
+    BufReader::new(stream).read_line(&mut status_line)?;
+    match status_line.split(' ').nth(1) {
+        Some(status) if status.starts_with('2') => Ok(()),
+        _ => Err(io::Error::other(format!(
+            "collector answered {}",
+            status_line.trim_end()
+        ))),
+    }
+}
+
+/// Splits `http://host:port/path` into the authority, with port 80 added
+/// when there is none, and the path.
+pub fn split_endpoint(endpoint: &str) -> io::Result<(String, &str)> {
+    let rest = endpoint.strip_prefix("http://").ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "the endpoint has to be an http:// URL",
+        )
+    })?;
+    let (authority, path) = match rest.find('/') {
+        Some(slash) => rest.split_at(slash),
+        None => (rest, "/"),
+    };
+    let authority = if authority.contains(':') {
+        authority.to_string()
+    } else {
+        format!("{}:80", authority)
+    };
+    Ok((authority, path))
+}
@@ -0,0 +1,80 @@
+//! Bandwidth caps from `[limits]`, enforced by pacing the writes of a
+//! response so one big download can't take the whole uplink.
+
+use serde::Deserialize;
+use std::io::{self, Write};
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[derive(Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct LimitsConfig {
+    // KiB per second for each response, unlimited when unset
+    pub rate_kbps: Option<u64>,
+    // own caps for some prefixes, the longest matching prefix wins
+    pub paths: Vec<PathRate>,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct PathRate {
+    pub prefix: String,
+    // 0 lifts the global cap below the prefix
+    pub rate_kbps: u64,
+}
+
+impl LimitsConfig {
+    /// The cap for responses to `path` in bytes per second.
+    pub fn bytes_per_second(&self, path: &str) -> Option<u64> {
+        let rate = match self
+            .paths
+            .iter()
+            .filter(|rule| path.starts_with(&rule.prefix))
+            .max_by_key(|rule| rule.prefix.len())
+        {
+            Some(rule) => rule.rate_kbps,
+            None => self.rate_kbps?,
+        };
+        (rate > 0).then(|| rate.saturating_mul(1024))
+    }
+}
+
+// how far ahead of the schedule a writer may get, small enough that the
+// rate holds even for short responses
+const SLICES_PER_SECOND: u64 = 20;
+
+/// A writer that sleeps whenever it got ahead of `bytes_per_second`.
+pub struct Throttled<W> {
+    inner: W,
+    bytes_per_second: u64,
+    started: Instant,
+    written: u64,
+}
+
+impl<W: Write> Throttled<W> {
+    pub fn new(inner: W, bytes_per_second: u64) -> Self {
+        Throttled {
+            inner,
+            bytes_per_second,
+            started: Instant::now(),
+            written: 0,
+        }
+    }
+}
+
+impl<W: Write> Write for Throttled<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let slice = (self.bytes_per_second / SLICES_PER_SECOND).max(512) as usize;
+        let n = self.inner.write(&buf[..buf.len().min(slice)])?;
+        self.written += n as u64;
+
+        let due = Duration::from_secs_f64(self.written as f64 / self.bytes_per_second as f64);
+        if let Some(ahead) = due.checked_sub(self.started.elapsed()) {
+            thread::sleep(ahead);
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
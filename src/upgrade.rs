@@ -0,0 +1,167 @@
+//! Binary upgrades without downtime: on SIGUSR2 the running server starts
+//! the binary again with its listening sockets, waits until the new process
+//! is serving and then drains its own connections and exits. Connections
+//! keep queueing in the shared sockets the whole time.
+
+use crate::listener::{self, Listener};
+use std::env;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::process::CommandExt;
+use std::process::{Child, Command};
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::time::Duration;
+
+// the count of sockets passed in, they start at descriptor 3 like with
+// systemd
+const LISTEN_FDS: &str = "NEBULA_LISTEN_FDS";
+// where the new process reports that it is serving
+const READY_FD: &str = "NEBULA_READY_FD";
+
+/// Whether this process was started by an upgrade. Only meaningful until
+/// the server is built, which takes the variables.
+pub fn is_upgrade() -> bool {
+    env::var_os(LISTEN_FDS).is_some()
+}
+
+// the pipe from READY_FD, taken along with the sockets while there is only
+// one thread
+static READY: AtomicI32 = AtomicI32::new(-1);
+
+/// The sockets the previous process passed in, empty when this isn't an
+/// upgrade.
+pub fn listen_fds() -> io::Result<Vec<Listener>> {
+    let count = env::var(LISTEN_FDS)
+        .ok()
+        .and_then(|n| n.parse::<RawFd>().ok());
+    let ready = env::var(READY_FD)
+        .ok()
+        .and_then(|fd| fd.parse::<RawFd>().ok());
+    // changing the environment is only sound while there is one thread,
+    // this runs when the server is built and before it starts any
+    env::remove_var(LISTEN_FDS);
+    env::remove_var(READY_FD);
+    if let Some(fd) = ready {
+        // processes started from here must not keep the previous one waiting
+        // SAFETY: fcntl() only sets a flag on the descriptor, one that isn't
+        // open fails with EBADF and is left alone
+        unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) };
+    }
+    READY.store(ready.unwrap_or(-1), Ordering::SeqCst);
+    let Some(count) = count else {
+        return Ok(Vec::new());
+    };
+    // SAFETY: the previous process duplicated the sockets to 3..3+count for
+    // exactly this one, and the variable naming them is gone now, so
+    // nothing else wraps them and each OwnedFd is the only owner
+    (3..3 + count)
+        .map(|fd| unsafe { listener::from_raw_fd(fd) })
+        .collect()
+}
+
+/// Tells the previous process that the sockets are being served, it
+/// starts draining then.
+pub fn ready() {
+    let fd = READY.swap(-1, Ordering::SeqCst);
+    if fd >= 0 {
+        // SAFETY: the swap takes the pipe out of READY, so it is owned here
+        // once. `listen_fds` is the only place that put it there
+        let mut pipe = unsafe { File::from_raw_fd(fd) };
+        let _ = pipe.write_all(b"1");
+    }
+}
+
+/// Starts the binary the server was started with, with the same
+/// arguments, handing it `sockets`. Waits until it is serving and returns
+/// it, or kills it when it isn't ready within `timeout`.
+pub fn start(sockets: &[OwnedFd], timeout: Duration) -> io::Result<Child> {
+    let (reader, writer) = pipe()?;
+    let mut args = env::args_os();
+    let program = args.next().unwrap_or_else(|| "http-nebula".into());
+
+    // the descriptors go to 3, 4, ... and the pipe right after them
+    let mut sources: Vec<RawFd> = sockets.iter().map(AsRawFd::as_raw_fd).collect();
+    sources.push(writer.as_raw_fd());
+    let mut moved = vec![0; sources.len()];
+    let mut command = Command::new(program);
+    command
+        .args(args)
+        .env(LISTEN_FDS, sockets.len().to_string())
+        .env(READY_FD, (3 + sockets.len()).to_string());
+    // SAFETY: between fork and exec the child may only make
+    // async-signal-safe calls, another thread may have held the allocator's
+    // lock. The closure only calls fcntl() and dup2(), writes to `moved`,
+    // which is allocated up front, and builds errors from errno without
+    // allocating. `sources` are open in the parent until spawn returns.
+    // The copies above the targets keep a source from being overwritten
+    // before it is moved, F_DUPFD_CLOEXEC closes them at exec
+    unsafe {
+        command.pre_exec(move || {
+            let above = 3 + sources.len() as RawFd;
+            for (copy, fd) in moved.iter_mut().zip(&sources) {
+                *copy = libc::fcntl(*fd, libc::F_DUPFD_CLOEXEC, above);
+                if *copy == -1 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+            for (target, copy) in (3..).zip(&moved) {
+                if libc::dup2(*copy, target) == -1 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+            Ok(())
+        });
+    }
+    let mut child = command.spawn()?;
+    drop(writer);
+
+    if wait_ready(reader, timeout)? {
+        Ok(child)
+    } else {
+        let _ = child.kill();
+        let _ = child.wait();
+        Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            "the new process exited or didn't start serving",
+        ))
+    }
+}
+
+// true once the byte from `ready` arrived, false when the process exited
+// or the time ran out
+fn wait_ready(mut reader: File, timeout: Duration) -> io::Result<bool> {
+    let mut poll = libc::pollfd {
+        fd: reader.as_raw_fd(),
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    let millis = timeout.as_millis().min(i32::MAX as u128) as i32;
+    // SAFETY: `poll` is a single live pollfd for an fd `reader` owns
+    match unsafe { libc::poll(&mut poll, 1, millis) } {
+        -1 => Err(io::Error::last_os_error()),
+        0 => Ok(false),
+        _ => {
+            let mut byte = [0; 1];
+            Ok(reader.read(&mut byte)? == 1)
+        }
+    }
+}
+
+fn pipe() -> io::Result<(File, OwnedFd)> {
+    let mut fds = [0; 2];
+    // SAFETY: pipe() writes two descriptors to the array it is given
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    let [reader, writer] = fds;
+    // SAFETY: pipe() just opened both, nothing else has them
+    let (reader, writer) = unsafe { (File::from_raw_fd(reader), OwnedFd::from_raw_fd(writer)) };
+    for fd in [reader.as_raw_fd(), writer.as_raw_fd()] {
+        // SAFETY: both are owned above and stay open for the call
+        if unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok((reader, writer))
+}
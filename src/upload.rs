@@ -0,0 +1,259 @@
+//! Writable mode, where PUT stores request bodies as files below a public
+//! directory and DELETE removes them again, and the form upload endpoint.
+
+use crate::error::NebulaError;
+use crate::fs::sanitize_path;
+use crate::multipart;
+use crate::response::Response;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::process;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct UploadConfig {
+    pub enabled: bool,
+    // the URL forms are posted to
+    pub path: String,
+    // where uploaded files are stored, not served unless it is below a
+    // public directory
+    pub dir: String,
+    // files over this many bytes fail the whole upload with a 413
+    pub max_file_size: u64,
+    pub max_files: usize,
+    // posts without a user from `[[auth.basic]]` are refused unless this is on
+    pub allow_anonymous: bool,
+}
+
+impl Default for UploadConfig {
+    fn default() -> Self {
+        UploadConfig {
+            enabled: false,
+            path: "/upload".to_string(),
+            dir: "uploads".to_string(),
+            max_file_size: 10 * 1024 * 1024,
+            max_files: 20,
+            allow_anonymous: false,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct StoredFile {
+    // the form field the file came in
+    field: Option<String>,
+    // the name the client sent
+    filename: String,
+    // where it was stored, relative to the upload directory
+    path: String,
+    size: usize,
+}
+
+#[derive(Serialize)]
+struct UploadResult {
+    files: Vec<StoredFile>,
+}
+
+// keeps temp names of concurrent uploads apart
+static UPLOADS: AtomicU64 = AtomicU64::new(0);
+
+/// Stores `body` as the file at `relative` below `root`, creating missing
+/// directories. The body goes to a temp file first and replaces the old
+/// file in one rename, so readers never see half an upload.
+///
+/// Answers 201 for a new file and 204 for a replaced one.
+pub fn put<R: Read>(root: &str, relative: &str, body: &mut R) -> Result<Response, NebulaError> {
+    let relative = sanitize_path(relative);
+    let target = Path::new(root).join(&relative);
+    if relative.is_empty() || target.is_dir() {
+        return Ok(Response::error(409, "Can't replace a directory"));
+    }
+
+    let parent = target.parent().unwrap_or(Path::new(root));
+    // missing directories are only created below an existing one that is
+    // still inside the root
+    let existing = parent.ancestors().find(|dir| dir.exists());
+    if !existing.is_some_and(|dir| inside(root, dir)) {
+        return Ok(Response::error(403, "Forbidden"));
+    }
+    if let Err(e) = fs::create_dir_all(parent) {
+        return match e.kind() {
+            io::ErrorKind::AlreadyExists | io::ErrorKind::NotADirectory => {
+                Ok(Response::error(409, "A parent of this path is a file"))
+            }
+            _ => Err(NebulaError::io(
+                format!("Failed to create {}", parent.display()),
+                e,
+            )),
+        };
+    }
+    if !inside(root, parent) {
+        return Ok(Response::error(403, "Forbidden"));
+    }
+
+    let existed = fs::symlink_metadata(&target).is_ok();
+    let name = target
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    // a dotfile, so it's never served while the upload is running
+    let temp = parent.join(format!(
+        ".{}.{}-{}.upload",
+        name,
+        process::id(),
+        UPLOADS.fetch_add(1, Ordering::Relaxed)
+    ));
+
+    let stored = File::create_new(&temp)
+        .and_then(|mut file| {
+            io::copy(body, &mut file)?;
+            file.sync_all()
+        })
+        .and_then(|_| fs::rename(&temp, &target));
+    if let Err(e) = stored {
+        let _ = fs::remove_file(&temp);
+        return Err(NebulaError::io(
+            format!("Failed to store {}", target.display()),
+            e,
+        ));
+    }
+
+    Ok(Response::new(if existed { 204 } else { 201 }))
+}
+
+/// Removes the file at `relative` below `root`. Directories are only
+/// removed, with everything in them, when `recursive` is set.
+pub fn delete(root: &str, relative: &str, recursive: bool) -> Result<Response, NebulaError> {
+    let relative = sanitize_path(relative);
+    let target = Path::new(root).join(&relative);
+    let Ok(metadata) = fs::symlink_metadata(&target) else {
+        return Ok(Response::error(404, "Page not found"));
+    };
+    if relative.is_empty() || (metadata.is_dir() && !recursive) {
+        return Ok(Response::error(409, "Can't delete a directory"));
+    }
+    if !inside(root, target.parent().unwrap_or(Path::new(root))) {
+        return Ok(Response::error(403, "Forbidden"));
+    }
+
+    let removed = if metadata.is_dir() {
+        fs::remove_dir_all(&target)
+    } else {
+        fs::remove_file(&target)
+    };
+    removed.map_err(|e| NebulaError::io(format!("Failed to delete {}", target.display()), e))?;
+    Ok(Response::new(204))
+}
+
+/// Stores the files of a `multipart/form-data` body in the upload
+/// directory and answers with a JSON list of them. Nothing is stored when
+/// any file is over the limit. Existing files are never replaced, a
+/// number is added to the name instead.
+pub fn form_upload(
+    config: &UploadConfig,
+    content_type: Option<&str>,
+    body: &[u8],
+) -> Result<Response, NebulaError> {
+    let Some(boundary) = content_type.and_then(multipart::boundary) else {
+        return Ok(Response::error(415, "Expected multipart/form-data"));
+    };
+    let parts = multipart::parse(body, &boundary)?;
+    let files: Vec<_> = parts
+        .iter()
+        .filter_map(|part| Some((part, part.filename.as_deref()?)))
+        .collect();
+
+    if files.len() > config.max_files {
+        return Ok(Response::error(413, "Too many files"));
+    }
+    if files
+        .iter()
+        .any(|(part, _)| part.data.len() as u64 > config.max_file_size)
+    {
+        return Ok(Response::error(413, "File too large"));
+    }
+
+    fs::create_dir_all(&config.dir)
+        .map_err(|e| NebulaError::io(format!("Failed to create {}", config.dir), e))?;
+    let mut stored = Vec::new();
+    for (part, filename) in files {
+        let name = store_new(&config.dir, &sanitize_filename(filename), part.data)?;
+        stored.push(StoredFile {
+            field: part.name.clone(),
+            filename: filename.to_string(),
+            path: name,
+            size: part.data.len(),
+        });
+    }
+
+    let mut response = Response::json(&UploadResult { files: stored });
+    response.set_status(201);
+    Ok(response)
+}
+
+// writes `data` to a file named like `name` that doesn't exist yet in
+// `dir`, `a.txt`, then `a-1.txt`, `a-2.txt` and so on
+fn store_new(dir: &str, name: &str, data: &[u8]) -> Result<String, NebulaError> {
+    let (stem, extension) = match name.rsplit_once('.') {
+        Some((stem, extension)) if !stem.is_empty() => (stem, Some(extension)),
+        _ => (name, None),
+    };
+    for n in 0.. {
+        let candidate = match (n, extension) {
+            (0, _) => name.to_string(),
+            (n, Some(extension)) => format!("{}-{}.{}", stem, n, extension),
+            (n, None) => format!("{}-{}", stem, n),
+        };
+        let path = Path::new(dir).join(&candidate);
+        let created = File::create_new(&path);
+        match created {
+            Ok(mut file) => {
+                return file.write_all(data).map(|_| candidate).map_err(|e| {
+                    NebulaError::io(format!("Failed to store {}", path.display()), e)
+                });
+            }
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(e) => {
+                return Err(NebulaError::io(
+                    format!("Failed to store {}", path.display()),
+                    e,
+                ))
+            }
+        }
+    }
+    unreachable!("ran out of file names")
+}
+
+// the last component of a client supplied file name, reduced to characters
+// that are safe on every filesystem and never hidden
+fn sanitize_filename(filename: &str) -> String {
+    let name = filename.rsplit(['/', '\\']).next().unwrap_or("");
+    let name: String = name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_') {
+                c
+            } else {
+                '_'
+            }
+        })
+        .take(200)
+        .collect();
+    let name = name.trim_start_matches('.');
+    if name.is_empty() {
+        "upload".to_string()
+    } else {
+        name.to_string()
+    }
+}
+
+// writes never follow symlinks out of the root, whatever follow_symlinks says
+pub fn inside(root: &str, dir: &Path) -> bool {
+    match (fs::canonicalize(root), fs::canonicalize(dir)) {
+        (Ok(root), Ok(dir)) => dir.starts_with(root),
+        _ => false,
+    }
+}
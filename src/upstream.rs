@@ -0,0 +1,170 @@
+//! Upstream selection and health checks for proxy rules.
+
+use crate::proxy::{self, Balance, ProxyConfig};
+use crate::state::ServerState;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Open connection counts and health per upstream and a round-robin cursor
+/// per proxy rule. Kept outside the config so the counts survive a reload.
+#[derive(Default)]
+pub struct Upstreams {
+    inner: Mutex<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    // keyed by upstream address, shared by every rule that points at it
+    active: HashMap<String, usize>,
+    // keyed by rule prefix
+    cursors: HashMap<String, usize>,
+    // consecutive failed health checks per upstream address
+    failures: HashMap<String, u32>,
+    // upstreams taken out of the rotation by failed health checks
+    unhealthy: Vec<String>,
+}
+
+impl Upstreams {
+    /// Picks a healthy upstream for `rule` that isn't in `skip` and counts a
+    /// connection against it until the guard is dropped. Returns `None` when
+    /// every upstream is unhealthy, skipped or at its connection limit.
+    pub fn select<'a>(&'a self, rule: &ProxyConfig, skip: &[String]) -> Option<UpstreamGuard<'a>> {
+        let candidates = rule.upstreams();
+        if candidates.is_empty() {
+            return None;
+        }
+
+        let mut inner = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        let has_capacity = |inner: &Inner, upstream: &str| {
+            let available = !skip.iter().any(|skipped| skipped == upstream)
+                && !inner
+                    .unhealthy
+                    .iter()
+                    .any(|unhealthy| unhealthy == upstream);
+            available
+                && (rule.max_connections == 0
+                    || inner.active.get(upstream).copied().unwrap_or(0) < rule.max_connections)
+        };
+
+        let chosen = match rule.balance {
+            Balance::RoundRobin => {
+                let start = inner.cursors.get(&rule.prefix).copied().unwrap_or(0);
+                let index = (0..candidates.len())
+                    .map(|offset| (start + offset) % candidates.len())
+                    .find(|index| has_capacity(&inner, candidates[*index]))?;
+                inner.cursors.insert(rule.prefix.clone(), index + 1);
+                candidates[index]
+            }
+            // ties go to the upstream listed first
+            Balance::LeastConnections => candidates
+                .iter()
+                .copied()
+                .filter(|upstream| has_capacity(&inner, upstream))
+                .min_by_key(|upstream| inner.active.get(*upstream).copied().unwrap_or(0))?,
+        };
+
+        *inner.active.entry(chosen.to_string()).or_insert(0) += 1;
+        Some(UpstreamGuard {
+            upstreams: self,
+            address: chosen.to_string(),
+        })
+    }
+
+    /// Whether health checks left `upstream` in the rotation.
+    pub fn is_healthy(&self, upstream: &str) -> bool {
+        let inner = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        !inner
+            .unhealthy
+            .iter()
+            .any(|unhealthy| unhealthy == upstream)
+    }
+
+    /// Records the result of a health check. `threshold` failures in a row
+    /// mark the upstream unhealthy, a single passing check brings it back.
+    pub fn record_check(&self, upstream: &str, healthy: bool, threshold: u32) {
+        let mut inner = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        let was_healthy = !inner
+            .unhealthy
+            .iter()
+            .any(|unhealthy| unhealthy == upstream);
+
+        if healthy {
+            inner.failures.remove(upstream);
+            if !was_healthy {
+                inner.unhealthy.retain(|unhealthy| unhealthy != upstream);
+                tracing::info!("Upstream {} is healthy again", upstream);
+            }
+            return;
+        }
+
+        let failures = inner.failures.entry(upstream.to_string()).or_insert(0);
+        *failures += 1;
+        let failures = *failures;
+        if was_healthy && failures >= threshold.max(1) {
+            inner.unhealthy.push(upstream.to_string());
+            tracing::warn!(
+                "Upstream {} failed {} health checks, removing it from rotation",
+                upstream,
+                failures
+            );
+        }
+    }
+}
+
+/// Runs the health checks of every proxy rule until the server shuts down.
+/// The config is re-read on every round so reloaded checks take effect.
+pub fn run_health_checks(state: &ServerState) {
+    // upstreams that several rules share are only checked once per interval
+    let mut next_check: HashMap<String, Instant> = HashMap::new();
+
+    while !state.is_shutting_down() {
+        let config = state.config();
+        for rule in &config.proxies {
+            let Some(check) = &rule.health_check else {
+                continue;
+            };
+            for upstream in rule.upstreams() {
+                let now = Instant::now();
+                if next_check.get(upstream).is_some_and(|due| *due > now) {
+                    continue;
+                }
+                next_check.insert(
+                    upstream.to_string(),
+                    now + Duration::from_secs(check.interval.max(1)),
+                );
+
+                let healthy = proxy::probe(upstream, check);
+                state
+                    .upstreams()
+                    .record_check(upstream, healthy, check.unhealthy_threshold);
+            }
+        }
+        thread::sleep(Duration::from_millis(500));
+    }
+}
+
+pub struct UpstreamGuard<'a> {
+    upstreams: &'a Upstreams,
+    address: String,
+}
+
+impl UpstreamGuard<'_> {
+    pub fn address(&self) -> &str {
+        &self.address
+    }
+}
+
+impl Drop for UpstreamGuard<'_> {
+    fn drop(&mut self) {
+        let mut inner = self
+            .upstreams
+            .inner
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        if let Some(active) = inner.active.get_mut(&self.address) {
+            *active = active.saturating_sub(1);
+        }
+    }
+}
@@ -0,0 +1,138 @@
+//! Request path normalization (RFC 3986 sections 2.1 and 5.2.4).
+
+/// Decodes `%XX` escapes. Returns `None` for truncated or non-hex escapes
+/// and for byte sequences that aren't valid UTF-8.
+pub fn percent_decode(input: &str) -> Option<String> {
+    let bytes = input.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            // from_str_radix alone would take a sign, as in `%+1`
+            let hex = bytes.get(i + 1..i + 3)?;
+            if !hex.iter().all(u8::is_ascii_hexdigit) {
+                return None;
+            }
+            let hex = std::str::from_utf8(hex).ok()?;
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+
+    String::from_utf8(decoded).ok()
+}
+
+/// Resolves `.` and `..` segments, never climbing above the root.
+/// `/a/./b/../c/` becomes `/a/c/`.
+pub fn remove_dot_segments(path: &str) -> String {
+    let mut output: Vec<&str> = Vec::new();
+    let mut trailing_slash = false;
+
+    for segment in path.split('/') {
+        trailing_slash = false;
+        match segment {
+            "" | "." => trailing_slash = true,
+            ".." => {
+                output.pop();
+                trailing_slash = true;
+            }
+            _ => output.push(segment),
+        }
+    }
+
+    let mut normalized = format!("/{}", output.join("/"));
+    if trailing_slash && !output.is_empty() {
+        normalized.push('/');
+    }
+    normalized
+}
+
+/// Decodes the path and removes dot segments afterwards, so encoded
+/// traversal like `/%2e%2e/secret` is resolved just like `/../secret`.
+/// NUL bytes are rejected outright since they truncate paths in C APIs.
+pub fn normalize_path(path: &str) -> Option<String> {
+    let decoded = percent_decode(path)?;
+    if decoded.contains('\0') {
+        return None;
+    }
+    Some(remove_dot_segments(&decoded))
+}
+
+/// Escapes everything in a path except unreserved characters and the
+/// slashes between segments, the reverse of [`percent_decode`].
+pub fn percent_encode_path(path: &str) -> String {
+    let mut encoded = String::with_capacity(path.len());
+    for byte in path.bytes() {
+        if byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'.' | b'_' | b'~' | b'/') {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_escapes_in_either_case() {
+        assert_eq!(percent_decode("/a%20b").as_deref(), Some("/a b"));
+        assert_eq!(percent_decode("%2e%2E%2f").as_deref(), Some("../"));
+        assert_eq!(percent_decode("%C3%a9").as_deref(), Some("\u{e9}"));
+    }
+
+    #[test]
+    fn rejects_malformed_escapes() {
+        assert_eq!(percent_decode("/a%"), None);
+        assert_eq!(percent_decode("/a%2"), None);
+        assert_eq!(percent_decode("/a%+1"), None);
+        assert_eq!(percent_decode("/a%-1"), None);
+        assert_eq!(percent_decode("/a%zz"), None);
+    }
+
+    #[test]
+    fn rejects_invalid_utf8() {
+        // an overlong `.`, decoders that accept it let `..` through
+        assert_eq!(percent_decode("/%C0%AE%C0%AE/secret"), None);
+        assert_eq!(percent_decode("/%FF"), None);
+    }
+
+    #[test]
+    fn resolves_encoded_traversal() {
+        assert_eq!(normalize_path("/a/%2e%2e/b").as_deref(), Some("/b"));
+        assert_eq!(
+            normalize_path("/%2e%2e/%2E%2E/etc").as_deref(),
+            Some("/etc")
+        );
+        // a decoded slash separates segments like any other
+        assert_eq!(normalize_path("/a/..%2f..%2fetc").as_deref(), Some("/etc"));
+    }
+
+    #[test]
+    fn never_climbs_above_the_root() {
+        assert_eq!(remove_dot_segments("/.."), "/");
+        assert_eq!(remove_dot_segments("/../../x"), "/x");
+        assert_eq!(remove_dot_segments("/a/./b/../c/"), "/a/c/");
+    }
+
+    #[test]
+    fn rejects_nul_bytes() {
+        assert_eq!(normalize_path("/a%00.html"), None);
+    }
+
+    #[test]
+    fn encodes_what_decoding_restores() {
+        let path = "/a b/%/\u{e9}";
+        assert_eq!(percent_encode_path(path), "/a%20b/%25/%C3%A9");
+        assert_eq!(
+            percent_decode(&percent_encode_path(path)).as_deref(),
+            Some(path)
+        );
+    }
+}
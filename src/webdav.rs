@@ -0,0 +1,354 @@
+//! The part of WebDAV (RFC 4918) that Finder, Explorer and rclone need to
+//! mount the static files: PROPFIND listings and, in writable mode, MKCOL,
+//! COPY, MOVE and locks. Locks are granted but never enforced, clients
+//! only insist on getting one before they write.
+
+use crate::config::NebulaConfig;
+use crate::digest::Digests;
+use crate::error::NebulaError;
+use crate::fs::{is_hidden, sanitize_path, symlinks_permitted};
+use crate::http_date;
+use crate::mime;
+use crate::response::{escape_html, Response};
+use crate::upload::{self, inside};
+use crate::uri;
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::fs::{self, Metadata};
+use std::io;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// keeps lock tokens handed out by this process apart
+static LOCKS: AtomicU64 = AtomicU64::new(0);
+
+/// How far below the requested resource PROPFIND and COPY reach.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Depth {
+    Zero,
+    One,
+    Infinity,
+}
+
+impl Depth {
+    /// A missing or unknown Depth header means infinity.
+    pub fn parse(header: Option<&str>) -> Depth {
+        match header.map(str::trim) {
+            Some("0") => Depth::Zero,
+            Some("1") => Depth::One,
+            _ => Depth::Infinity,
+        }
+    }
+}
+
+/// Answers a PROPFIND on `href` with the same properties for every
+/// resource, whatever the body asked for. Depth infinity is refused like
+/// most servers do, it would walk the whole tree for one request.
+pub fn propfind(
+    root: &str,
+    relative: &str,
+    href: &str,
+    depth: Depth,
+    config: &NebulaConfig,
+    digests: &Digests,
+    locks: bool,
+) -> Response {
+    if depth == Depth::Infinity {
+        return xml_response(
+            403,
+            "<D:error xmlns:D=\"DAV:\"><D:propfind-finite-depth/></D:error>",
+        );
+    }
+
+    let file_path = format!("{}/{}", root, sanitize_path(relative));
+    let content = &config.content;
+    let follow_symlinks = content.follow_symlinks;
+    // the same ETags GET answers with, so clients can tell what changed
+    let etag = |path: &str, metadata: &Metadata| {
+        metadata
+            .is_file()
+            .then(|| digests.etag(&config.etag, path, metadata))
+            .flatten()
+    };
+    let metadata = match fs::metadata(&file_path) {
+        Ok(metadata) if symlinks_permitted(follow_symlinks, root, &file_path) => metadata,
+        _ => return Response::error(404, "Page not found"),
+    };
+
+    let mut xml = String::from("<D:multistatus xmlns:D=\"DAV:\">");
+    let href = if metadata.is_dir() && !href.ends_with('/') {
+        format!("{}/", href)
+    } else {
+        href.to_string()
+    };
+    write_entry(
+        &mut xml,
+        &href,
+        &file_path,
+        &metadata,
+        etag(&file_path, &metadata).as_deref(),
+        &config.mime,
+        locks,
+    );
+
+    if depth == Depth::One && metadata.is_dir() {
+        let mut children: Vec<_> = fs::read_dir(&file_path)
+            .into_iter()
+            .flatten()
+            .filter_map(Result::ok)
+            .filter_map(|entry| entry.file_name().into_string().ok())
+            .filter(|name| !(content.deny_dotfiles && is_hidden(name)))
+            .collect();
+        children.sort();
+
+        for name in children {
+            let child_path = format!("{}/{}", file_path.trim_end_matches('/'), name);
+            if !symlinks_permitted(follow_symlinks, root, &child_path) {
+                continue;
+            }
+            // broken symlinks are left out instead of failing the listing
+            let Ok(metadata) = fs::metadata(&child_path) else {
+                continue;
+            };
+            let slash = if metadata.is_dir() { "/" } else { "" };
+            let child_href = format!("{}{}{}", href, name, slash);
+            write_entry(
+                &mut xml,
+                &child_href,
+                &child_path,
+                &metadata,
+                etag(&child_path, &metadata).as_deref(),
+                &config.mime,
+                locks,
+            );
+        }
+    }
+
+    xml.push_str("</D:multistatus>");
+    xml_response(207, &xml)
+}
+
+/// Creates the directory at `relative`. Its parent has to exist already.
+pub fn mkcol(root: &str, relative: &str) -> Result<Response, NebulaError> {
+    let target = Path::new(root).join(sanitize_path(relative));
+    if fs::symlink_metadata(&target).is_ok() {
+        return Ok(Response::error(405, "Already exists"));
+    }
+    let parent = target.parent().unwrap_or(Path::new(root));
+    if !parent.is_dir() {
+        return Ok(Response::error(409, "Parent directory missing"));
+    }
+    if !inside(root, parent) {
+        return Ok(Response::error(403, "Forbidden"));
+    }
+
+    fs::create_dir(&target)
+        .map_err(|e| NebulaError::io(format!("Failed to create {}", target.display()), e))?;
+    Ok(Response::new(201))
+}
+
+/// Copies or moves `from` to `to`, both relative to `root`. Directories
+/// are copied with everything in them unless `depth` is zero. Answers 201
+/// for a new destination and 204 for one that was replaced, which only
+/// happens with `overwrite`.
+pub fn transfer(
+    root: &str,
+    from: &str,
+    to: &str,
+    overwrite: bool,
+    is_move: bool,
+    depth: Depth,
+) -> Result<Response, NebulaError> {
+    let (from, to) = (sanitize_path(from), sanitize_path(to));
+    let source = Path::new(root).join(&from);
+    let target = Path::new(root).join(&to);
+    let Ok(metadata) = fs::symlink_metadata(&source) else {
+        return Ok(Response::error(404, "Page not found"));
+    };
+    // copies would turn a symlink to somewhere outside into a real file
+    if from.is_empty() || to.is_empty() || (!is_move && metadata.is_symlink()) {
+        return Ok(Response::error(403, "Forbidden"));
+    }
+    // a directory can't end up inside itself
+    if to == from || to.starts_with(&format!("{}/", from)) {
+        return Ok(Response::error(403, "Destination is inside the source"));
+    }
+
+    let parent = target.parent().unwrap_or(Path::new(root));
+    if !parent.is_dir() {
+        return Ok(Response::error(409, "Parent directory missing"));
+    }
+    let parents_inside = [source.parent(), Some(parent)]
+        .into_iter()
+        .flatten()
+        .all(|dir| inside(root, dir));
+    if !parents_inside {
+        return Ok(Response::error(403, "Forbidden"));
+    }
+
+    let existed = fs::symlink_metadata(&target).is_ok();
+    if existed {
+        if !overwrite {
+            return Ok(Response::error(412, "Destination exists"));
+        }
+        upload::delete(root, &to, true)?;
+    }
+
+    let result = if is_move {
+        fs::rename(&source, &target)
+    } else if metadata.is_dir() {
+        copy_dir(&source, &target, depth == Depth::Zero)
+    } else {
+        fs::copy(&source, &target).map(|_| ())
+    };
+    result.map_err(|e| {
+        NebulaError::io(
+            format!(
+                "Failed to copy {} to {}",
+                source.display(),
+                target.display()
+            ),
+            e,
+        )
+    })?;
+    Ok(Response::new(if existed { 204 } else { 201 }))
+}
+
+/// Grants a lock on `relative`, creating an empty file when nothing is
+/// there yet. Refreshes of a lock get a new token, nobody checks them.
+pub fn lock(root: &str, relative: &str, href: &str) -> Result<Response, NebulaError> {
+    let target = Path::new(root).join(sanitize_path(relative));
+    let status = if target.exists() {
+        200
+    } else {
+        let created = upload::put(root, relative, &mut io::empty())?;
+        if created.status() >= 400 {
+            return Ok(created);
+        }
+        201
+    };
+
+    let since_epoch = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let token = format!(
+        "opaquelocktoken:{:016x}-{:x}-{:x}",
+        since_epoch.as_nanos() as u64,
+        std::process::id(),
+        LOCKS.fetch_add(1, Ordering::Relaxed)
+    );
+    // whatever Timeout the client asked for, it gets an hour
+    let xml = format!(
+        "<D:prop xmlns:D=\"DAV:\"><D:lockdiscovery><D:activelock>\
+         <D:locktype><D:write/></D:locktype><D:lockscope><D:exclusive/></D:lockscope>\
+         <D:depth>0</D:depth><D:timeout>Second-3600</D:timeout>\
+         <D:locktoken><D:href>{}</D:href></D:locktoken>\
+         <D:lockroot><D:href>{}</D:href></D:lockroot>\
+         </D:activelock></D:lockdiscovery></D:prop>",
+        token,
+        escape_html(&uri::percent_encode_path(href))
+    );
+    let mut response = xml_response(status, &xml);
+    response
+        .headers_mut()
+        .insert("Lock-Token", &format!("<{}>", token));
+    Ok(response)
+}
+
+/// The path a Destination header points to, it may be a full URL.
+pub fn destination_path(header: &str) -> Option<String> {
+    let header = header.trim();
+    let path = match header.split_once("://") {
+        Some((_, rest)) => rest.find('/').map_or("/", |slash| &rest[slash..]),
+        None => header,
+    };
+    let path = path.split(['?', '#']).next().unwrap_or(path);
+    uri::normalize_path(path)
+}
+
+// one `<D:response>` with the properties of a file or directory
+fn write_entry(
+    xml: &mut String,
+    href: &str,
+    file_path: &str,
+    metadata: &Metadata,
+    etag: Option<&str>,
+    mime_overrides: &HashMap<String, String>,
+    locks: bool,
+) {
+    let name = href
+        .trim_end_matches('/')
+        .rsplit('/')
+        .next()
+        .unwrap_or_default();
+    let _ = write!(
+        xml,
+        "<D:response><D:href>{}</D:href><D:propstat><D:prop>\
+         <D:displayname>{}</D:displayname>",
+        escape_html(&uri::percent_encode_path(href)),
+        escape_html(name)
+    );
+    if metadata.is_dir() {
+        xml.push_str("<D:resourcetype><D:collection/></D:resourcetype>");
+    } else {
+        let _ = write!(
+            xml,
+            "<D:resourcetype/><D:getcontentlength>{}</D:getcontentlength>\
+             <D:getcontenttype>{}</D:getcontenttype>",
+            metadata.len(),
+            escape_html(mime::content_type(file_path, mime_overrides))
+        );
+        if let Some(etag) = etag {
+            let _ = write!(xml, "<D:getetag>{}</D:getetag>", escape_html(etag));
+        }
+    }
+    if let Ok(modified) = metadata.modified() {
+        let created = metadata.created().unwrap_or(modified);
+        let _ = write!(
+            xml,
+            "<D:getlastmodified>{}</D:getlastmodified>\
+             <D:creationdate>{}</D:creationdate>",
+            http_date::format(modified),
+            http_date::format_rfc3339(created)
+        );
+    }
+    if locks {
+        xml.push_str(
+            "<D:supportedlock><D:lockentry><D:lockscope><D:exclusive/></D:lockscope>\
+             <D:locktype><D:write/></D:locktype></D:lockentry></D:supportedlock>",
+        );
+    }
+    xml.push_str("</D:prop><D:status>HTTP/1.1 200 OK</D:status></D:propstat></D:response>");
+}
+
+// copies a directory tree, symlinks are left out so nothing from outside
+// the root is copied into it
+fn copy_dir(from: &Path, to: &Path, shallow: bool) -> io::Result<()> {
+    fs::create_dir(to)?;
+    if shallow {
+        return Ok(());
+    }
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        let target = to.join(entry.file_name());
+        if file_type.is_dir() {
+            copy_dir(&entry.path(), &target, false)?;
+        } else if file_type.is_file() {
+            fs::copy(entry.path(), target)?;
+        }
+    }
+    Ok(())
+}
+
+fn xml_response(status: u16, xml: &str) -> Response {
+    Response::builder()
+        .status(status)
+        .header("Content-Type", "application/xml; charset=utf-8")
+        .body(format!(
+            "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n{}",
+            xml
+        ))
+        .build()
+}
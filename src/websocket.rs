@@ -0,0 +1,323 @@
+//! WebSocket connections (RFC 6455): the opening handshake, frames for
+//! route handlers and tunnels for proxied connections.
+
+use crate::listener::Stream;
+use crate::request::Request;
+use crate::response::Response;
+use base64::Engine;
+use std::io::{self, Cursor, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+// appended to the client's key before hashing, RFC 6455 section 1.3
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+const OP_CONTINUATION: u8 = 0x0;
+const OP_TEXT: u8 = 0x1;
+const OP_BINARY: u8 = 0x2;
+const OP_CLOSE: u8 = 0x8;
+const OP_PING: u8 = 0x9;
+const OP_PONG: u8 = 0xa;
+
+/// A complete message, fragmented ones are joined before they're handed
+/// out.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+}
+
+/// The server end of an upgraded connection, handed to the handlers of
+/// [`Router::websocket`](crate::Router::websocket). Pings are answered
+/// and the closing handshake is done while receiving.
+pub struct WebSocket {
+    reader: io::Chain<Cursor<Vec<u8>>, Stream>,
+    writer: Sender,
+    // larger messages close the connection with 1009
+    max_message: u64,
+    closed: bool,
+}
+
+/// Sends messages on a [`WebSocket`] from another thread.
+#[derive(Clone)]
+pub struct Sender {
+    stream: Arc<Mutex<Stream>>,
+}
+
+impl Sender {
+    pub fn send(&self, message: &Message) -> io::Result<()> {
+        match message {
+            Message::Text(text) => self.frame(OP_TEXT, text.as_bytes()),
+            Message::Binary(data) => self.frame(OP_BINARY, data),
+        }
+    }
+
+    // the lock keeps frames from several threads from interleaving
+    fn frame(&self, opcode: u8, payload: &[u8]) -> io::Result<()> {
+        let mut frame = vec![0x80 | opcode];
+        match payload.len() {
+            len @ 0..=125 => frame.push(len as u8),
+            len @ 126..=0xffff => {
+                frame.push(126);
+                frame.extend_from_slice(&(len as u16).to_be_bytes());
+            }
+            len => {
+                frame.push(127);
+                frame.extend_from_slice(&(len as u64).to_be_bytes());
+            }
+        }
+        frame.extend_from_slice(payload);
+        let mut stream = self.stream.lock().unwrap_or_else(|e| e.into_inner());
+        stream.write_all(&frame)
+    }
+}
+
+impl WebSocket {
+    pub(crate) fn new(
+        stream: &Stream,
+        buffered: Vec<u8>,
+        max_message: u64,
+    ) -> io::Result<WebSocket> {
+        // idle connections are normal here, the keep-alive timeout isn't
+        stream.set_read_timeout(None)?;
+        Ok(WebSocket {
+            reader: Cursor::new(buffered).chain(stream.try_clone()?),
+            writer: Sender {
+                stream: Arc::new(Mutex::new(stream.try_clone()?)),
+            },
+            max_message,
+            closed: false,
+        })
+    }
+
+    /// The next message from the client, `None` once the connection was
+    /// closed.
+    pub fn recv(&mut self) -> io::Result<Option<Message>> {
+        let mut message: Option<(u8, Vec<u8>)> = None;
+        while !self.closed {
+            let Some((fin, opcode, payload)) = self.read_frame()? else {
+                self.closed = true;
+                break;
+            };
+            match opcode {
+                OP_PING => self.writer.frame(OP_PONG, &payload)?,
+                OP_PONG => {}
+                OP_CLOSE => {
+                    // the status code is echoed, RFC 6455 section 5.5.1
+                    let _ = self.writer.frame(OP_CLOSE, payload.get(..2).unwrap_or(&[]));
+                    self.closed = true;
+                }
+                OP_TEXT | OP_BINARY if message.is_none() => message = Some((opcode, payload)),
+                OP_CONTINUATION if message.is_some() => {
+                    if let Some((_, data)) = &mut message {
+                        data.extend_from_slice(&payload);
+                        if data.len() as u64 > self.max_message {
+                            return Err(self.fail(1009, "message too large"));
+                        }
+                    }
+                }
+                _ => return Err(self.fail(1002, "unexpected frame")),
+            }
+
+            // control frames may arrive between the fragments of a message
+            if fin && opcode & 0x08 == 0 {
+                match message.take() {
+                    Some((OP_TEXT, data)) => {
+                        return match String::from_utf8(data) {
+                            Ok(text) => Ok(Some(Message::Text(text))),
+                            Err(_) => Err(self.fail(1007, "text message is not UTF-8")),
+                        };
+                    }
+                    Some((_, data)) => return Ok(Some(Message::Binary(data))),
+                    None => {}
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    pub fn send(&self, message: &Message) -> io::Result<()> {
+        self.writer.send(message)
+    }
+
+    /// A handle for sending from other threads while this one receives.
+    pub fn sender(&self) -> Sender {
+        self.writer.clone()
+    }
+
+    /// Starts the closing handshake, `recv` returns `None` once the client
+    /// answered.
+    pub fn close(&mut self) -> io::Result<()> {
+        self.writer.frame(OP_CLOSE, &1000u16.to_be_bytes())
+    }
+
+    // one frame with its payload unmasked, `None` when the client went away
+    // without a close frame
+    fn read_frame(&mut self) -> io::Result<Option<(bool, u8, Vec<u8>)>> {
+        let mut head = [0; 2];
+        match self.reader.read_exact(&mut head) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e),
+        }
+        let fin = head[0] & 0x80 != 0;
+        let opcode = head[0] & 0x0f;
+        // clients have to mask every frame, and no extensions were agreed on
+        if head[1] & 0x80 == 0 || head[0] & 0x70 != 0 {
+            return Err(self.fail(1002, "unmasked or reserved frame"));
+        }
+        let len = match head[1] & 0x7f {
+            126 => {
+                let mut len = [0; 2];
+                self.reader.read_exact(&mut len)?;
+                u16::from_be_bytes(len) as u64
+            }
+            127 => {
+                let mut len = [0; 8];
+                self.reader.read_exact(&mut len)?;
+                u64::from_be_bytes(len)
+            }
+            len => len as u64,
+        };
+        let control = opcode & 0x08 != 0;
+        if control && (len > 125 || !fin) {
+            return Err(self.fail(1002, "invalid control frame"));
+        }
+        if len > self.max_message {
+            return Err(self.fail(1009, "message too large"));
+        }
+
+        let mut mask = [0; 4];
+        self.reader.read_exact(&mut mask)?;
+        let mut payload = vec![0; len as usize];
+        self.reader.read_exact(&mut payload)?;
+        for (i, byte) in payload.iter_mut().enumerate() {
+            *byte ^= mask[i % 4];
+        }
+        Ok(Some((fin, opcode, payload)))
+    }
+
+    // closes the connection with `code` after a protocol violation
+    fn fail(&mut self, code: u16, reason: &str) -> io::Error {
+        let mut payload = code.to_be_bytes().to_vec();
+        payload.extend_from_slice(reason.as_bytes());
+        let _ = self.writer.frame(OP_CLOSE, &payload);
+        self.closed = true;
+        io::Error::new(io::ErrorKind::InvalidData, reason)
+    }
+}
+
+/// The `101 Switching Protocols` answer to an upgrade request, or the
+/// error response when the request isn't a valid handshake.
+pub fn handshake(request: &Request) -> Result<Response, Response> {
+    if request.method != "GET" || !request.is_websocket_upgrade() {
+        return Err(Response::error(400, "Expected a WebSocket handshake"));
+    }
+    if request.header("sec-websocket-version").map(str::trim) != Some("13") {
+        let mut response = Response::error(426, "Unsupported WebSocket version");
+        response.headers_mut().insert("Sec-WebSocket-Version", "13");
+        return Err(response);
+    }
+    let key = request.header("sec-websocket-key").unwrap_or("").trim();
+    let decoded = base64::engine::general_purpose::STANDARD.decode(key);
+    if !decoded.is_ok_and(|nonce| nonce.len() == 16) {
+        return Err(Response::error(400, "Invalid Sec-WebSocket-Key"));
+    }
+
+    let accept = base64::engine::general_purpose::STANDARD
+        .encode(sha1(format!("{}{}", key, GUID).as_bytes()));
+    Ok(Response::builder()
+        .status(101)
+        .header("Upgrade", "websocket")
+        .header("Sec-WebSocket-Accept", &accept)
+        .build())
+}
+
+/// Relays bytes both ways between a client and an upstream that agreed to
+/// an upgrade, until either side closes. `client_buffered` and
+/// `upstream_buffered` were read past the heads already. Returns the
+/// number of bytes sent to the client.
+pub fn tunnel(
+    client: &Stream,
+    client_buffered: Vec<u8>,
+    upstream: TcpStream,
+    upstream_buffered: Vec<u8>,
+) -> io::Result<u64> {
+    client.set_read_timeout(None)?;
+    upstream.set_read_timeout(None)?;
+
+    let mut to_upstream = upstream.try_clone()?;
+    let mut from_client = client.try_clone()?;
+    let uplink = thread::spawn(move || {
+        let copied = io::copy(
+            &mut Cursor::new(client_buffered).chain(&mut from_client),
+            &mut to_upstream,
+        );
+        let _ = to_upstream.shutdown(Shutdown::Write);
+        copied
+    });
+
+    let mut to_client = client.try_clone()?;
+    let copied = io::copy(
+        &mut Cursor::new(upstream_buffered).chain(&upstream),
+        &mut to_client,
+    );
+    // the client half may still be waiting for data, closing both ends
+    // wakes it up
+    let _ = client.shutdown(Shutdown::Both);
+    let _ = upstream.shutdown(Shutdown::Both);
+    let _ = uplink.join();
+    copied
+}
+
+// SHA-1 (RFC 3174), only used for Sec-WebSocket-Accept where it's mandated
+fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut h: [u32; 5] = [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476, 0xc3d2e1f0];
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&((data.len() as u64) * 8).to_be_bytes());
+
+    for block in message.chunks(64) {
+        let mut w = [0u32; 80];
+        for (i, word) in block.chunks(4).enumerate() {
+            w[i] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+        }
+        for i in 16..80 {
+            w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e] = h;
+        for (i, word) in w.iter().enumerate() {
+            let (f, k) = match i {
+                0..=19 => ((b & c) | (!b & d), 0x5a827999),
+                20..=39 => (b ^ c ^ d, 0x6ed9eba1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8f1bbcdc),
+                _ => (b ^ c ^ d, 0xca62c1d6),
+            };
+            let temp = a
+                .rotate_left(5)
+                .wrapping_add(f)
+                .wrapping_add(e)
+                .wrapping_add(k)
+                .wrapping_add(*word);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = temp;
+        }
+        for (state, value) in h.iter_mut().zip([a, b, c, d, e]) {
+            *state = state.wrapping_add(value);
+        }
+    }
+
+    let mut digest = [0; 20];
+    for (chunk, word) in digest.chunks_mut(4).zip(h) {
+        chunk.copy_from_slice(&word.to_be_bytes());
+    }
+    digest
+}
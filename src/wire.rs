@@ -0,0 +1,102 @@
+//! `logging.dump_wire`: request and response heads as they cross the
+//! connection, logged for debugging in the span of the connection. Response
+//! bodies are cut off after `logging.dump_body_bytes`.
+
+use std::fmt::Write as _;
+use std::io::{self, Write};
+
+/// Logs a request head as it was read.
+pub fn dump_request(head: &[u8]) {
+    log(render('>', head, &[], 0));
+}
+
+/// A writer that passes everything on and keeps the response head plus the
+/// start of the body for `finish`.
+pub struct WireDump<W> {
+    inner: W,
+    body_limit: usize,
+    head: Vec<u8>,
+    body: Vec<u8>,
+    // body bytes beyond the ones kept
+    skipped: u64,
+}
+
+impl<W: Write> WireDump<W> {
+    pub fn new(inner: W, body_limit: usize) -> Self {
+        WireDump {
+            inner,
+            body_limit,
+            head: Vec::new(),
+            body: Vec::new(),
+            skipped: 0,
+        }
+    }
+
+    /// Logs what was written.
+    pub fn finish(self) {
+        log(render('<', &self.head, &self.body, self.skipped));
+    }
+
+    fn record(&mut self, mut bytes: &[u8]) {
+        if !self.head.ends_with(b"\r\n\r\n") {
+            let searched = self.head.len().saturating_sub(3);
+            self.head.extend_from_slice(bytes);
+            bytes = &[];
+            if let Some(end) = self.head[searched..]
+                .windows(4)
+                .position(|window| window == b"\r\n\r\n")
+            {
+                let end = searched + end + 4;
+                let rest = self.head.split_off(end);
+                self.record(&rest);
+            }
+        }
+        let kept = bytes.len().min(self.body_limit - self.body.len());
+        self.body.extend_from_slice(&bytes[..kept]);
+        self.skipped += (bytes.len() - kept) as u64;
+    }
+}
+
+impl<W: Write> Write for WireDump<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.record(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+// at info, turning the dump on is enough to see it
+fn log(text: String) {
+    tracing::info!(target: "nebula::wire", "\n{}", text.trim_end());
+}
+
+// one block per message, built up front so the ones of different workers
+// don't interleave
+fn render(direction: char, head: &[u8], body: &[u8], skipped: u64) -> String {
+    let mut text = String::new();
+    for line in String::from_utf8_lossy(head).lines() {
+        let _ = writeln!(text, "{} {}", direction, line);
+    }
+    if !body.is_empty() {
+        // binary bodies stay readable and can't mess with the terminal
+        let body: String = String::from_utf8_lossy(body)
+            .chars()
+            .map(|c| match c {
+                '\n' | '\t' => c,
+                c if c.is_control() => '.',
+                c => c,
+            })
+            .collect();
+        for line in body.lines() {
+            let _ = writeln!(text, "{} {}", direction, line);
+        }
+    }
+    if skipped > 0 {
+        let _ = writeln!(text, "{} [{} more body bytes]", direction, skipped);
+    }
+    text
+}